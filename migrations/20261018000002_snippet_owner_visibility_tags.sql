@@ -0,0 +1,13 @@
+ALTER TABLE snippets
+    ADD COLUMN user_id INTEGER NULL,
+    ADD COLUMN visibility VARCHAR(10) NOT NULL DEFAULT 'public',
+    ADD CONSTRAINT fk_snippets_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL,
+    ADD INDEX idx_snippets_visibility_created (visibility, created);
+
+CREATE TABLE snippet_tags (
+    snippet_id INTEGER NOT NULL,
+    tag VARCHAR(50) NOT NULL,
+    PRIMARY KEY (snippet_id, tag),
+    INDEX idx_snippet_tags_tag (tag),
+    CONSTRAINT fk_snippet_tags_snippet FOREIGN KEY (snippet_id) REFERENCES snippets(id) ON DELETE CASCADE
+);
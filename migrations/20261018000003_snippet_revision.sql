@@ -0,0 +1,2 @@
+-- bumped on every content change, lets derived data (preview images) be cached per revision
+ALTER TABLE snippets ADD COLUMN revision INTEGER NOT NULL DEFAULT 1;
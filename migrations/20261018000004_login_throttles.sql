@@ -0,0 +1,10 @@
+-- failed login attempts, tracked separately per email address and per client ip
+CREATE TABLE login_throttles (
+    kind VARCHAR(5) NOT NULL,
+    throttle_key VARCHAR(255) NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failure DATETIME NOT NULL,
+    locked_until DATETIME NULL,
+    PRIMARY KEY (kind, throttle_key),
+    INDEX idx_login_throttles_locked_until (locked_until)
+);
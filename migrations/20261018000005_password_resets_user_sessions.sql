@@ -0,0 +1,18 @@
+-- one-time password reset links, only the SHA-256 digest of the token is stored
+CREATE TABLE password_resets (
+    token_hash CHAR(64) NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    expires DATETIME NOT NULL,
+    created DATETIME NOT NULL,
+    INDEX idx_password_resets_user_id (user_id),
+    CONSTRAINT fk_password_resets_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- the sessions store keeps its records opaque, this remembers which of them belong to whom
+CREATE TABLE user_sessions (
+    session_id VARCHAR(64) NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    created DATETIME NOT NULL,
+    INDEX idx_user_sessions_user_id (user_id),
+    CONSTRAINT fk_user_sessions_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,14 @@
+-- accounts that existed before verification was introduced are trusted as they are
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE users SET email_verified = TRUE;
+
+-- verification links, only the SHA-256 digest of the token is stored.
+-- rows are kept until they expire so that resends can be rate limited
+CREATE TABLE email_verifications (
+    token_hash CHAR(64) NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    expires DATETIME NOT NULL,
+    created DATETIME NOT NULL,
+    INDEX idx_email_verifications_user_id (user_id, created),
+    CONSTRAINT fk_email_verifications_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
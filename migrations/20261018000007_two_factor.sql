@@ -0,0 +1,13 @@
+-- the authenticator secret has to be readable to check codes, so it cannot be hashed.
+-- totp_last_step is the time step of the last accepted code and keeps codes from being replayed
+ALTER TABLE users
+    ADD COLUMN totp_secret VARCHAR(64) NULL,
+    ADD COLUMN totp_last_step BIGINT NULL;
+
+-- single-use recovery codes, only the SHA-256 digest is stored
+CREATE TABLE recovery_codes (
+    user_id INTEGER NOT NULL,
+    code_hash CHAR(64) NOT NULL,
+    PRIMARY KEY (user_id, code_hash),
+    CONSTRAINT fk_recovery_codes_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,7 @@
+-- what the sessions page shows about each device, last_seen is refreshed at most once a minute
+ALTER TABLE user_sessions
+    ADD COLUMN ip VARCHAR(45) NULL,
+    ADD COLUMN user_agent VARCHAR(255) NULL,
+    ADD COLUMN last_seen DATETIME NULL;
+UPDATE user_sessions SET last_seen = created;
+ALTER TABLE user_sessions MODIFY last_seen DATETIME NOT NULL;
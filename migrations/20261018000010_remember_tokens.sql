@@ -0,0 +1,17 @@
+-- persistent logins ("remember me"), stored as selector plus SHA-256 digest of the validator.
+-- every use replaces the token with a new one of the same series. Replaced tokens are kept
+-- until the series expires: one coming back means the cookie was copied
+CREATE TABLE remember_tokens (
+    selector VARCHAR(64) NOT NULL PRIMARY KEY,
+    validator_hash CHAR(64) NOT NULL,
+    series VARCHAR(64) NOT NULL,
+    user_id INTEGER NOT NULL,
+    -- the session the token started, so ending that session ends the token as well
+    session_id VARCHAR(64) NULL,
+    replaced DATETIME NULL,
+    expires DATETIME NOT NULL,
+    created DATETIME NOT NULL,
+    INDEX idx_remember_tokens_series (series),
+    INDEX idx_remember_tokens_user_id (user_id),
+    CONSTRAINT fk_remember_tokens_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Argon2id hashes in PHC string format are longer than the 60 characters of bcrypt
+ALTER TABLE users MODIFY hashed_password VARCHAR(255) NOT NULL;
@@ -0,0 +1,15 @@
+-- WebAuthn passkeys users can log in with instead of email and password.
+-- authenticators know the user by a random handle, not by the id or email
+ALTER TABLE users ADD COLUMN passkey_handle CHAR(36) NULL UNIQUE;
+
+CREATE TABLE passkeys (
+    credential_id VARBINARY(1023) NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    -- the public key and signature counter, serialised by webauthn-rs
+    passkey TEXT NOT NULL,
+    created DATETIME NOT NULL,
+    last_used DATETIME NULL,
+    INDEX idx_passkeys_user_id (user_id),
+    CONSTRAINT fk_passkeys_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+-- what a user may do besides using the site, each role includes the ones before it
+ALTER TABLE users
+    ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'user',
+    ADD CONSTRAINT chk_users_role CHECK (role IN ('user', 'moderator', 'admin'));
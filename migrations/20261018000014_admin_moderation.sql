@@ -0,0 +1,5 @@
+-- accounts an admin has disabled can no longer log in
+ALTER TABLE users ADD COLUMN disabled DATETIME NULL;
+
+-- snippets an admin has deleted are hidden everywhere, but can still be restored
+ALTER TABLE snippets ADD COLUMN deleted DATETIME NULL;
@@ -0,0 +1,23 @@
+-- snippets a moderator has hidden until the reports about them have been reviewed
+ALTER TABLE snippets ADD COLUMN hidden DATETIME NULL;
+
+CREATE TABLE snippet_reports (
+    id INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    snippet_id INTEGER NOT NULL,
+    -- NULL for anonymous reports, and once the reporter deletes their account
+    reporter_id INTEGER NULL,
+    reporter_ip VARCHAR(45) NULL,
+    reason VARCHAR(16) NOT NULL,
+    details VARCHAR(500) NOT NULL DEFAULT '',
+    created DATETIME NOT NULL,
+    -- set once a moderator has dealt with the report
+    resolved DATETIME NULL,
+    resolved_by INTEGER NULL,
+    resolution VARCHAR(16) NULL,
+    INDEX idx_snippet_reports_open (resolved, created),
+    INDEX idx_snippet_reports_snippet (snippet_id),
+    CONSTRAINT chk_snippet_reports_reason CHECK (reason IN ('spam', 'malware', 'secret', 'other')),
+    CONSTRAINT fk_snippet_reports_snippet FOREIGN KEY (snippet_id) REFERENCES snippets(id) ON DELETE CASCADE,
+    CONSTRAINT fk_snippet_reports_reporter FOREIGN KEY (reporter_id) REFERENCES users(id) ON DELETE SET NULL,
+    CONSTRAINT fk_snippet_reports_resolver FOREIGN KEY (resolved_by) REFERENCES users(id) ON DELETE SET NULL
+);
\ No newline at end of file
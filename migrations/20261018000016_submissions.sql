@@ -0,0 +1,14 @@
+-- snippets and signups as seen by the spam filter, to tell repeated content and bursts from one address
+CREATE TABLE submissions (
+    id BIGINT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    kind VARCHAR(16) NOT NULL,
+    ip VARCHAR(45) NULL,
+    user_id INTEGER NULL,
+    -- digest of the normalised snippet content, NULL for signups
+    content_hash CHAR(64) NULL,
+    created DATETIME NOT NULL,
+    INDEX idx_submissions_ip (kind, ip, created),
+    INDEX idx_submissions_user (kind, user_id, created),
+    INDEX idx_submissions_content_hash (content_hash, created),
+    CONSTRAINT fk_submissions_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
\ No newline at end of file
@@ -0,0 +1,6 @@
+-- token buckets shared by all instances, used with --shared-rate-limits
+CREATE TABLE rate_limits (
+    bucket_key VARCHAR(100) NOT NULL PRIMARY KEY,
+    tokens DOUBLE NOT NULL,
+    updated DATETIME(6) NOT NULL
+);
\ No newline at end of file
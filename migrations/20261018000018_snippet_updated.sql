@@ -0,0 +1,2 @@
+-- when the content was last changed, feeds date their entries by it
+ALTER TABLE snippets ADD COLUMN updated DATETIME NULL;
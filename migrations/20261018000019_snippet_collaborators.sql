@@ -0,0 +1,10 @@
+-- users the owner of a snippet invited to edit it live along with them
+CREATE TABLE snippet_collaborators (
+    snippet_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    created DATETIME NOT NULL,
+    PRIMARY KEY (snippet_id, user_id),
+    INDEX idx_snippet_collaborators_user (user_id),
+    CONSTRAINT fk_snippet_collaborators_snippet FOREIGN KEY (snippet_id) REFERENCES snippets(id) ON DELETE CASCADE,
+    CONSTRAINT fk_snippet_collaborators_user FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
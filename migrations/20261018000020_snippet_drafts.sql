@@ -0,0 +1,8 @@
+-- the text of a live session that ended while it waited for somebody to confirm the
+-- secrets in it, the next live session of the snippet picks it up again
+CREATE TABLE snippet_drafts (
+    snippet_id INTEGER NOT NULL PRIMARY KEY,
+    content TEXT NOT NULL,
+    saved DATETIME NOT NULL,
+    CONSTRAINT fk_snippet_drafts_snippet FOREIGN KEY (snippet_id) REFERENCES snippets(id) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+-- when the snippet was last deleted, restored, hidden or shown again. Feeds it leaves or
+-- comes back to change then, its content did not
+ALTER TABLE snippets ADD COLUMN moderated DATETIME NULL;
+UPDATE snippets SET moderated = GREATEST(COALESCE(deleted, hidden), COALESCE(hidden, deleted));
@@ -0,0 +1,342 @@
+// Where a request really comes from. Behind a reverse proxy the peer address is the proxy's,
+// the client is then taken from `X-Forwarded-For` or `Forwarded`, whichever the proxies are
+// configured to write, but only when the peer is a proxy we trust: anybody else can put
+// whatever they like in those headers.
+use std::{fmt, net::IpAddr, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::FromRequestParts,
+    http::{HeaderMap, StatusCode, request::Parts},
+};
+
+/// A network like "10.0.0.0/8" or "2001:db8::/32", a bare address stands for itself.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not an address or network like 10.0.0.0/8", value);
+        let (address, prefix) = match value.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value.trim(), None),
+        };
+        let address = address.parse::<IpAddr>().map_err(|_| invalid())?;
+        let network = address.to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            // "::ffff:10.0.0.0/104" counts the 96 bits of the mapping prefix too
+            Some(prefix) if address.is_ipv6() && network.is_ipv4() => prefix
+                .parse::<u8>()
+                .ok()
+                .and_then(|prefix| prefix.checked_sub(96))
+                .ok_or_else(invalid)?,
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The header our proxies record the client in. Only that one is read: a proxy that appends to
+/// one of them passes the other on as the client wrote it.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            _ => Err(format!(
+                "{} is not one of x-forwarded-for or forwarded",
+                value
+            )),
+        }
+    }
+}
+
+/// The client's address, `middleware::request_ip` puts it on every request.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<ClientIp>().copied().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "client address missing, is the request_ip middleware in place?",
+        ))
+    }
+}
+
+/// Which peers may tell us the client's address, and which clients are let in at all.
+#[derive(Clone, Default)]
+pub struct IpPolicy {
+    trusted_proxies: Arc<Vec<Cidr>>,
+    forwarded_header: ForwardedHeader,
+    allow: Arc<Vec<Cidr>>,
+    deny: Arc<Vec<Cidr>>,
+}
+
+impl IpPolicy {
+    /// An empty `allow` lets in everybody who is not denied.
+    pub fn new(
+        trusted_proxies: Vec<Cidr>,
+        forwarded_header: ForwardedHeader,
+        allow: Vec<Cidr>,
+        deny: Vec<Cidr>,
+    ) -> Self {
+        Self {
+            trusted_proxies: Arc::new(trusted_proxies),
+            forwarded_header,
+            allow: Arc::new(allow),
+            deny: Arc::new(deny),
+        }
+    }
+
+    fn trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client behind `peer`. The forwarding chain is walked back from the peer for as long
+    /// as the hops are trusted proxies, the first one that is not is the client.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.trusted(peer) {
+            return peer;
+        }
+        let chain = match self.forwarded_header {
+            ForwardedHeader::XForwardedFor => x_forwarded_for(headers),
+            ForwardedHeader::Forwarded => forwarded_for(headers),
+        };
+        let mut client = peer;
+        for hop in chain.into_iter().rev() {
+            // an entry we cannot read might be anybody, it does not get to be trusted
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop;
+            if !self.trusted(hop) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Whether the client may use the site at all. Denials win over the allow list.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+// `for` of every `Forwarded` element (RFC 7239), in the order the proxies added them
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+// "192.0.2.43", "192.0.2.43:47011", "[2001:db8::1]:4711" or "2001:db8::1";
+// obfuscated identifiers like "_hidden" and "unknown" give `None`
+fn parse_node(value: &str) -> Option<IpAddr> {
+    if let Some(rest) = value.strip_prefix('[') {
+        let (address, _) = rest.split_once(']')?;
+        return address.parse::<IpAddr>().ok().map(|ip| ip.to_canonical());
+    }
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    let (address, _port) = value.rsplit_once(':')?;
+    address.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().unwrap()
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let everything = cidr("0.0.0.0/0");
+        assert!(everything.contains(ip("10.1.2.3")));
+        assert!(everything.contains(ip("255.255.255.255")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+
+        let single = cidr("192.0.2.1/32");
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+        let bare = cidr("2001:db8::1");
+        assert!(bare.contains(ip("2001:db8::1")));
+        assert!(!bare.contains(ip("2001:db8::2")));
+
+        let private = cidr("10.0.0.0/8");
+        assert!(private.contains(ip("10.255.0.1")));
+        assert!(!private.contains(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_ipv4() {
+        // what a dual-stack socket reports for an IPv4 peer
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(!cidr("::ffff:10.0.0.0/104").contains(ip("11.1.2.3")));
+        assert!(cidr("::ffff:192.0.2.1").contains(ip("192.0.2.1")));
+        assert!("::ffff:10.0.0.0/95".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn invalid_networks_are_refused() {
+        for invalid in [
+            "",
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/x",
+            "example.com",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn the_chain_is_walked_back_through_trusted_proxies_only() {
+        let policy = IpPolicy::new(
+            vec![cidr("10.0.0.0/8")],
+            ForwardedHeader::XForwardedFor,
+            Vec::new(),
+            Vec::new(),
+        );
+        let chain = headers("x-forwarded-for", "198.51.100.7, 203.0.113.9, 10.0.0.2");
+        // the first hop that is not one of our proxies is the client, whatever it claims
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &chain), ip("203.0.113.9"));
+        // anybody else saying who the client is gets ignored
+        assert_eq!(
+            policy.client_ip(ip("203.0.113.50"), &chain),
+            ip("203.0.113.50")
+        );
+        // an unreadable hop stops the walk at the last proxy
+        let unknown = headers("x-forwarded-for", "198.51.100.7, unknown, 10.0.0.2");
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &unknown), ip("10.0.0.2"));
+        // a proxy without the header is talking for itself
+        assert_eq!(
+            policy.client_ip(ip("::ffff:10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let policy = IpPolicy::new(
+            vec![cidr("10.0.0.0/8")],
+            ForwardedHeader::Forwarded,
+            Vec::new(),
+            Vec::new(),
+        );
+        let mut both = headers(
+            "forwarded",
+            r#"for=198.51.100.7;proto=https, for="[2001:db8::7]:4711""#,
+        );
+        both.insert("x-forwarded-for", "192.0.2.99".parse().unwrap());
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &both), ip("2001:db8::7"));
+        assert_eq!(parse_node("192.0.2.43:47011"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn denials_win_over_the_allow_list() {
+        let open = IpPolicy::new(
+            Vec::new(),
+            ForwardedHeader::XForwardedFor,
+            Vec::new(),
+            vec![cidr("192.0.2.0/24")],
+        );
+        assert!(open.permits(ip("198.51.100.1")));
+        assert!(!open.permits(ip("192.0.2.1")));
+        let closed = IpPolicy::new(
+            Vec::new(),
+            ForwardedHeader::XForwardedFor,
+            vec![cidr("192.0.2.0/24")],
+            vec![cidr("192.0.2.13")],
+        );
+        assert!(closed.permits(ip("192.0.2.1")));
+        assert!(!closed.permits(ip("192.0.2.13")));
+        assert!(!closed.permits(ip("198.51.100.1")));
+    }
+}
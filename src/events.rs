@@ -0,0 +1,218 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, Sender, error::RecvError};
+
+// how many events a subscriber may fall behind before it starts lagging,
+// and how many are kept around for `Last-Event-ID` replay
+const CHANNEL_CAPACITY: usize = 64;
+const REPLAY_CAPACITY: usize = 100;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SnippetCreated {
+    pub id: i32,
+    pub title: String,
+    pub created: String,
+}
+
+/// What a subscriber receives next.
+pub enum Update {
+    Snippet(SnippetCreated),
+    // events were lost and cannot be replayed, the client has to reload
+    Reset,
+}
+
+// Snippets are not necessarily published in id order, two requests creating one each can
+// finish the other way round. Events keep the order they were published in, ids only say
+// which events have been seen.
+struct Recent {
+    // in the order they were published
+    events: VecDeque<SnippetCreated>,
+    // highest id of the events that have been dropped from `events`
+    evicted_up_to: i32,
+}
+
+/// In-process fan-out of newly created public snippets to every live subscriber.
+#[derive(Clone)]
+pub struct SnippetEvents {
+    sender: Sender<SnippetCreated>,
+    recent: Arc<Mutex<Recent>>,
+}
+
+impl SnippetEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            recent: Arc::new(Mutex::new(Recent {
+                events: VecDeque::with_capacity(REPLAY_CAPACITY),
+                evicted_up_to: 0,
+            })),
+        }
+    }
+
+    pub fn publish(&self, event: SnippetCreated) {
+        {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.events.len() == REPLAY_CAPACITY
+                && let Some(evicted) = recent.events.pop_front()
+            {
+                recent.evicted_up_to = recent.evicted_up_to.max(evicted.id);
+            }
+            recent.events.push_back(event.clone());
+        }
+        // an error only means nobody is listening right now
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self, last_event_id: Option<i32>) -> Subscription {
+        // subscribe before reading the replay buffer so nothing published in between is lost
+        let receiver = self.sender.subscribe();
+        let mut subscription = Subscription {
+            events: self.clone(),
+            receiver,
+            pending: VecDeque::new(),
+            last_id: last_event_id.unwrap_or(0),
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+        };
+        if last_event_id.is_some() {
+            subscription.catch_up();
+        }
+        subscription
+    }
+
+    // The events published after the one with `last_id`, `None` when some of them may have
+    // been evicted already
+    fn since(&self, last_id: i32) -> Option<Vec<SnippetCreated>> {
+        let recent = self.recent.lock().unwrap();
+        if let Some(position) = recent.events.iter().position(|event| event.id == last_id) {
+            return Some(recent.events.iter().skip(position + 1).cloned().collect());
+        }
+        // the event is gone, or was never published here (the server restarted in between)
+        if recent.evicted_up_to > 0 && last_id <= recent.evicted_up_to {
+            return None;
+        }
+        Some(
+            recent
+                .events
+                .iter()
+                .filter(|event| event.id > last_id)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+pub struct Subscription {
+    events: SnippetEvents,
+    receiver: Receiver<SnippetCreated>,
+    pending: VecDeque<Update>,
+    // id of the event received last, not necessarily the highest
+    last_id: i32,
+    // ids of the events received lately, replayed events may also come through the channel
+    seen: HashSet<i32>,
+    seen_order: VecDeque<i32>,
+}
+
+impl Subscription {
+    pub async fn next(&mut self) -> Option<Update> {
+        loop {
+            if let Some(update) = self.pending.pop_front() {
+                if let Update::Snippet(event) = &update {
+                    if !self.see(event.id) {
+                        continue;
+                    }
+                    self.last_id = event.id;
+                }
+                return Some(update);
+            }
+            match self.receiver.recv().await {
+                Ok(event) => self.pending.push_back(Update::Snippet(event)),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::debug!("live update subscriber lagged by {} events", skipped);
+                    self.catch_up();
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    // whether the event is new to the subscriber, an event is only ever seen again while it
+    // is still in the replay buffer or the channel
+    fn see(&mut self, id: i32) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+        if self.seen_order.len() > REPLAY_CAPACITY + CHANNEL_CAPACITY
+            && let Some(forgotten) = self.seen_order.pop_front()
+        {
+            self.seen.remove(&forgotten);
+        }
+        true
+    }
+
+    fn catch_up(&mut self) {
+        match self.events.since(self.last_id) {
+            Some(missed) => self.pending.extend(missed.into_iter().map(Update::Snippet)),
+            None => self.pending.push_back(Update::Reset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: i32) -> SnippetCreated {
+        SnippetCreated {
+            id,
+            title: format!("snippet {}", id),
+            created: String::new(),
+        }
+    }
+
+    async fn ids(subscription: &mut Subscription, count: usize) -> Vec<i32> {
+        let mut ids = Vec::new();
+        while ids.len() < count {
+            match subscription.next().await {
+                Some(Update::Snippet(event)) => ids.push(event.id),
+                _ => panic!("no event"),
+            }
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn events_published_out_of_id_order_all_arrive() {
+        let events = SnippetEvents::new();
+        let mut live = events.subscribe(None);
+        events.publish(event(6));
+        events.publish(event(5));
+        assert_eq!(ids(&mut live, 2).await, [6, 5]);
+
+        // a client reconnecting after 6 still gets 5, and nothing twice
+        let mut replayed = events.subscribe(Some(6));
+        events.publish(event(7));
+        assert_eq!(ids(&mut replayed, 2).await, [5, 7]);
+        assert!(replayed.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn evicted_events_make_the_client_reload() {
+        let events = SnippetEvents::new();
+        for id in 1..=REPLAY_CAPACITY as i32 + 1 {
+            events.publish(event(id));
+        }
+        let mut subscription = events.subscribe(Some(0));
+        assert!(matches!(subscription.next().await, Some(Update::Reset)));
+        let mut subscription = events.subscribe(Some(1));
+        assert!(matches!(subscription.next().await, Some(Update::Reset)));
+        let mut subscription = events.subscribe(Some(2));
+        assert_eq!(ids(&mut subscription, 1).await, [3]);
+    }
+}
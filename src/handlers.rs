@@ -1,24 +1,66 @@
 use std::{collections::HashMap, sync::Arc};
 
 use crate::{
-    AppState, Authenticated,
-    templates::{HomeTemplate, ViewTemplate},
+    AppState, CurrentUser,
+    client_ip::ClientIp,
+    events::{SnippetCreated, Update},
+    mailer::{self, Email},
+    middleware::Embeddable,
+    models::errors::ErrInvalidCredentials,
+    models::password_reset::RESET_TOKEN_MINUTES,
+    models::reports::{OpenReport, ReportReason, Resolution},
+    models::snippet::{LatestFilter, Snippet, SnippetState, VISIBILITY_PRIVATE, VISIBILITY_PUBLIC},
+    models::submissions::KIND_SIGNUP,
+    models::users::{Role, User},
+    oidc::federated_user,
+    pow::PowForm,
+    preview,
+    spam::{Submission, SubmissionKind},
+    templates::{
+        AdminLockoutRow, AdminSnippetRow, AdminSnippetsTemplate, AdminTemplate, AdminUserRow,
+        AdminUsersTemplate, AtomTemplate, EmbedTemplate, HomeTemplate, LiveTemplate,
+        LoginTwoFactorTemplate, ModerationTemplate, RecoveryCodesTemplate, ReportRow, RssTemplate,
+        SessionRow, SessionsTemplate, TwoFactorConfirmTemplate, TwoFactorTemplate, ViewTemplate,
+    },
     utils::{
+        account_form_validation::{
+            AccountTemplate, DeleteAccountData, EmailData, NameData, PasskeyData, PasskeyRow,
+            PasswordData, SNIPPETS_ANONYMISE, field_errors,
+        },
+        csrf::{self, CsrfToken},
         form_validation::{CreateTemplate, SnippetData},
         login_form_validation::{LoginData, LoginTemplate},
+        password_reset_form_validation::{
+            ForgotPasswordData, ForgotPasswordTemplate, ResetPasswordData, ResetPasswordTemplate,
+        },
+        percent_encode, remember,
         signup_form_validation::{SignupData, SignupTemplate},
+        token, totp,
     },
 };
 
 use askama::Template;
 use axum::{
-    Extension,
-    extract::{Path, State},
+    Extension, Form, Json,
+    extract::{Path, Query, State, WebSocketUpgrade},
     http::{HeaderMap, StatusCode, header},
-    response::{IntoResponse, Redirect, Response},
+    response::{
+        IntoResponse, Redirect, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
 };
-use sqlx::{error::DatabaseError, mysql::MySqlDatabaseError};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, stream};
+use serde::{Deserialize, Serialize};
+use sqlx::mysql::MySqlDatabaseError;
 use tower_sessions::Session;
+use validator::Validate;
+use webauthn_rs::prelude::{
+    DiscoverableAuthentication, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential,
+};
 
 pub async fn hn() -> Response {
     (StatusCode::OK, "hello").into_response()
@@ -26,34 +68,37 @@ pub async fn hn() -> Response {
 
 pub async fn home(
     State(state): State<Arc<AppState>>,
-    ext_state: Extension<Authenticated>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
     session: Session,
 ) -> Response {
     let snippets = state.snippets.latest().await;
-    let is_authenticated = AppState::is_authenticated(ext_state).await;
-    if !snippets.is_err() {
-        let view_snippets = snippets
-            .unwrap()
-            .into_iter()
-            .map(|snippet| ViewTemplate::convert_to_view(snippet, is_authenticated))
-            .collect::<Vec<ViewTemplate>>();
-        let flash_present: Option<String> = session.remove("flash").await.unwrap();
-        let mut f = "".to_string();
-        if let Some(flash) = flash_present {
-            if flash.len() > 0 {
+    let is_authenticated = AppState::is_authenticated(&user);
+    let needs_verification = AppState::needs_verification(&user);
+    match snippets {
+        Ok(snippets) => {
+            let view_snippets = snippets
+                .into_iter()
+                .map(|snippet| ViewTemplate::convert_to_view(snippet, is_authenticated))
+                .collect::<Vec<ViewTemplate>>();
+            let flash_present: Option<String> = session.remove("flash").await.unwrap();
+            let mut f = "".to_string();
+            if let Some(flash) = flash_present
+                && !flash.is_empty()
+            {
                 f = flash;
             }
+            let home_template = HomeTemplate {
+                view_snippets,
+                flash: f,
+                is_authenticated,
+                needs_verification,
+                csrf_token: csrf.0,
+            };
+            let template_render_result = home_template.render();
+            AppState::render(template_render_result)
         }
-        let home_template = HomeTemplate {
-            view_snippets,
-            flash: f,
-            is_authenticated: is_authenticated,
-        };
-        let template_render_result = home_template.render();
-        AppState::render(template_render_result)
-    } else {
-        let error = snippets.err().unwrap();
-        AppState::server_error(Box::new(error))
+        Err(error) => AppState::server_error(Box::new(error)),
     }
 }
 
@@ -61,48 +106,572 @@ pub async fn snippet_view(
     session: Session,
     Path(snippet_id): Path<u32>,
     State(state): State<Arc<AppState>>,
-    ext_state: Extension<Authenticated>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
 ) -> Response {
     let result = state.snippets.get(&snippet_id).await;
-    let is_authenticated = AppState::is_authenticated(ext_state).await;
-    if !result.is_err() {
-        let snippet = result.unwrap();
-        let mut template = ViewTemplate::new(
-            snippet.title,
+    let is_authenticated = AppState::is_authenticated(&user);
+    let needs_verification = AppState::needs_verification(&user);
+    match result {
+        Ok(snippet) => {
+            let user_id: Option<i32> = session.get("authenticatedUserID").await.unwrap_or_default();
+            let hidden = snippet.hidden.is_some();
+            // moderators need to see what they are reviewing
+            let reviewing = hidden
+                && user
+                    .as_ref()
+                    .is_some_and(|Extension(user)| user.has_role(Role::Moderator));
+            if !snippet.visible_to(user_id) && !reviewing {
+                if hidden && snippet.visibility != VISIBILITY_PRIVATE {
+                    return (
+                        StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                        "this snippet is unavailable while it is being reviewed",
+                    )
+                        .into_response();
+                }
+                return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
+            }
+            let owner = user_id.is_some() && user_id == snippet.user_id;
+            let tags = match state.snippets.tags(snippet.id).await {
+                Ok(tags) => tags,
+                Err(e) => return AppState::server_error(Box::new(e)),
+            };
+            let can_edit_live = match user_id {
+                Some(user_id) => match edits_live(&state, &snippet, user_id).await {
+                    Ok(can_edit_live) => can_edit_live,
+                    Err(e) => return AppState::server_error(Box::new(e)),
+                },
+                None => false,
+            };
+            let collaborators = if owner {
+                match state.collaborators.list(snippet.id).await {
+                    Ok(collaborators) => collaborators,
+                    Err(e) => return AppState::server_error(Box::new(e)),
+                }
+            } else {
+                Vec::new()
+            };
+            let mut template = ViewTemplate::new(
+                snippet.title,
+                snippet.id,
+                snippet.content,
+                snippet.created,
+                snippet.expires,
+                is_authenticated,
+            );
+            template.set_tags(tags);
+            template.set_base_url(state.base_url.clone());
+            template.set_revision(snippet.revision);
+            template.set_hidden(hidden);
+            template.set_can_report(!owner && !hidden);
+            template.set_can_edit_live(can_edit_live);
+            template.set_owner(owner, collaborators);
+            template.set_needs_verification(needs_verification);
+            template.set_csrf_token(csrf.0);
+
+            let flash_present: Option<String> = session.remove("flash").await.unwrap();
+            if let Some(flash) = flash_present
+                && !flash.is_empty()
+            {
+                template.set_flash(flash);
+            }
+            let template_render_result = template.render();
+            AppState::render(template_render_result)
+        }
+        Err(sqlx::error::Error::RowNotFound) => {
+            (StatusCode::NOT_FOUND, "snippet could not be found!").into_response()
+        }
+        Err(error) => AppState::server_error(Box::new(error)),
+    }
+}
+
+// Live editing is for the owner and the collaborators they invited. Nobody can invite
+// anyone to a snippet without an owner, and collaborators lose access while it is hidden.
+async fn edits_live(
+    state: &AppState,
+    snippet: &Snippet,
+    user_id: i32,
+) -> Result<bool, sqlx::Error> {
+    if snippet.user_id == Some(user_id) {
+        return Ok(true);
+    }
+    if snippet.user_id.is_none() || !snippet.visible_to(Some(user_id)) {
+        return Ok(false);
+    }
+    state
+        .collaborators
+        .is_collaborator(snippet.id, user_id)
+        .await
+}
+
+pub async fn snippet_live(
+    session: Session,
+    Path(snippet_id): Path<u32>,
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+) -> Response {
+    let Ok(Some(user_id)) = session.get::<i32>("authenticatedUserID").await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match state.snippets.get(&snippet_id).await {
+        Ok(snippet) => {
+            match edits_live(&state, &snippet, user_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
+                }
+                Err(e) => return AppState::server_error(Box::new(e)),
+            }
+            let template = LiveTemplate {
+                title: snippet.title,
+                id: snippet.id,
+                is_authenticated: true,
+                needs_verification: AppState::needs_verification(&user),
+                csrf_token: csrf.0,
+            };
+            AppState::render(template.render())
+        }
+        Err(sqlx::error::Error::RowNotFound) => {
+            (StatusCode::NOT_FOUND, "snippet could not be found!").into_response()
+        }
+        Err(error) => AppState::server_error(Box::new(error)),
+    }
+}
+
+// the socket is authenticated through the same session cookie as every other request,
+// `require_auth` has already run by the time we get here
+pub async fn snippet_live_ws(
+    ws: WebSocketUpgrade,
+    session: Session,
+    headers: HeaderMap,
+    Path(snippet_id): Path<u32>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    // browsers send cookies along with cross-site WebSocket handshakes, so check where it came from
+    if !same_origin(&headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            "cross-origin live sessions are not allowed",
+        )
+            .into_response();
+    }
+    let Ok(Some(user_id)) = session.get::<i32>("authenticatedUserID").await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let snippet = match state.snippets.get(&snippet_id).await {
+        Ok(snippet) => snippet,
+        Err(sqlx::error::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
+        }
+        Err(error) => return AppState::server_error(Box::new(error)),
+    };
+    match edits_live(&state, &snippet, user_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
+        }
+        Err(error) => return AppState::server_error(Box::new(error)),
+    }
+    let name = match state.users.name(user_id).await {
+        Ok(name) => name,
+        Err(error) => return AppState::server_error(Box::new(error)),
+    };
+    // saves stop reaching the snippet once this login is revoked
+    let session_id = session.id().map(|id| id.to_string()).unwrap_or_default();
+    let live_sessions = state.live_sessions.clone();
+    ws.on_upgrade(move |socket| {
+        live_sessions.handle_socket(
+            socket,
             snippet.id,
             snippet.content,
-            snippet.created,
-            snippet.expires,
-            is_authenticated,
-        );
+            user_id,
+            name,
+            session_id,
+        )
+    })
+}
 
-        let flash_present: Option<String> = session.remove("flash").await.unwrap();
-        if let Some(flash) = flash_present {
-            if flash.len() > 0 {
-                template.set_flash(flash);
+#[derive(Deserialize)]
+pub struct CollaboratorForm {
+    email: String,
+}
+
+/// Invites somebody with an account to edit the snippet live, only its owner may.
+pub async fn snippet_collaborator_add_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(snippet_id): Path<u32>,
+    Form(form): Form<CollaboratorForm>,
+) -> Response {
+    let snippet = match owned_snippet(&state, &session, snippet_id).await {
+        Ok(snippet) => snippet,
+        Err(response) => return response,
+    };
+    let location = format!("/snippet/view/{}", snippet.id);
+    let flash = match state.users.find_by_email(form.email.trim()).await {
+        Ok(Some((user_id, _))) if Some(user_id) == snippet.user_id => {
+            "You can always edit your own snippets.".to_string()
+        }
+        Ok(Some((user_id, email))) => match state.collaborators.add(snippet.id, user_id).await {
+            Ok(true) => {
+                tracing::info!(
+                    "user {} invited to edit snippet {} live",
+                    user_id,
+                    snippet.id
+                );
+                format!("{} can now edit this snippet live.", email)
             }
+            Ok(false) => format!("{} can edit this snippet live already.", email),
+            Err(e) => return AppState::server_error(Box::new(e)),
+        },
+        Ok(None) => "There is no account with that email address.".to_string(),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let _ = session.insert("flash", flash).await;
+    Redirect::to(&location).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct CollaboratorRemoveForm {
+    user_id: i32,
+}
+
+/// Takes live editing away from a collaborator, a session they are in ends right away.
+pub async fn snippet_collaborator_remove_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(snippet_id): Path<u32>,
+    Form(form): Form<CollaboratorRemoveForm>,
+) -> Response {
+    let snippet = match owned_snippet(&state, &session, snippet_id).await {
+        Ok(snippet) => snippet,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.collaborators.remove(snippet.id, form.user_id).await {
+        return AppState::server_error(Box::new(e));
+    }
+    state.live_sessions.revoke(snippet.id, form.user_id);
+    tracing::info!(
+        "user {} can no longer edit snippet {} live",
+        form.user_id,
+        snippet.id
+    );
+    let _ = session
+        .insert("flash", "The collaborator has been removed.")
+        .await;
+    Redirect::to(&format!("/snippet/view/{}", snippet.id)).into_response()
+}
+
+// the snippet, if the logged in user owns it. Anybody else is told it does not exist
+async fn owned_snippet(
+    state: &AppState,
+    session: &Session,
+    snippet_id: u32,
+) -> Result<Snippet, Response> {
+    let user_id: Option<i32> = session.get("authenticatedUserID").await.unwrap_or_default();
+    match state.snippets.get(&snippet_id).await {
+        Ok(snippet) if user_id.is_some() && snippet.user_id == user_id => Ok(snippet),
+        Ok(_) | Err(sqlx::error::Error::RowNotFound) => {
+            Err((StatusCode::NOT_FOUND, "snippet could not be found!").into_response())
         }
-        let template_render_result = template.render();
-        AppState::render(template_render_result)
-    } else {
-        let error = result.err().unwrap();
-        if let sqlx::error::Error::RowNotFound = error {
+        Err(error) => Err(AppState::server_error(Box::new(error))),
+    }
+}
+
+fn same_origin(headers: &HeaderMap) -> bool {
+    let origin = headers.get(header::ORIGIN).and_then(|o| o.to_str().ok());
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+    match (origin, host) {
+        (Some(origin), Some(host)) => origin
+            .split_once("://")
+            .is_some_and(|(_, origin_host)| origin_host == host),
+        _ => false,
+    }
+}
+
+pub async fn snippet_embed(
+    Path(snippet_id): Path<u32>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let snippet = match state.snippets.get(&snippet_id).await {
+        // embeds are anonymous, so private snippets are never shown
+        Ok(snippet) if snippet.visible_to(None) => snippet,
+        Ok(_) | Err(sqlx::error::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
+        }
+        Err(error) => return AppState::server_error(Box::new(error)),
+    };
+    let template = EmbedTemplate {
+        title: snippet.title,
+        id: snippet.id,
+        content: snippet.content,
+        base_url: state.base_url.clone(),
+    };
+    let mut response = AppState::render(template.render());
+    let csp = format!(
+        "default-src 'none'; style-src 'self' fonts.googleapis.com; font-src fonts.gstatic.com; frame-ancestors {}",
+        state.embed_ancestors
+    );
+    if let Ok(csp) = csp.parse() {
+        response
+            .headers_mut()
+            .insert("Content-Security-Policy", csp);
+    }
+    // X-Frame-Options cannot list origins, browsers honour frame-ancestors instead
+    if state.embed_ancestors.trim() == "'self'" {
+        response
+            .headers_mut()
+            .insert("X-Frame-Options", "sameorigin".parse().unwrap());
+    }
+    response.extensions_mut().insert(Embeddable);
+    response
+}
+
+// The revision is part of the path so the image can be cached forever, any edit changes the url.
+pub async fn snippet_preview(
+    Path((snippet_id, revision)): Path<(u32, i32)>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let snippet = match state.snippets.get(&snippet_id).await {
+        Ok(snippet) if snippet.visible_to(None) => snippet,
+        Ok(_) | Err(sqlx::error::Error::RowNotFound) => {
             return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
         }
-        AppState::server_error(Box::new(error))
+        Err(error) => return AppState::server_error(Box::new(error)),
+    };
+    if snippet.revision != revision {
+        return Redirect::temporary(&format!(
+            "/snippet/preview/{}/{}",
+            snippet.id, snippet.revision
+        ))
+        .into_response();
+    }
+    let image = match state.previews.get(snippet.id, snippet.revision) {
+        Some(image) => image,
+        None => {
+            let id = snippet.id;
+            let rendered = tokio::task::spawn_blocking(move || {
+                preview::render(id, &snippet.title, &snippet.content)
+            })
+            .await;
+            match rendered {
+                Ok(Ok(image)) => {
+                    let image = Bytes::from(image);
+                    state.previews.insert(id, revision, image.clone());
+                    image
+                }
+                Ok(Err(e)) => return AppState::server_error(Box::new(e)),
+                Err(e) => return AppState::server_error(Box::new(e)),
+            }
+        }
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        image,
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct OEmbedQuery {
+    url: String,
+    format: Option<String>,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct OEmbedResponse {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    provider_name: &'static str,
+    provider_url: String,
+    title: String,
+    html: String,
+    width: u32,
+    height: u32,
+    cache_age: u32,
+}
+
+// violation reports are small, anything bigger is not one
+pub const CSP_REPORT_LIMIT: usize = 16 * 1024;
+
+/// Collects Content-Security-Policy violations, both the `application/csp-report` bodies of
+/// `report-uri` and the `application/reports+json` lists of the Reporting API, and logs them.
+pub async fn csp_report(client_ip: ClientIp, body: Bytes) -> StatusCode {
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let reports: Vec<&serde_json::Value> = match &value {
+        serde_json::Value::Array(reports) => reports
+            .iter()
+            .filter(|report| report["type"] == "csp-violation")
+            .map(|report| &report["body"])
+            .collect(),
+        report => vec![&report["csp-report"]],
+    };
+    for report in reports {
+        // the older format uses dashes, the Reporting API camel case
+        let field = |dashed: &str, camel: &str| {
+            report
+                .get(dashed)
+                .or_else(|| report.get(camel))
+                .and_then(|value| value.as_str())
+                .unwrap_or("")
+                .chars()
+                .take(200)
+                .collect::<String>()
+        };
+        tracing::warn!(
+            "csp violation on {} : {} blocked {} (from {})",
+            field("document-uri", "documentURL"),
+            field("violated-directive", "effectiveDirective"),
+            field("blocked-uri", "blockedURL"),
+            client_ip
+        );
+    }
+    StatusCode::NO_CONTENT
+}
+
+// https://oembed.com/#section2
+pub async fn oembed(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<OEmbedQuery>,
+) -> Response {
+    if query
+        .format
+        .as_deref()
+        .is_some_and(|format| format != "json")
+    {
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            "only the json format is supported",
+        )
+            .into_response();
     }
+    let snippet_id = query
+        .url
+        .strip_prefix(&state.base_url)
+        .and_then(|path| {
+            path.strip_prefix("/snippet/view/")
+                .or_else(|| path.strip_prefix("/snippet/embed/"))
+        })
+        .and_then(|id| id.trim_end_matches('/').parse::<u32>().ok());
+    let Some(snippet_id) = snippet_id else {
+        return (StatusCode::NOT_FOUND, "not a snippet url").into_response();
+    };
+    let snippet = match state.snippets.get(&snippet_id).await {
+        Ok(snippet) if snippet.visible_to(None) => snippet,
+        Ok(_) | Err(sqlx::error::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
+        }
+        Err(error) => return AppState::server_error(Box::new(error)),
+    };
+    let width = query.maxwidth.unwrap_or(600).min(600);
+    let height = query.maxheight.unwrap_or(400).min(400);
+    let html = format!(
+        r#"<iframe src="{}/snippet/embed/{}" width="{}" height="{}" frameborder="0" title="{}"></iframe>"#,
+        state.base_url,
+        snippet.id,
+        width,
+        height,
+        askama::filters::escape(&snippet.title, askama::filters::Html)
+            .map(|title| title.to_string())
+            .unwrap_or_default()
+    );
+    let body = OEmbedResponse {
+        version: "1.0",
+        kind: "rich",
+        provider_name: "Snippetbox",
+        provider_url: state.base_url.clone(),
+        title: snippet.title,
+        html,
+        width,
+        height,
+        cache_age: 3600,
+    };
+    // wiki tools may fetch this from the browser
+    ([(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], Json(body)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ReportForm {
+    reason: String,
+    #[serde(default)]
+    details: String,
 }
 
-pub async fn snippet_create(ext_state: Extension<Authenticated>) -> Response {
+const REPORT_DETAILS_MAX: usize = 500;
+
+/// Files a report about the snippet for moderators to look at. Anybody who can see the
+/// snippet may report it.
+pub async fn snippet_report_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    client_ip: ClientIp,
+    Path(snippet_id): Path<u32>,
+    Form(form): Form<ReportForm>,
+) -> Response {
+    let user_id: Option<i32> = session.get("authenticatedUserID").await.unwrap_or_default();
+    let snippet = match state.snippets.get(&snippet_id).await {
+        Ok(snippet) if snippet.visible_to(user_id) => snippet,
+        Ok(_) | Err(sqlx::error::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, "snippet could not be found!").into_response();
+        }
+        Err(error) => return AppState::server_error(Box::new(error)),
+    };
+    let location = format!("/snippet/view/{}", snippet.id);
+    let Some(reason) = ReportReason::parse(&form.reason) else {
+        let _ = session
+            .insert("flash", "Please choose why you are reporting this snippet.")
+            .await;
+        return Redirect::to(&location).into_response();
+    };
+    let details: String = form
+        .details
+        .trim()
+        .chars()
+        .take(REPORT_DETAILS_MAX)
+        .collect();
+    let ip = Some(client_ip.to_string());
+    let flash = match state
+        .reports
+        .create(snippet.id, user_id, ip.as_deref(), reason, &details)
+        .await
+    {
+        Ok(true) => {
+            tracing::info!("snippet {} reported as {}", snippet.id, reason.as_str());
+            "Thank you, a moderator will look into your report."
+        }
+        Ok(false) => "You have reported this snippet already.",
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let _ = session.insert("flash", flash).await;
+    Redirect::to(&location).into_response()
+}
+
+pub async fn snippet_create(user: Option<Extension<CurrentUser>>, csrf: CsrfToken) -> Response {
     // Redirect the user to the relevant page for the snippet.
     // function call for checking authentication may not be required on some paths
-    let is_authenticated = AppState::is_authenticated(ext_state).await;
+    let is_authenticated = AppState::is_authenticated(&user);
     let create = CreateTemplate {
         user_errors: HashMap::new(),
         title: "".to_string(),
         content: "".to_string(),
         expires: 365,
-        is_authenticated: is_authenticated,
+        visibility: VISIBILITY_PUBLIC.to_string(),
+        tags: "".to_string(),
+        secret_findings: Vec::new(),
+        secrets_blocked: false,
+        is_authenticated,
+        // only verified users get here
+        needs_verification: false,
+        csrf_token: csrf.0,
     };
     let template_render_result = create.render();
     AppState::render(template_render_result)
@@ -113,6 +682,7 @@ pub async fn snippet_create(ext_state: Extension<Authenticated>) -> Response {
 pub async fn snippet_create_post(
     State(state): State<Arc<AppState>>,
     session: Session,
+    client_ip: ClientIp,
     snippet_data: SnippetData,
 ) -> Response {
     // default form data size = 10 MB, can be restricted like so
@@ -123,21 +693,77 @@ pub async fn snippet_create_post(
     //     .unwrap()
     //     .as
     //     .to_string();
+    let findings = state.secrets.scan(&snippet_data.content);
+    if !findings.is_empty() && (state.secrets.blocks() || !snippet_data.confirm_secrets) {
+        tracing::info!(
+            "snippet held back, it looks like it contains {} secret(s)",
+            findings.len()
+        );
+        let template = CreateTemplate {
+            user_errors: HashMap::new(),
+            title: snippet_data.title,
+            content: snippet_data.content,
+            expires: snippet_data.expires,
+            visibility: snippet_data.visibility,
+            tags: snippet_data.tags,
+            secret_findings: findings.iter().map(|finding| finding.describe()).collect(),
+            secrets_blocked: state.secrets.blocks(),
+            is_authenticated: true,
+            needs_verification: false,
+            csrf_token: csrf::current(&session).await.unwrap_or_default(),
+        };
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            AppState::render(template.render()),
+        )
+            .into_response();
+    }
+    let user_id: Option<i32> = session.get("authenticatedUserID").await.unwrap_or_default();
+    let held_back = match spam_check_snippet(&state, client_ip, user_id, &snippet_data).await {
+        Ok(held_back) => held_back,
+        Err(response) => return response,
+    };
+    let tags = snippet_data.tag_list();
+    let title = snippet_data.title.clone();
     let result = state
         .snippets
         .insert(
             snippet_data.title,
             snippet_data.content,
             snippet_data.expires.into(),
+            user_id,
+            &snippet_data.visibility,
+            &tags,
+            held_back.as_deref(),
         )
         .await;
     let mut redirection_uri = "/".to_string();
     let mut headers = HeaderMap::new();
     headers.insert(header::LOCATION, redirection_uri.parse().unwrap());
-    if let Err(e) = result {
-        AppState::server_error(Box::new(e));
-    } else {
-        redirection_uri = format!("/snippet/view/{}", result.unwrap());
+    match result {
+        // the snippet and its report are written together, nothing is left behind
+        Err(e) => return AppState::server_error(Box::new(e)),
+        Ok((id, created)) => {
+            if let Some(reasons) = &held_back {
+                tracing::info!("snippet {} held back for review : {}", id, reasons);
+                let _ = session
+                    .insert(
+                        "flash",
+                        "Your snippet has been created. A moderator will look at it before others can see it.",
+                    )
+                    .await;
+                return Redirect::to(&format!("/snippet/view/{}", id)).into_response();
+            }
+            if snippet_data.visibility == VISIBILITY_PUBLIC {
+                state.snippet_events.publish(SnippetCreated {
+                    id: id as i32,
+                    title: title.clone(),
+                    // the way the home page shows it
+                    created: created.to_string(),
+                });
+            }
+            redirection_uri = format!("/snippet/view/{}", id)
+        }
     }
     session
         .insert("flash", "Snippet successfully created!")
@@ -146,24 +772,190 @@ pub async fn snippet_create_post(
     Redirect::to(&redirection_uri).into_response()
 }
 
-pub async fn user_signup() -> Response {
+// accounts younger than this have their snippets checked for spam
+const NEW_ACCOUNT_DAYS: i64 = 7;
+const SPAM_REASONS_MAX: usize = 500;
+
+/// Runs the spam filter over a snippet of a new account. Returns why the snippet should be
+/// held back for moderators, if it should.
+async fn spam_check_snippet(
+    state: &AppState,
+    client_ip: ClientIp,
+    user_id: Option<i32>,
+    snippet_data: &SnippetData,
+) -> Result<Option<String>, Response> {
+    if let Some(user_id) = user_id {
+        let user = match state.users.get(user_id).await {
+            Ok(user) => user,
+            Err(e) => return Err(AppState::server_error(Box::new(e))),
+        };
+        if Utc::now() - user.created > chrono::Duration::days(NEW_ACCOUNT_DAYS) {
+            return Ok(None);
+        }
+    }
+    let ip = Some(client_ip.to_string());
+    let text = format!("{}\n{}", snippet_data.title, snippet_data.content);
+    let verdict = state
+        .spam
+        .check(&Submission {
+            kind: SubmissionKind::Snippet,
+            ip: ip.as_deref(),
+            user_id,
+            text: &text,
+            email: None,
+        })
+        .await;
+    if !verdict.suspicious {
+        return Ok(None);
+    }
+    let reasons = format!(
+        "spam score {} : {}",
+        verdict.score,
+        verdict.reasons.join("; ")
+    );
+    Ok(Some(reasons.chars().take(SPAM_REASONS_MAX).collect()))
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    // for the first connection, browsers only send `Last-Event-ID` when reconnecting
+    last_event_id: Option<i32>,
+}
+
+pub async fn snippet_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok())
+        .or(query.last_event_id);
+    let subscription = state.snippet_events.subscribe(last_event_id);
+    let stream = stream::unfold(subscription, |mut subscription| async move {
+        let event = match subscription.next().await? {
+            Update::Snippet(snippet) => Event::default()
+                .id(snippet.id.to_string())
+                .event("snippet")
+                .json_data(&snippet),
+            Update::Reset => Ok(Event::default().event("reset").data("reload")),
+        };
+        Some((event, subscription))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+const POW_FAILED: &str = "Your browser could not complete the security check. Please make sure JavaScript is enabled and try again.";
+
+/// A proof-of-work challenge for the signup or login form, fetched by `pow.js`.
+pub async fn user_challenge(
+    State(state): State<Arc<AppState>>,
+    Path(form): Path<String>,
+    client_ip: ClientIp,
+) -> Response {
+    let Some(form) = PowForm::parse(&form) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let ip = Some(client_ip.to_string());
+    let activity = match form {
+        PowForm::Signup => match &ip {
+            Some(ip) => state.submissions.count_from_ip(KIND_SIGNUP, ip, 60).await,
+            None => Ok(0),
+        },
+        PowForm::Login => state
+            .login_throttles
+            .failures(None, ip.as_deref())
+            .await
+            .map(i64::from),
+    };
+    let activity = activity.unwrap_or_else(|e| {
+        tracing::error!("could not look up recent activity of {:?} : {}", ip, e);
+        0
+    });
+    let challenge = state.pow.issue(form, ip.as_deref(), activity);
+    ([(header::CACHE_CONTROL, "no-store")], Json(challenge)).into_response()
+}
+
+pub async fn user_signup(csrf: CsrfToken) -> Response {
     let user = SignupTemplate {
         user_errors: HashMap::new(),
         name: "".to_string(),
         email: "".to_string(),
         password: "".to_string(),
         is_authenticated: false,
+        needs_verification: false,
+        csrf_token: csrf.0,
     };
 
     let template_render_result = user.render();
     AppState::render(template_render_result)
 }
 
+// MySQL's error number for a duplicate key
+const ER_DUP_ENTRY: u16 = 1062;
+
 pub async fn user_signup_post(
     State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
     session: Session,
+    client_ip: ClientIp,
     signup_data: SignupData,
 ) -> Response {
+    let ip = Some(client_ip.to_string());
+    if let Err(e) = state.pow.verify(
+        PowForm::Signup,
+        ip.as_deref(),
+        &signup_data.pow_challenge,
+        &signup_data.pow_solution,
+    ) {
+        tracing::info!(
+            "signup of {} refused, proof of work : {}",
+            signup_data.email,
+            e
+        );
+        let template = SignupTemplate {
+            user_errors: HashMap::from([("signup_error".to_string(), POW_FAILED.to_string())]),
+            name: signup_data.name,
+            email: signup_data.email,
+            password: "".to_string(),
+            is_authenticated: false,
+            needs_verification: false,
+            csrf_token: csrf.0,
+        };
+        return (StatusCode::FORBIDDEN, AppState::render(template.render())).into_response();
+    }
+    let verdict = state
+        .spam
+        .check(&Submission {
+            kind: SubmissionKind::Signup,
+            ip: ip.as_deref(),
+            user_id: None,
+            text: &signup_data.name,
+            email: Some(&signup_data.email),
+        })
+        .await;
+    if verdict.suspicious {
+        tracing::info!(
+            "signup of {} refused, spam score {} : {}",
+            signup_data.email,
+            verdict.score,
+            verdict.reasons.join("; ")
+        );
+        let template = SignupTemplate {
+            user_errors: HashMap::from([(
+                "signup_error".to_string(),
+                "We could not create your account right now. Please try again later.".to_string(),
+            )]),
+            name: signup_data.name,
+            email: signup_data.email,
+            password: "".to_string(),
+            is_authenticated: false,
+            needs_verification: false,
+            csrf_token: csrf.0,
+        };
+        return (StatusCode::FORBIDDEN, AppState::render(template.render())).into_response();
+    }
     let result = state
         .users
         .insert(
@@ -176,44 +968,166 @@ pub async fn user_signup_post(
     let mut headers = HeaderMap::new();
     headers.insert(header::LOCATION, redirection_uri.parse().unwrap());
 
-    //  centralised DB error handling required. This is too ugly
-    if let Err(e) = result {
-        let e: sqlx::error::Error = *(e.downcast().unwrap()); // problematic if bcrypt has an error. Fix.
-        if let sqlx::error::Error::Database(err) = e {
-            let x = *(err.downcast::<MySqlDatabaseError>());
-            if x.number() == 1062 {
-                let mut user = SignupTemplate {
-                    user_errors: HashMap::new(),
-                    name: signup_data.name,
-                    email: signup_data.email,
-                    password: "".to_string(),
-                    is_authenticated: false,
-                };
-                user.user_errors.insert(
-                    "signup_error".to_string(),
-                    "This email is already in use".to_string(),
-                );
-                let template_render_result = user.render();
-                return AppState::render(template_render_result);
-            }
+    // an email already in use is the only error the user can do something about, hashing
+    // the password or any other database error is ours
+    let id = match result.map_err(|e| e.downcast::<sqlx::Error>()) {
+        Ok(id) => id,
+        Err(Ok(e))
+            if matches!(&*e, sqlx::Error::Database(err)
+                if err.try_downcast_ref::<MySqlDatabaseError>()
+                    .is_some_and(|err| err.number() == ER_DUP_ENTRY)) =>
+        {
+            let mut user = SignupTemplate {
+                user_errors: HashMap::new(),
+                name: signup_data.name,
+                email: signup_data.email,
+                password: "".to_string(),
+                is_authenticated: false,
+                needs_verification: false,
+                csrf_token: csrf.0,
+            };
+            user.user_errors.insert(
+                "signup_error".to_string(),
+                "This email is already in use".to_string(),
+            );
+            let template_render_result = user.render();
+            return AppState::render(template_render_result);
         }
-    } else {
-        redirection_uri = format!("/user/login")
+        Err(Ok(e)) => return AppState::server_error(e),
+        Err(Err(e)) => return AppState::server_error(e),
+    };
+    redirection_uri = "/user/login".to_string();
+    if let Err(e) = send_verification_email(&state, id as i32, signup_data.email).await {
+        // the user can ask for another link once logged in
+        tracing::error!("verification email for user {} failed : {}", id, e);
     }
     session
-        .insert("flash", "Your signup was successful. Please log in.")
+        .insert(
+            "flash",
+            "Your signup was successful. We've sent you an email to confirm your address. Please log in.",
+        )
         .await
         .unwrap();
     Redirect::to(&redirection_uri).into_response()
 }
 
-pub async fn user_login(session: Session) -> Response {
-    let mut login_template = LoginTemplate::new("".to_string(), "".to_string());
-    let flash_present: Option<String> = session.remove("flash").await.unwrap();
-    if let Some(flash) = flash_present {
-        if flash.len() > 0 {
-            login_template.flash = flash;
+async fn send_verification_email(
+    state: &AppState,
+    user_id: i32,
+    to: String,
+) -> Result<(), sqlx::Error> {
+    let token = state.email_verifications.create(user_id).await?;
+    let email = Email {
+        to,
+        subject: "Confirm your Snippetbox email address".to_string(),
+        body: format!(
+            "Welcome to Snippetbox!\n\n\
+            Please confirm your email address by opening this link:\n\n{}/user/verify-email?token={}\n\n\
+            If you didn't sign up, you can ignore this email.\n",
+            state.base_url, token
+        ),
+    };
+    mailer::send_in_background(state.mailer.clone(), email);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    token: String,
+}
+
+pub async fn user_verify_email(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+    Query(query): Query<VerifyEmailQuery>,
+) -> Response {
+    match state.email_verifications.verify(&query.token).await {
+        Ok(Some(user_id)) => {
+            tracing::info!("email address of user {} verified", user_id);
+            let _ = session
+                .insert("flash", "Thanks, your email address has been verified.")
+                .await;
+            Redirect::to("/").into_response()
+        }
+        Ok(None) => {
+            let is_authenticated = AppState::is_authenticated(&user);
+            AppState::error_page(
+                StatusCode::BAD_REQUEST,
+                "This verification link is invalid or has expired. Log in to request a new one.",
+                is_authenticated,
+                csrf.0,
+            )
+        }
+        Err(e) => AppState::server_error(Box::new(e)),
+    }
+}
+
+pub async fn user_verify_email_resend(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let user = match state.users.get(user_id).await {
+        Ok(user) => user,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let flash = if user.email_verified {
+        "Your email address is already verified.".to_string()
+    } else {
+        match state
+            .email_verifications
+            .resend_blocked_until(user_id)
+            .await
+        {
+            Ok(Some(until)) => {
+                let minutes = (until - Utc::now()).num_minutes() + 1;
+                format!(
+                    "Please wait {} minute{} before asking for another verification email.",
+                    minutes,
+                    if minutes == 1 { "" } else { "s" }
+                )
+            }
+            Ok(None) => {
+                if let Err(e) = send_verification_email(&state, user_id, user.email.clone()).await {
+                    return AppState::server_error(Box::new(e));
+                }
+                format!("We've sent a new verification link to {}.", user.email)
+            }
+            Err(e) => return AppState::server_error(Box::new(e)),
         }
+    };
+    let _ = session.insert("flash", flash).await;
+    Redirect::to("/").into_response()
+}
+
+pub async fn user_login(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    csrf: CsrfToken,
+    client_ip: ClientIp,
+) -> Response {
+    let failures = state
+        .login_throttles
+        .failures(None, Some(&client_ip.to_string()))
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("could not look up failed login attempts : {}", e);
+            0
+        });
+    let mut login_template = LoginTemplate::new("".to_string(), "".to_string())
+        .with_options(&state.login_options)
+        .with_pow(state.pow.login_required(failures));
+    login_template.csrf_token = csrf.0;
+    let flash_present: Option<String> = session.remove("flash").await.unwrap();
+    if let Some(flash) = flash_present
+        && !flash.is_empty()
+    {
+        login_template.flash = flash;
     }
     let template_render_result = login_template.render();
     AppState::render(template_render_result)
@@ -221,38 +1135,119 @@ pub async fn user_login(session: Session) -> Response {
 
 pub async fn user_login_post(
     State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
     session: Session,
+    headers: HeaderMap,
+    client_ip: ClientIp,
     login_data: LoginData,
 ) -> Response {
     let redirection_uri = "/snippet/create";
+    let ip = Some(client_ip.to_string());
+    match state
+        .login_throttles
+        .locked_until(&login_data.email, ip.as_deref())
+        .await
+    {
+        Ok(Some(until)) => {
+            let minutes = (until - Utc::now()).num_minutes() + 1;
+            let mut login_template = LoginTemplate::new(login_data.email, "".to_string())
+                .with_options(&state.login_options);
+            login_template.csrf_token = csrf.0;
+            login_template.user_errors.insert(
+                "login_error".to_string(),
+                format!(
+                    "Too many failed login attempts. Please try again in {} minute{}.",
+                    minutes,
+                    if minutes == 1 { "" } else { "s" }
+                ),
+            );
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                AppState::render(login_template.render()),
+            )
+                .into_response();
+        }
+        Ok(None) => {}
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    let failures = match state
+        .login_throttles
+        .failures(Some(&login_data.email), ip.as_deref())
+        .await
+    {
+        Ok(failures) => failures,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    if state.pow.login_required(failures)
+        && let Err(e) = state.pow.verify(
+            PowForm::Login,
+            ip.as_deref(),
+            &login_data.pow_challenge,
+            &login_data.pow_solution,
+        )
+    {
+        tracing::info!(
+            "login of {} refused, proof of work : {}",
+            login_data.email,
+            e
+        );
+        let mut login_template = LoginTemplate::new(login_data.email, "".to_string())
+            .with_options(&state.login_options)
+            .with_pow(true);
+        login_template.csrf_token = csrf.0;
+        login_template
+            .user_errors
+            .insert("login_error".to_string(), POW_FAILED.to_string());
+        return (
+            StatusCode::FORBIDDEN,
+            AppState::render(login_template.render()),
+        )
+            .into_response();
+    }
     match state
         .users
         .authenticate(&login_data.email, &login_data.password)
         .await
     {
         Ok(id) => {
+            let two_factor = match state.two_factor.secret(id).await {
+                Ok(secret) => secret.is_some(),
+                Err(e) => return AppState::server_error(Box::new(e)),
+            };
+            if two_factor {
+                // failed attempts are only forgiven once the second factor has been passed as well
+                tracing::info!("password accepted, second factor required : {}", id);
+                let remember = login_data.remember.is_some();
+                return begin_two_factor(&session, id, &login_data.email, remember).await;
+            }
             tracing::info!("login successful : {}", id);
-            match session.cycle_id().await {
-                Ok(_) => {
-                    let ses_auth_ins = session.insert("authenticatedUserID", id).await;
-                    if let Err(e) = ses_auth_ins {
-                        tracing::error!(
-                            "could not insert the authenticated user id into the session : {}",
-                            e.to_string()
-                        );
-                    }
-                    Redirect::to(&redirection_uri).into_response()
-                }
-                Err(e) => {
-                    tracing::info!("session could not be renewed : {}", e);
-                    AppState::server_error(Box::new(e))
-                }
+            if let Err(e) = state.login_throttles.reset(&login_data.email).await {
+                tracing::error!("could not reset failed login attempts : {}", e);
+            }
+            if let Err(response) = log_in(&state, &session, &headers, client_ip, id).await {
+                return response;
             }
+            let response = Redirect::to(redirection_uri).into_response();
+            if login_data.remember.is_some() {
+                return remember_device(&state, &session, id, response).await;
+            }
+            response
         }
         Err(e) => {
+            if e.downcast_ref::<ErrInvalidCredentials>().is_some()
+                && let Err(e) = state
+                    .login_throttles
+                    .record_failure(&login_data.email, ip.as_deref())
+                    .await
+            {
+                tracing::error!("could not record failed login attempt : {}", e);
+            }
             // centralised error handling that takes errors,
             // downcasts them with proper handling and then returns the correct error type
-            let mut login_template = LoginTemplate::new(login_data.email, "".to_string());
+            let mut login_template = LoginTemplate::new(login_data.email, "".to_string())
+                .with_options(&state.login_options)
+                .with_pow(state.pow.login_required(failures + 1));
+            login_template.csrf_token = csrf.0;
             login_template
                 .user_errors
                 .insert("login_error".to_string(), e.to_string());
@@ -263,30 +1258,2340 @@ pub async fn user_login_post(
     }
 }
 
-pub async fn user_logout_post(session: Session) -> Response {
-    match session.cycle_id().await {
-        Ok(_) => {
-            // why renew token first and then remove authenticatedUserID , why not do it before ?
-            let auth_rmv: Result<Option<i32>, tower_sessions::session::Error> =
-                session.remove("authenticatedUserID").await;
-            match auth_rmv {
-                Ok(_) => {
-                    // error handling here
-                    tracing::info!("session renewal successful");
-                    let _ = session
-                        .insert("flash", "You've been successfully logged out")
-                        .await;
-                    Redirect::to("/").into_response()
-                }
-                Err(e) => {
+/// Turns the session into a logged in one. The session gets a new id and csrf token,
+/// and is recorded so that it can be revoked later on.
+pub async fn log_in(
+    state: &AppState,
+    session: &Session,
+    headers: &HeaderMap,
+    client_ip: ClientIp,
+    id: i32,
+) -> Result<(), Response> {
+    match state.users.is_disabled(id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            tracing::info!("login refused for disabled user {}", id);
+            return Err(AppState::error_page(
+                StatusCode::FORBIDDEN,
+                "This account has been disabled. Please contact the site administrator.",
+                false,
+                csrf::current(session).await.unwrap_or_default(),
+            ));
+        }
+        Err(e) => return Err(AppState::server_error(Box::new(e))),
+    }
+    if let Err(e) = session.cycle_id().await {
+        tracing::info!("session could not be renewed : {}", e);
+        return Err(AppState::server_error(Box::new(e)));
+    }
+    if let Err(e) = csrf::rotate(session).await {
+        tracing::error!("could not rotate the csrf token : {}", e);
+        return Err(AppState::server_error(Box::new(e)));
+    }
+    let ses_auth_ins = session.insert("authenticatedUserID", id).await;
+    if let Err(e) = ses_auth_ins {
+        tracing::error!(
+            "could not insert the authenticated user id into the session : {}",
+            e.to_string()
+        );
+    }
+    // the new id is only assigned once the session is stored
+    if let Err(e) = session.save().await {
+        tracing::error!("could not save the session : {}", e);
+        return Err(AppState::server_error(Box::new(e)));
+    }
+    let session_id = session.id().map(|id| id.to_string()).unwrap_or_default();
+    let ip = Some(client_ip.to_string());
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    if let Err(e) = state
+        .user_sessions
+        .record(&session_id, id, ip.as_deref(), user_agent)
+        .await
+    {
+        tracing::error!("could not record the session : {}", e);
+        return Err(AppState::server_error(Box::new(e)));
+    }
+    Ok(())
+}
+
+/// Hands the freshly logged in device a "remember me" cookie along with `response`.
+/// Failing to do so is not worth failing the login over.
+async fn remember_device(
+    state: &AppState,
+    session: &Session,
+    user_id: i32,
+    mut response: Response,
+) -> Response {
+    let session_id = session.id().map(|id| id.to_string()).unwrap_or_default();
+    match state.remember_tokens.issue(user_id, &session_id).await {
+        Ok(token) => {
+            let cookie = remember::set_cookie(&token, &state.base_url);
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+        Err(e) => tracing::error!("could not remember the device of user {} : {}", user_id, e),
+    }
+    response
+}
+
+/// The logged in user's id, routes behind `require_auth` can rely on it being there.
+async fn current_user_id(session: &Session) -> Result<i32, Response> {
+    match session.get::<i32>("authenticatedUserID").await {
+        Ok(Some(id)) => Ok(id),
+        Ok(None) => Err(Redirect::to("/user/login").into_response()),
+        Err(e) => Err(AppState::server_error(Box::new(e))),
+    }
+}
+
+pub async fn user_logout_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    headers: HeaderMap,
+) -> Response {
+    // logging out also means not being let back in by the cookie
+    let remembered = remember::from_headers(&headers);
+    if let Some(token) = &remembered
+        && let Err(e) = state.remember_tokens.forget(token).await
+    {
+        tracing::error!("could not forget the remembered device : {}", e);
+    }
+    if let Some(session_id) = session.id()
+        && let Err(e) = state.user_sessions.forget(&session_id.to_string()).await
+    {
+        tracing::error!("could not forget the session : {}", e);
+    }
+    match session.cycle_id().await {
+        Ok(_) => {
+            // why renew token first and then remove authenticatedUserID , why not do it before ?
+            let auth_rmv: Result<Option<i32>, tower_sessions::session::Error> =
+                session.remove("authenticatedUserID").await;
+            match auth_rmv {
+                Ok(_) => {
+                    // error handling here
+                    tracing::info!("session renewal successful");
+                    if let Err(e) = csrf::rotate(&session).await {
+                        tracing::error!("could not rotate the csrf token : {}", e);
+                        return AppState::server_error(Box::new(e));
+                    }
+                    let _ = session
+                        .insert("flash", "You've been successfully logged out")
+                        .await;
+                    let mut response = Redirect::to("/").into_response();
+                    if remembered.is_some() {
+                        response
+                            .headers_mut()
+                            .append(header::SET_COOKIE, remember::clear_cookie());
+                    }
+                    response
+                }
+                Err(e) => {
                     tracing::error!("problems in removing auth details from session : {}", e);
-                    AppState::server_error(Box::new(e)).into_response()
+                    AppState::server_error(Box::new(e))
                 }
             }
         }
         Err(e) => {
             tracing::error!("session could not be renewed : {}", e);
-            AppState::server_error(Box::new(e)).into_response()
+            AppState::server_error(Box::new(e))
+        }
+    }
+}
+
+// what the session has to remember while the browser is away at the provider
+const OIDC_STATE_KEY: &str = "oidcState";
+const OIDC_NONCE_KEY: &str = "oidcNonce";
+const OIDC_PKCE_VERIFIER_KEY: &str = "oidcPkceVerifier";
+
+const FAILED_SSO_LOGIN: &str = "Single sign-on did not succeed. Please try logging in again.";
+
+pub async fn user_login_oidc(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+) -> Response {
+    let Some(oidc) = &state.oidc else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let request = match oidc.authorize().await {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::error!("single sign-on could not be started : {}", e);
+            return AppState::error_page(
+                StatusCode::BAD_GATEWAY,
+                "Single sign-on is not available right now. Please try again later.",
+                false,
+                csrf.0,
+            );
+        }
+    };
+    let stored = async {
+        session.insert(OIDC_STATE_KEY, request.state).await?;
+        session.insert(OIDC_NONCE_KEY, request.nonce).await?;
+        session
+            .insert(OIDC_PKCE_VERIFIER_KEY, request.pkce_verifier)
+            .await
+    };
+    if let Err(e) = stored.await {
+        return AppState::server_error(Box::new(e));
+    }
+    Redirect::to(&request.url).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    // set instead of the code when the provider refused or the user cancelled
+    error: Option<String>,
+}
+
+pub async fn user_login_oidc_callback(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+    headers: HeaderMap,
+    client_ip: ClientIp,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Response {
+    let Some(oidc) = &state.oidc else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // each sign-in attempt can come back only once
+    let expected_state: Option<String> = session.remove(OIDC_STATE_KEY).await.unwrap_or_default();
+    let nonce: Option<String> = session.remove(OIDC_NONCE_KEY).await.unwrap_or_default();
+    let pkce_verifier: Option<String> = session
+        .remove(OIDC_PKCE_VERIFIER_KEY)
+        .await
+        .unwrap_or_default();
+    let failed = |message: &str| {
+        AppState::error_page(StatusCode::BAD_REQUEST, message, false, csrf.0.clone())
+    };
+    if let Some(error) = query.error {
+        tracing::info!("single sign-on refused by the provider : {}", error);
+        return failed(FAILED_SSO_LOGIN);
+    }
+    let (Some(expected_state), Some(nonce), Some(pkce_verifier), Some(code), Some(returned_state)) = (
+        expected_state,
+        nonce,
+        pkce_verifier,
+        query.code,
+        query.state,
+    ) else {
+        return failed(FAILED_SSO_LOGIN);
+    };
+    let identity = match oidc
+        .exchange(&expected_state, &returned_state, code, pkce_verifier, nonce)
+        .await
+    {
+        Ok(identity) => identity,
+        Err(e) => {
+            tracing::info!("single sign-on rejected : {}", e);
+            return failed(FAILED_SSO_LOGIN);
+        }
+    };
+    let user_id = match federated_user(&state.identities, &identity).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            return failed(
+                "Your single sign-on account has no verified email address, so it cannot be used to log in here.",
+            );
+        }
+        Err(e) => return AppState::server_error(e),
+    };
+    // the provider stands in for the password, a second factor set up here still applies
+    let two_factor = match state.two_factor.secret(user_id).await {
+        Ok(secret) => secret.is_some(),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    if two_factor {
+        tracing::info!(
+            "single sign-on accepted, second factor required : {}",
+            user_id
+        );
+        // wrong codes count against the account's own address, whatever the provider says
+        let user = match state.users.get(user_id).await {
+            Ok(user) => user,
+            Err(e) => return AppState::server_error(Box::new(e)),
+        };
+        return begin_two_factor(&session, user_id, &user.email, false).await;
+    }
+    tracing::info!("login successful through single sign-on : {}", user_id);
+    match log_in(&state, &session, &headers, client_ip, user_id).await {
+        Ok(_) => Redirect::to("/snippet/create").into_response(),
+        Err(response) => response,
+    }
+}
+
+// the state of a passkey ceremony, kept in the session between its two requests
+const PASSKEY_REGISTRATION_KEY: &str = "passkeyRegistration";
+const PASSKEY_LOGIN_KEY: &str = "passkeyLogin";
+
+const FAILED_PASSKEY_LOGIN: &str =
+    "That passkey could not be used to log in. Please try again or log in another way.";
+
+/// A passkey being added, tied to the user who started it.
+#[derive(Serialize, Deserialize)]
+struct PendingPasskey {
+    user_id: i32,
+    name: String,
+    state: PasskeyRegistration,
+}
+
+// what the passkey script gets back: where to go next, or messages keyed like the page's forms
+#[derive(Serialize)]
+struct PasskeyOutcome {
+    redirect: Option<&'static str>,
+    errors: HashMap<String, String>,
+}
+
+fn passkey_done(redirect: &'static str) -> Response {
+    Json(PasskeyOutcome {
+        redirect: Some(redirect),
+        errors: HashMap::new(),
+    })
+    .into_response()
+}
+
+fn passkey_failed(status: StatusCode, field: &str, message: &str) -> Response {
+    let errors = HashMap::from([(field.to_string(), message.to_string())]);
+    (
+        status,
+        Json(PasskeyOutcome {
+            redirect: None,
+            errors,
+        }),
+    )
+        .into_response()
+}
+
+/// Challenges the browser to sign in with any passkey it holds for this site.
+pub async fn user_login_passkey_start(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+) -> Response {
+    let (options, ceremony) = match state.webauthn.start_login() {
+        Ok(started) => started,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    if let Err(e) = session.insert(PASSKEY_LOGIN_KEY, ceremony).await {
+        return AppState::server_error(Box::new(e));
+    }
+    Json(options).into_response()
+}
+
+pub async fn user_login_passkey_finish(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    headers: HeaderMap,
+    client_ip: ClientIp,
+    Json(credential): Json<PublicKeyCredential>,
+) -> Response {
+    // each challenge can be answered only once
+    let ceremony: Option<DiscoverableAuthentication> =
+        session.remove(PASSKEY_LOGIN_KEY).await.unwrap_or_default();
+    let failed = || {
+        passkey_failed(
+            StatusCode::UNAUTHORIZED,
+            "login_error",
+            FAILED_PASSKEY_LOGIN,
+        )
+    };
+    let Some(ceremony) = ceremony else {
+        return failed();
+    };
+    let (handle, credential_id) = match state.webauthn.identify(&credential) {
+        Ok(identified) => identified,
+        Err(e) => {
+            tracing::info!("passkey login without a usable user handle : {}", e);
+            return failed();
+        }
+    };
+    let user_id = match state.passkeys.user_for(handle).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return failed(),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let stored = match state.passkeys.find(user_id, credential_id).await {
+        Ok(Some(stored)) => stored,
+        // removed on the account page, the authenticator does not know yet
+        Ok(None) => return failed(),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let result = match state
+        .webauthn
+        .finish_login(&credential, ceremony, &stored.passkey)
+    {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::info!("passkey login of user {} rejected : {}", user_id, e);
+            return failed();
+        }
+    };
+    let mut passkey = stored.passkey;
+    let changed = passkey.update_credential(&result) == Some(true);
+    if let Err(e) = state.passkeys.used(&passkey, changed).await {
+        tracing::error!("could not note the use of a passkey : {}", e);
+    }
+    // the authenticator verified the user itself, that is two factors already
+    tracing::info!("login successful with a passkey : {}", user_id);
+    match log_in(&state, &session, &headers, client_ip, user_id).await {
+        Ok(_) => passkey_done("/snippet/create"),
+        Err(response) => response,
+    }
+}
+
+pub async fn user_forgot_password(csrf: CsrfToken) -> Response {
+    let template = ForgotPasswordTemplate {
+        email: "".to_string(),
+        user_errors: HashMap::new(),
+        is_authenticated: false,
+        needs_verification: false,
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+pub async fn user_forgot_password_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    forgot_data: ForgotPasswordData,
+) -> Response {
+    match state.users.find_by_email(&forgot_data.email).await {
+        Ok(Some((id, email))) => {
+            let token = match state.password_resets.create(id).await {
+                Ok(token) => token,
+                Err(e) => return AppState::server_error(Box::new(e)),
+            };
+            let email = Email {
+                to: email,
+                subject: "Reset your Snippetbox password".to_string(),
+                body: format!(
+                    "Someone asked to reset the password of your Snippetbox account.\n\n\
+                    To choose a new password, open this link within {} minutes:\n\n{}/user/reset-password?token={}\n\n\
+                    If this wasn't you, you can ignore this email.\n",
+                    RESET_TOKEN_MINUTES, state.base_url, token
+                ),
+            };
+            // sent in the background so the response takes as long as for unknown addresses
+            mailer::send_in_background(state.mailer.clone(), email);
+        }
+        Ok(None) => {
+            tracing::info!(
+                "password reset requested for unknown email {}",
+                forgot_data.email
+            );
+        }
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    // the same answer either way, so the form cannot be used to find out who has an account
+    let _ = session
+        .insert(
+            "flash",
+            "If an account exists for that email address, we've sent it a link to reset the password.",
+        )
+        .await;
+    Redirect::to("/user/login").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordQuery {
+    token: String,
+}
+
+const INVALID_RESET_LINK: &str =
+    "This password reset link is invalid or has expired. Please request a new one.";
+
+pub async fn user_reset_password(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    Query(query): Query<ResetPasswordQuery>,
+) -> Response {
+    let is_authenticated = AppState::is_authenticated(&user);
+    match state.password_resets.is_valid(&query.token).await {
+        Ok(true) => {
+            let template = ResetPasswordTemplate {
+                token: query.token,
+                user_errors: HashMap::new(),
+                is_authenticated,
+                needs_verification: false,
+                csrf_token: csrf.0,
+            };
+            AppState::render(template.render())
+        }
+        Ok(false) => AppState::error_page(
+            StatusCode::BAD_REQUEST,
+            INVALID_RESET_LINK,
+            is_authenticated,
+            csrf.0,
+        ),
+        Err(e) => AppState::server_error(Box::new(e)),
+    }
+}
+
+pub async fn user_reset_password_post(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+    reset_data: ResetPasswordData,
+) -> Response {
+    let user_id = match state
+        .password_resets
+        .reset_password(&reset_data.token, &reset_data.password)
+        .await
+    {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            let is_authenticated = AppState::is_authenticated(&user);
+            return AppState::error_page(
+                StatusCode::BAD_REQUEST,
+                INVALID_RESET_LINK,
+                is_authenticated,
+                csrf.0,
+            );
+        }
+        Err(e) => return AppState::server_error(e),
+    };
+    // whoever knew the old password may still be logged in somewhere
+    state.live_sessions.end_sessions(user_id, "");
+    match state.user_sessions.revoke_all(user_id).await {
+        Ok(revoked) => tracing::info!(
+            "password of user {} was reset, {} session(s) ended",
+            user_id,
+            revoked
+        ),
+        Err(e) => {
+            tracing::error!("sessions of user {} could not be ended : {}", user_id, e);
+            return AppState::server_error(Box::new(e));
+        }
+    }
+    // the current session may have been one of them, start from a clean one
+    if let Err(e) = session.flush().await {
+        tracing::error!("could not end the current session : {}", e);
+    }
+    let _ = session
+        .insert(
+            "flash",
+            "Your password has been reset. Please log in with your new password.",
+        )
+        .await;
+    Redirect::to("/user/login").into_response()
+}
+
+// between the password and the second factor the session only knows who is logging in
+const TWO_FACTOR_USER_KEY: &str = "twoFactorUserID";
+const TWO_FACTOR_EMAIL_KEY: &str = "twoFactorEmail";
+const TWO_FACTOR_STARTED_KEY: &str = "twoFactorStarted";
+const TWO_FACTOR_REMEMBER_KEY: &str = "twoFactorRemember";
+const TWO_FACTOR_PENDING_SECRET_KEY: &str = "twoFactorPendingSecret";
+// the second step has to follow the password within this many seconds
+const TWO_FACTOR_LOGIN_SECONDS: i64 = 5 * 60;
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    password: String,
+}
+
+// the user and email of a login that is waiting for its second factor
+async fn pending_two_factor(session: &Session) -> Option<(i32, String)> {
+    let started: i64 = session.get(TWO_FACTOR_STARTED_KEY).await.ok()??;
+    if Utc::now().timestamp() - started > TWO_FACTOR_LOGIN_SECONDS {
+        return None;
+    }
+    let user_id: i32 = session.get(TWO_FACTOR_USER_KEY).await.ok()??;
+    let email: String = session.get(TWO_FACTOR_EMAIL_KEY).await.ok()??;
+    Some((user_id, email))
+}
+
+// remembers who is logging in and sends them on to the second step
+async fn begin_two_factor(
+    session: &Session,
+    user_id: i32,
+    email: &str,
+    remember: bool,
+) -> Response {
+    let started = Utc::now().timestamp();
+    if let Err(e) = session.insert(TWO_FACTOR_USER_KEY, user_id).await {
+        return AppState::server_error(Box::new(e));
+    }
+    let _ = session.insert(TWO_FACTOR_EMAIL_KEY, email).await;
+    let _ = session.insert(TWO_FACTOR_STARTED_KEY, started).await;
+    let _ = session.insert(TWO_FACTOR_REMEMBER_KEY, remember).await;
+    Redirect::to("/user/login/two-factor").into_response()
+}
+
+async fn clear_pending_two_factor(session: &Session) {
+    let _ = session.remove::<i32>(TWO_FACTOR_USER_KEY).await;
+    let _ = session.remove::<String>(TWO_FACTOR_EMAIL_KEY).await;
+    let _ = session.remove::<i64>(TWO_FACTOR_STARTED_KEY).await;
+    let _ = session.remove::<bool>(TWO_FACTOR_REMEMBER_KEY).await;
+}
+
+pub async fn user_login_two_factor(session: Session, csrf: CsrfToken) -> Response {
+    if pending_two_factor(&session).await.is_none() {
+        return Redirect::to("/user/login").into_response();
+    }
+    let template = LoginTwoFactorTemplate {
+        error: "".to_string(),
+        is_authenticated: false,
+        needs_verification: false,
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+pub async fn user_login_two_factor_post(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+    headers: HeaderMap,
+    client_ip: ClientIp,
+    Form(form): Form<CodeForm>,
+) -> Response {
+    let Some((user_id, email)) = pending_two_factor(&session).await else {
+        clear_pending_two_factor(&session).await;
+        let _ = session
+            .insert("flash", "Your login has expired. Please log in again.")
+            .await;
+        return Redirect::to("/user/login").into_response();
+    };
+    let render_error = |status: StatusCode, error: String, csrf_token: String| {
+        let template = LoginTwoFactorTemplate {
+            error,
+            is_authenticated: false,
+            needs_verification: false,
+            csrf_token,
+        };
+        (status, AppState::render(template.render())).into_response()
+    };
+    // guessing codes counts against the same limits as guessing passwords
+    let ip = Some(client_ip.to_string());
+    match state
+        .login_throttles
+        .locked_until(&email, ip.as_deref())
+        .await
+    {
+        Ok(Some(until)) => {
+            let minutes = (until - Utc::now()).num_minutes() + 1;
+            return render_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Too many failed login attempts. Please try again in {} minute{}.",
+                    minutes,
+                    if minutes == 1 { "" } else { "s" }
+                ),
+                csrf.0,
+            );
         }
+        Ok(None) => {}
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    let secret = match state.two_factor.secret(user_id).await {
+        Ok(secret) => secret,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let accepted = match secret {
+        // turned off from another session in the meantime, the password was enough
+        None => Ok(true),
+        Some(secret) => match totp::verify(&secret.secret, &form.code, secret.last_step) {
+            Some(step) => state.two_factor.use_step(user_id, step).await,
+            // anything that is not a current code may still be one of the recovery codes
+            None => {
+                state
+                    .two_factor
+                    .use_recovery_code(user_id, &form.code)
+                    .await
+            }
+        },
+    };
+    match accepted {
+        Ok(true) => {
+            tracing::info!("login successful : {}", user_id);
+            if let Err(e) = state.login_throttles.reset(&email).await {
+                tracing::error!("could not reset failed login attempts : {}", e);
+            }
+            let remember: bool = session
+                .get(TWO_FACTOR_REMEMBER_KEY)
+                .await
+                .unwrap_or_default()
+                .unwrap_or_default();
+            clear_pending_two_factor(&session).await;
+            if let Err(response) = log_in(&state, &session, &headers, client_ip, user_id).await {
+                return response;
+            }
+            let response = Redirect::to("/snippet/create").into_response();
+            if remember {
+                return remember_device(&state, &session, user_id, response).await;
+            }
+            response
+        }
+        Ok(false) => {
+            tracing::info!("second factor rejected for user {}", user_id);
+            if let Err(e) = state
+                .login_throttles
+                .record_failure(&email, ip.as_deref())
+                .await
+            {
+                tracing::error!("could not record failed login attempt : {}", e);
+            }
+            render_error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "That code is not valid. Please try again.".to_string(),
+                csrf.0,
+            )
+        }
+        Err(e) => AppState::server_error(Box::new(e)),
+    }
+}
+
+async fn render_two_factor_settings(
+    state: &AppState,
+    user_id: i32,
+    needs_verification: bool,
+    error: &str,
+    flash: String,
+    csrf_token: String,
+) -> Response {
+    let enabled = match state.two_factor.secret(user_id).await {
+        Ok(secret) => secret.is_some(),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let recovery_codes_left = match state.two_factor.recovery_codes_left(user_id).await {
+        Ok(count) => count,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let template = TwoFactorTemplate {
+        enabled,
+        recovery_codes_left,
+        error: error.to_string(),
+        flash,
+        is_authenticated: true,
+        needs_verification,
+        csrf_token,
+    };
+    let status = if error.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    (status, AppState::render(template.render())).into_response()
+}
+
+pub async fn two_factor_settings(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let flash: Option<String> = session.remove("flash").await.unwrap_or_default();
+    let needs_verification = AppState::needs_verification(&user);
+    render_two_factor_settings(
+        &state,
+        user_id,
+        needs_verification,
+        "",
+        flash.unwrap_or_default(),
+        csrf.0,
+    )
+    .await
+}
+
+/// Starts enrolling an authenticator. The secret stays in the session until the first code
+/// confirms it, so an abandoned enrolment never locks anybody out.
+pub async fn two_factor_enrol_post(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+    Form(form): Form<PasswordForm>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    match state.users.check_password(user_id, &form.password).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let needs_verification = AppState::needs_verification(&user);
+            return render_two_factor_settings(
+                &state,
+                user_id,
+                needs_verification,
+                "Your password is incorrect.",
+                "".to_string(),
+                csrf.0,
+            )
+            .await;
+        }
+        Err(e) => return AppState::server_error(e),
+    }
+    if let Err(e) = session
+        .insert(TWO_FACTOR_PENDING_SECRET_KEY, totp::generate_secret())
+        .await
+    {
+        return AppState::server_error(Box::new(e));
+    }
+    Redirect::to("/account/two-factor/confirm").into_response()
+}
+
+async fn render_two_factor_confirm(
+    state: &AppState,
+    user_id: i32,
+    secret: String,
+    needs_verification: bool,
+    error: &str,
+    csrf_token: String,
+) -> Response {
+    let user = match state.users.get(user_id).await {
+        Ok(user) => user,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let qr_svg = match totp::qr_svg(&totp::provisioning_uri(&secret, &user.email)) {
+        Ok(svg) => svg,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let template = TwoFactorConfirmTemplate {
+        qr_svg,
+        secret,
+        error: error.to_string(),
+        is_authenticated: true,
+        needs_verification,
+        csrf_token,
+    };
+    let status = if error.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    (status, AppState::render(template.render())).into_response()
+}
+
+pub async fn two_factor_confirm(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let Ok(Some(secret)) = session.get::<String>(TWO_FACTOR_PENDING_SECRET_KEY).await else {
+        return Redirect::to("/account/two-factor").into_response();
+    };
+    let needs_verification = AppState::needs_verification(&user);
+    render_two_factor_confirm(&state, user_id, secret, needs_verification, "", csrf.0).await
+}
+
+pub async fn two_factor_confirm_post(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+    Form(form): Form<CodeForm>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let Ok(Some(secret)) = session.get::<String>(TWO_FACTOR_PENDING_SECRET_KEY).await else {
+        return Redirect::to("/account/two-factor").into_response();
+    };
+    let needs_verification = AppState::needs_verification(&user);
+    let Some(step) = totp::verify(&secret, &form.code, None) else {
+        return render_two_factor_confirm(
+            &state,
+            user_id,
+            secret,
+            needs_verification,
+            "That code is not valid. Check the time on your phone and try again.",
+            csrf.0,
+        )
+        .await;
+    };
+    let codes = totp::generate_recovery_codes();
+    if let Err(e) = state.two_factor.enable(user_id, &secret, &codes).await {
+        return AppState::server_error(Box::new(e));
+    }
+    // the confirmation code cannot be used again to log in
+    if let Err(e) = state.two_factor.use_step(user_id, step).await {
+        tracing::error!("could not record the used time step : {}", e);
+    }
+    let _ = session
+        .remove::<String>(TWO_FACTOR_PENDING_SECRET_KEY)
+        .await;
+    tracing::info!("two-factor authentication enabled for user {}", user_id);
+    // the only time the codes are shown, they are stored hashed
+    let template = RecoveryCodesTemplate {
+        codes,
+        is_authenticated: true,
+        needs_verification,
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+pub async fn two_factor_disable_post(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+    Form(form): Form<PasswordForm>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    match state.users.check_password(user_id, &form.password).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let needs_verification = AppState::needs_verification(&user);
+            return render_two_factor_settings(
+                &state,
+                user_id,
+                needs_verification,
+                "Your password is incorrect.",
+                "".to_string(),
+                csrf.0,
+            )
+            .await;
+        }
+        Err(e) => return AppState::server_error(e),
+    }
+    if let Err(e) = state.two_factor.disable(user_id).await {
+        return AppState::server_error(Box::new(e));
+    }
+    tracing::info!("two-factor authentication disabled for user {}", user_id);
+    let _ = session
+        .insert("flash", "Two-factor authentication has been turned off.")
+        .await;
+    Redirect::to("/account/two-factor").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionForm {
+    session: String,
+}
+
+pub async fn account_sessions(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let current = session.id().map(|id| id.to_string()).unwrap_or_default();
+    let sessions = match state.user_sessions.list(user_id).await {
+        Ok(sessions) => sessions,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let format = "%d %b %Y at %H:%M UTC";
+    let sessions = sessions
+        .into_iter()
+        .map(|device| SessionRow {
+            handle: token::digest(&device.session_id),
+            ip: device.ip.unwrap_or_else(|| "unknown".to_string()),
+            user_agent: device
+                .user_agent
+                .unwrap_or_else(|| "Unknown device".to_string()),
+            created: device.created.format(format).to_string(),
+            last_seen: device.last_seen.format(format).to_string(),
+            current: device.session_id == current,
+        })
+        .collect();
+    let flash: Option<String> = session.remove("flash").await.unwrap_or_default();
+    let template = SessionsTemplate {
+        sessions,
+        flash: flash.unwrap_or_default(),
+        is_authenticated: true,
+        needs_verification: AppState::needs_verification(&user),
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+/// Logs out one of the user's other devices. It is gone from the store at once, so its
+/// next request is anonymous.
+pub async fn account_session_revoke_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Form(form): Form<RevokeSessionForm>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let current = session.id().map(|id| id.to_string()).unwrap_or_default();
+    let sessions = match state.user_sessions.list(user_id).await {
+        Ok(sessions) => sessions,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let target = sessions
+        .into_iter()
+        .map(|device| device.session_id)
+        .find(|session_id| {
+            *session_id != current && csrf::matches(&token::digest(session_id), &form.session)
+        });
+    let flash = match target {
+        Some(session_id) => match state.user_sessions.revoke(user_id, &session_id).await {
+            Ok(_) => {
+                state.live_sessions.end_session(&session_id);
+                tracing::info!("user {} ended one of their sessions", user_id);
+                "That session has been logged out."
+            }
+            Err(e) => return AppState::server_error(Box::new(e)),
+        },
+        // already expired, or logged out in the meantime
+        None => "That session had already ended.",
+    };
+    let _ = session.insert("flash", flash).await;
+    Redirect::to("/account/sessions").into_response()
+}
+
+pub async fn account_sessions_revoke_others_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let current = session.id().map(|id| id.to_string()).unwrap_or_default();
+    match state
+        .user_sessions
+        .revoke_all_except(user_id, &current)
+        .await
+    {
+        Ok(revoked) => tracing::info!("user {} ended {} other session(s)", user_id, revoked),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    state.live_sessions.end_sessions(user_id, &current);
+    let _ = session
+        .insert("flash", "All your other sessions have been logged out.")
+        .await;
+    Redirect::to("/account/sessions").into_response()
+}
+
+const INCORRECT_PASSWORD: &str = "Your password is incorrect.";
+
+async fn render_account(
+    state: &AppState,
+    user_id: i32,
+    user_errors: HashMap<String, String>,
+    flash: String,
+    csrf_token: String,
+) -> Response {
+    let user = match state.users.get(user_id).await {
+        Ok(user) => user,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let passkeys = match state.passkeys.list(user_id).await {
+        Ok(passkeys) => passkeys,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let format = "%d %b %Y";
+    let passkeys = passkeys
+        .into_iter()
+        .map(|passkey| PasskeyRow {
+            id: URL_SAFE_NO_PAD.encode(&passkey.credential_id),
+            name: passkey.name,
+            created: passkey.created.format(format).to_string(),
+            last_used: passkey
+                .last_used
+                .map(|last_used| last_used.format(format).to_string())
+                .unwrap_or_else(|| "never".to_string()),
+        })
+        .collect();
+    let status = if user_errors.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    let template = AccountTemplate {
+        name: user.name,
+        email: user.email,
+        email_verified: user.email_verified,
+        passkeys,
+        is_moderator: user.role >= Role::Moderator,
+        is_admin: user.role == Role::Admin,
+        user_errors,
+        flash,
+        is_authenticated: true,
+        needs_verification: !user.email_verified,
+        csrf_token,
+    };
+    (status, AppState::render(template.render())).into_response()
+}
+
+// renders the account page with a single error under one of its forms
+async fn account_error(
+    state: &AppState,
+    user_id: i32,
+    field: &str,
+    message: &str,
+    csrf_token: String,
+) -> Response {
+    let user_errors = HashMap::from([(field.to_string(), message.to_string())]);
+    render_account(state, user_id, user_errors, "".to_string(), csrf_token).await
+}
+
+pub async fn account(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let flash: Option<String> = session.remove("flash").await.unwrap_or_default();
+    render_account(
+        &state,
+        user_id,
+        HashMap::new(),
+        flash.unwrap_or_default(),
+        csrf.0,
+    )
+    .await
+}
+
+pub async fn account_name_post(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+    Form(form): Form<NameData>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(e) = form.validate() {
+        let user_errors = field_errors("name", &e);
+        return render_account(&state, user_id, user_errors, "".to_string(), csrf.0).await;
+    }
+    if let Err(e) = state.users.update_name(user_id, &form.name).await {
+        return AppState::server_error(Box::new(e));
+    }
+    let _ = session.insert("flash", "Your name has been changed.").await;
+    Redirect::to("/account").into_response()
+}
+
+pub async fn account_email_post(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+    Form(form): Form<EmailData>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(e) = form.validate() {
+        let user_errors = field_errors("email", &e);
+        return render_account(&state, user_id, user_errors, "".to_string(), csrf.0).await;
+    }
+    match state.users.check_password(user_id, &form.password).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return account_error(
+                &state,
+                user_id,
+                "email_password",
+                INCORRECT_PASSWORD,
+                csrf.0,
+            )
+            .await;
+        }
+        Err(e) => return AppState::server_error(e),
+    }
+    let user = match state.users.get(user_id).await {
+        Ok(user) => user,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    match state.users.find_by_email(&form.email).await {
+        Ok(Some((id, _))) if id == user_id => {
+            let _ = session
+                .insert("flash", "That is already your email address.")
+                .await;
+            return Redirect::to("/account").into_response();
+        }
+        Ok(Some(_)) => {
+            return account_error(
+                &state,
+                user_id,
+                "email_email",
+                "This email is already in use",
+                csrf.0,
+            )
+            .await;
+        }
+        Ok(None) => {}
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    if let Err(e) = state.users.update_email(user_id, &form.email).await {
+        return AppState::server_error(Box::new(e));
+    }
+    tracing::info!("user {} changed their email address", user_id);
+    // the old address hears about it, in case somebody else made the change
+    let notice = Email {
+        to: user.email,
+        subject: "Your Snippetbox email address was changed".to_string(),
+        body: format!(
+            "The email address of your Snippetbox account was changed to {}.\n\n\
+            If this wasn't you, please contact us right away.\n",
+            form.email
+        ),
+    };
+    mailer::send_in_background(state.mailer.clone(), notice);
+    if let Err(e) = send_verification_email(&state, user_id, form.email.clone()).await {
+        tracing::error!("verification email for user {} failed : {}", user_id, e);
+    }
+    let _ = session
+        .insert(
+            "flash",
+            format!(
+                "Your email address has been changed. We've sent a link to {} to confirm it.",
+                form.email
+            ),
+        )
+        .await;
+    Redirect::to("/account").into_response()
+}
+
+pub async fn account_password_post(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+    headers: HeaderMap,
+    client_ip: ClientIp,
+    Form(form): Form<PasswordData>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(e) = form.validate() {
+        let user_errors = field_errors("password", &e);
+        return render_account(&state, user_id, user_errors, "".to_string(), csrf.0).await;
+    }
+    match state
+        .users
+        .check_password(user_id, &form.current_password)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return account_error(
+                &state,
+                user_id,
+                "password_current_password",
+                INCORRECT_PASSWORD,
+                csrf.0,
+            )
+            .await;
+        }
+        Err(e) => return AppState::server_error(e),
+    }
+    if let Err(e) = state.users.update_password(user_id, &form.password).await {
+        return AppState::server_error(e);
+    }
+    // other sessions end, this one carries on under a new id
+    match state.user_sessions.revoke_all(user_id).await {
+        Ok(revoked) => tracing::info!(
+            "password of user {} was changed, {} session(s) ended",
+            user_id,
+            revoked
+        ),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    state.live_sessions.end_sessions(user_id, "");
+    if let Err(response) = log_in(&state, &session, &headers, client_ip, user_id).await {
+        return response;
+    }
+    let _ = session
+        .insert("flash", "Your password has been changed.")
+        .await;
+    Redirect::to("/account").into_response()
+}
+
+pub async fn account_delete_post(
+    State(state): State<Arc<AppState>>,
+    csrf: CsrfToken,
+    session: Session,
+    Form(form): Form<DeleteAccountData>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    if let Err(e) = form.validate() {
+        let user_errors = field_errors("delete", &e);
+        return render_account(&state, user_id, user_errors, "".to_string(), csrf.0).await;
+    }
+    match state.users.check_password(user_id, &form.password).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return account_error(
+                &state,
+                user_id,
+                "delete_password",
+                INCORRECT_PASSWORD,
+                csrf.0,
+            )
+            .await;
+        }
+        Err(e) => return AppState::server_error(e),
+    }
+    // the sessions have to go first, deleting the user forgets which ones were theirs
+    if let Err(e) = state.user_sessions.revoke_all(user_id).await {
+        return AppState::server_error(Box::new(e));
+    }
+    state.live_sessions.end_sessions(user_id, "");
+    let keep_snippets = form.snippets == SNIPPETS_ANONYMISE;
+    if let Err(e) = state.users.delete(user_id, keep_snippets).await {
+        return AppState::server_error(Box::new(e));
+    }
+    tracing::info!(
+        "user {} deleted their account, snippets {}",
+        user_id,
+        if keep_snippets { "kept" } else { "deleted" }
+    );
+    if let Err(e) = session.flush().await {
+        tracing::error!("could not end the current session : {}", e);
+    }
+    let _ = session
+        .insert("flash", "Your account has been deleted.")
+        .await;
+    Redirect::to("/").into_response()
+}
+
+/// Checks the password and hands the browser the options to create a passkey with.
+pub async fn account_passkey_register_start(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Json(mut form): Json<PasskeyData>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    form.name = form.name.trim().to_string();
+    if let Err(e) = form.validate() {
+        let errors = field_errors("passkey", &e);
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(PasskeyOutcome {
+                redirect: None,
+                errors,
+            }),
+        )
+            .into_response();
+    }
+    match state.users.check_password(user_id, &form.password).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return passkey_failed(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "passkey_password",
+                INCORRECT_PASSWORD,
+            );
+        }
+        Err(e) => return AppState::server_error(e),
+    }
+    let user = match state.users.get(user_id).await {
+        Ok(user) => user,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let handle = match state.passkeys.handle(user_id).await {
+        Ok(handle) => handle,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let existing: Vec<_> = match state.passkeys.list(user_id).await {
+        Ok(passkeys) => passkeys.into_iter().map(|stored| stored.passkey).collect(),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let (options, ceremony) =
+        match state
+            .webauthn
+            .start_registration(handle, &user.email, &user.name, &existing)
+        {
+            Ok(started) => started,
+            Err(e) => return AppState::server_error(Box::new(e)),
+        };
+    let pending = PendingPasskey {
+        user_id,
+        name: form.name,
+        state: ceremony,
+    };
+    if let Err(e) = session.insert(PASSKEY_REGISTRATION_KEY, pending).await {
+        return AppState::server_error(Box::new(e));
+    }
+    Json(options).into_response()
+}
+
+pub async fn account_passkey_register_finish(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Json(credential): Json<RegisterPublicKeyCredential>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let pending: Option<PendingPasskey> = session
+        .remove(PASSKEY_REGISTRATION_KEY)
+        .await
+        .unwrap_or_default();
+    let Some(pending) = pending.filter(|pending| pending.user_id == user_id) else {
+        return passkey_failed(
+            StatusCode::BAD_REQUEST,
+            "passkey",
+            "Adding the passkey took too long. Please try again.",
+        );
+    };
+    let passkey = match state
+        .webauthn
+        .finish_registration(&credential, &pending.state)
+    {
+        Ok(passkey) => passkey,
+        Err(e) => {
+            tracing::info!("passkey of user {} rejected : {}", user_id, e);
+            return passkey_failed(
+                StatusCode::BAD_REQUEST,
+                "passkey",
+                "Your passkey could not be added. Please try again.",
+            );
+        }
+    };
+    match state
+        .passkeys
+        .insert(user_id, &pending.name, &passkey)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return passkey_failed(
+                StatusCode::CONFLICT,
+                "passkey",
+                "That passkey has been added already.",
+            );
+        }
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    tracing::info!("user {} added a passkey", user_id);
+    let _ = session
+        .insert("flash", "Your passkey has been added.")
+        .await;
+    passkey_done("/account")
+}
+
+#[derive(Deserialize)]
+pub struct RemovePasskeyForm {
+    passkey: String,
+}
+
+pub async fn account_passkey_delete_post(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Form(form): Form<RemovePasskeyForm>,
+) -> Response {
+    let user_id = match current_user_id(&session).await {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let removed = match URL_SAFE_NO_PAD.decode(&form.passkey) {
+        Ok(credential_id) => match state.passkeys.delete(user_id, &credential_id).await {
+            Ok(removed) => removed,
+            Err(e) => return AppState::server_error(Box::new(e)),
+        },
+        Err(_) => false,
+    };
+    let flash = if removed {
+        tracing::info!("user {} removed a passkey", user_id);
+        "Your passkey has been removed. You may also want to delete it from your device."
+    } else {
+        "That passkey had already been removed."
+    };
+    let _ = session.insert("flash", flash).await;
+    Redirect::to("/account").into_response()
+}
+
+const ADMIN_PAGE_SIZE: i64 = 50;
+const ADMIN_STATS_DAYS: i64 = 14;
+
+#[derive(Deserialize)]
+pub struct AdminListQuery {
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    state: String,
+    page: Option<i64>,
+}
+
+impl AdminListQuery {
+    fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    // the same listing on another page, or nothing when there is no such page
+    fn link(&self, path: &str, page: i64, exists: bool) -> String {
+        if !exists {
+            return "".to_string();
+        }
+        let mut params = vec![("q", self.q.clone())];
+        if !self.state.is_empty() {
+            params.push(("state", self.state.clone()));
+        }
+        params.push(("page", page.to_string()));
+        match serde_urlencoded::to_string(params) {
+            Ok(query) => format!("{}?{}", path, query),
+            Err(_) => "".to_string(),
+        }
+    }
+}
+
+fn admin_users_url(email: &str) -> String {
+    format!(
+        "/admin/users?{}",
+        serde_urlencoded::to_string([("q", email)]).unwrap_or_default()
+    )
+}
+
+pub async fn admin(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+) -> Response {
+    let (users, disabled_users) = match state.users.counts().await {
+        Ok(counts) => counts,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let live_snippets = match state.snippets.live_count().await {
+        Ok(count) => count,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let per_day = match state.snippets.created_per_day(ADMIN_STATS_DAYS).await {
+        Ok(per_day) => per_day,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let per_day = per_day
+        .into_iter()
+        .map(|(day, count)| (day.format("%d %b %Y").to_string(), count))
+        .collect();
+    let template = AdminTemplate {
+        users,
+        disabled_users,
+        live_snippets,
+        per_day,
+        is_authenticated: true,
+        needs_verification: AppState::needs_verification(&user),
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+pub async fn admin_users(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<CurrentUser>,
+    csrf: CsrfToken,
+    session: Session,
+    Query(query): Query<AdminListQuery>,
+) -> Response {
+    let page = query.page();
+    // one more than fits on the page tells whether there is a next one
+    let mut users = match state
+        .users
+        .search(&query.q, ADMIN_PAGE_SIZE + 1, (page - 1) * ADMIN_PAGE_SIZE)
+        .await
+    {
+        Ok(users) => users,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let more = users.len() as i64 > ADMIN_PAGE_SIZE;
+    users.truncate(ADMIN_PAGE_SIZE as usize);
+    let users = users
+        .into_iter()
+        .map(|user| AdminUserRow {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            role: user.role.as_str(),
+            email_verified: user.email_verified,
+            created: user.created.format("%d %b %Y").to_string(),
+            disabled: user.disabled.is_some(),
+            snippets: user.snippets,
+        })
+        .collect();
+    let lockouts = match state.login_throttles.active_lockouts().await {
+        Ok(lockouts) => lockouts,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let lockouts = lockouts
+        .into_iter()
+        .map(|lockout| AdminLockoutRow {
+            kind: lockout.kind,
+            key: lockout.key,
+            failures: lockout.failures,
+            locked_until: lockout
+                .locked_until
+                .format("%d %b %Y at %H:%M:%S UTC")
+                .to_string(),
+        })
+        .collect();
+    let flash: Option<String> = session.remove("flash").await.unwrap_or_default();
+    let template = AdminUsersTemplate {
+        users,
+        lockouts,
+        previous: query.link("/admin/users", page - 1, page > 1),
+        next: query.link("/admin/users", page + 1, more),
+        query: query.q,
+        admin_id: admin.id,
+        flash: flash.unwrap_or_default(),
+        is_authenticated: true,
+        needs_verification: !admin.email_verified,
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    kind: String,
+    key: String,
+}
+
+/// Lets an email or ip locked out after failed logins try again right away.
+pub async fn admin_lockout_unlock_post(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<CurrentUser>,
+    session: Session,
+    Form(form): Form<UnlockForm>,
+) -> Response {
+    let flash = match state.login_throttles.unlock(&form.kind, &form.key).await {
+        Ok(true) => {
+            tracing::info!("admin {} unlocked {} {}", admin.id, form.kind, form.key);
+            format!("{} can try to log in again.", form.key)
+        }
+        // it ran out in the meantime
+        Ok(false) => format!("{} was not locked out anymore.", form.key),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let _ = session.insert("flash", flash).await;
+    Redirect::to("/admin/users").into_response()
+}
+
+// the user an admin action is about, or the page to show when there is none
+async fn admin_target(state: &AppState, session: &Session, id: i32) -> Result<User, Response> {
+    match state.users.get(id).await {
+        Ok(user) => Ok(user),
+        Err(sqlx::Error::RowNotFound) => Err(AppState::error_page(
+            StatusCode::NOT_FOUND,
+            "That user does not exist.",
+            true,
+            csrf::current(session).await.unwrap_or_default(),
+        )),
+        Err(e) => Err(AppState::server_error(Box::new(e))),
+    }
+}
+
+/// Keeps the user from logging in, and logs them out everywhere.
+pub async fn admin_user_disable_post(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Response {
+    let user = match admin_target(&state, &session, id).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    // nobody would be left to undo it
+    if user.id == admin.id {
+        let _ = session
+            .insert("flash", "You cannot disable your own account.")
+            .await;
+        return Redirect::to(&admin_users_url(&user.email)).into_response();
+    }
+    if let Err(e) = state.users.set_disabled(user.id, true).await {
+        return AppState::server_error(Box::new(e));
+    }
+    match state.user_sessions.revoke_all(user.id).await {
+        Ok(revoked) => tracing::info!(
+            "admin {} disabled user {} and ended {} session(s)",
+            admin.id,
+            user.id,
+            revoked
+        ),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    state.live_sessions.end_sessions(user.id, "");
+    let _ = session
+        .insert(
+            "flash",
+            format!("{} has been disabled and logged out.", user.email),
+        )
+        .await;
+    Redirect::to(&admin_users_url(&user.email)).into_response()
+}
+
+pub async fn admin_user_enable_post(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Response {
+    let user = match admin_target(&state, &session, id).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.users.set_disabled(user.id, false).await {
+        return AppState::server_error(Box::new(e));
+    }
+    tracing::info!("admin {} enabled user {}", admin.id, user.id);
+    let _ = session
+        .insert("flash", format!("{} can log in again.", user.email))
+        .await;
+    Redirect::to(&admin_users_url(&user.email)).into_response()
+}
+
+/// Replaces the user's password with one nobody knows, logs them out everywhere and
+/// emails them a link to choose a new one.
+pub async fn admin_user_reset_password_post(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Response {
+    let user = match admin_target(&state, &session, id).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    if let Err(e) = state
+        .users
+        .update_password(user.id, &token::generate())
+        .await
+    {
+        return AppState::server_error(e);
+    }
+    if let Err(e) = state.user_sessions.revoke_all(user.id).await {
+        return AppState::server_error(Box::new(e));
+    }
+    state.live_sessions.end_sessions(user.id, "");
+    let token = match state.password_resets.create(user.id).await {
+        Ok(token) => token,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Choose a new Snippetbox password".to_string(),
+        body: format!(
+            "An administrator has reset the password of your Snippetbox account.\n\n\
+            To choose a new password, open this link within {} minutes:\n\n{}/user/reset-password?token={}\n\n\
+            After that, you can ask for a new link on the login page.\n",
+            RESET_TOKEN_MINUTES, state.base_url, token
+        ),
+    };
+    mailer::send_in_background(state.mailer.clone(), email);
+    tracing::info!("admin {} reset the password of user {}", admin.id, user.id);
+    let _ = session
+        .insert(
+            "flash",
+            format!(
+                "The password of {} has been reset and a link to choose a new one sent.",
+                user.email
+            ),
+        )
+        .await;
+    Redirect::to(&admin_users_url(&user.email)).into_response()
+}
+
+pub async fn admin_snippets(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+    Query(query): Query<AdminListQuery>,
+) -> Response {
+    let page = query.page();
+    let filter = SnippetState::parse(&query.state);
+    let mut snippets = match state
+        .snippets
+        .browse(
+            &query.q,
+            filter,
+            ADMIN_PAGE_SIZE + 1,
+            (page - 1) * ADMIN_PAGE_SIZE,
+        )
+        .await
+    {
+        Ok(snippets) => snippets,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let more = snippets.len() as i64 > ADMIN_PAGE_SIZE;
+    snippets.truncate(ADMIN_PAGE_SIZE as usize);
+    let now = Utc::now();
+    let format = "%d %b %Y at %H:%M UTC";
+    let snippets = snippets
+        .into_iter()
+        .map(|snippet| AdminSnippetRow {
+            id: snippet.id,
+            title: snippet.title,
+            author: match (snippet.author, snippet.user_id) {
+                (Some(author), _) => author,
+                (None, Some(_)) => "deleted user".to_string(),
+                (None, None) => "anonymous".to_string(),
+            },
+            visibility: snippet.visibility,
+            created: snippet.created.format(format).to_string(),
+            expires: snippet.expires.format(format).to_string(),
+            status: if snippet.deleted.is_some() {
+                "deleted"
+            } else if snippet.hidden.is_some() {
+                "hidden"
+            } else if snippet.expires <= now {
+                "expired"
+            } else {
+                "live"
+            },
+        })
+        .collect();
+    let flash: Option<String> = session.remove("flash").await.unwrap_or_default();
+    let template = AdminSnippetsTemplate {
+        snippets,
+        previous: query.link("/admin/snippets", page - 1, page > 1),
+        next: query.link("/admin/snippets", page + 1, more),
+        query: query.q,
+        state: filter.as_str(),
+        flash: flash.unwrap_or_default(),
+        is_authenticated: true,
+        needs_verification: AppState::needs_verification(&user),
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+async fn admin_set_snippet_deleted(
+    state: &AppState,
+    session: &Session,
+    admin: &CurrentUser,
+    id: i32,
+    deleted: bool,
+) -> Response {
+    match state.snippets.set_deleted(id, deleted).await {
+        Ok(true) if deleted => state.live_sessions.close(id),
+        Ok(true) => {}
+        Ok(false) => {
+            return AppState::error_page(
+                StatusCode::NOT_FOUND,
+                if deleted {
+                    "That snippet does not exist or has been deleted already."
+                } else {
+                    "That snippet does not exist or has not been deleted."
+                },
+                true,
+                csrf::current(session).await.unwrap_or_default(),
+            );
+        }
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    let flash = if deleted {
+        tracing::info!("admin {} deleted snippet {}", admin.id, id);
+        format!("Snippet {} has been deleted.", id)
+    } else {
+        tracing::info!("admin {} restored snippet {}", admin.id, id);
+        format!("Snippet {} has been restored.", id)
+    };
+    let _ = session.insert("flash", flash).await;
+    Redirect::to("/admin/snippets").into_response()
+}
+
+pub async fn admin_snippet_delete_post(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Response {
+    admin_set_snippet_deleted(&state, &session, &admin, id, true).await
+}
+
+pub async fn admin_snippet_restore_post(
+    State(state): State<Arc<AppState>>,
+    Extension(admin): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Response {
+    admin_set_snippet_deleted(&state, &session, &admin, id, false).await
+}
+
+const MODERATION_PAGE_SIZE: i64 = 50;
+
+#[derive(Deserialize)]
+pub struct ModerationQuery {
+    page: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ModerationDeleteForm {
+    // what happens to the author besides losing the snippet: nothing, warn or suspend
+    #[serde(default)]
+    author: String,
+}
+
+pub async fn moderation(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    csrf: CsrfToken,
+    session: Session,
+    Query(query): Query<ModerationQuery>,
+) -> Response {
+    let page = query.page.unwrap_or(1).max(1);
+    let mut reports = match state
+        .reports
+        .open(MODERATION_PAGE_SIZE + 1, (page - 1) * MODERATION_PAGE_SIZE)
+        .await
+    {
+        Ok(reports) => reports,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let more = reports.len() as i64 > MODERATION_PAGE_SIZE;
+    reports.truncate(MODERATION_PAGE_SIZE as usize);
+    let reports = reports
+        .into_iter()
+        .map(|report| ReportRow {
+            id: report.id,
+            snippet_id: report.snippet_id,
+            title: report.title,
+            reason: report.reason.label(),
+            details: report.details,
+            reporter: match (report.reporter, report.reporter_ip) {
+                (Some(reporter), _) => reporter,
+                (None, Some(ip)) => format!("anonymous ({})", ip),
+                (None, None) => "spam filter".to_string(),
+            },
+            created: report.created.format("%d %b %Y at %H:%M UTC").to_string(),
+            hidden: report.hidden.is_some(),
+            author: report.author.unwrap_or_else(|| "anonymous".to_string()),
+            has_author: report.author_id.is_some(),
+        })
+        .collect();
+    let flash: Option<String> = session.remove("flash").await.unwrap_or_default();
+    let template = ModerationTemplate {
+        reports,
+        previous: if page > 1 {
+            format!("/moderation?page={}", page - 1)
+        } else {
+            "".to_string()
+        },
+        next: if more {
+            format!("/moderation?page={}", page + 1)
+        } else {
+            "".to_string()
+        },
+        flash: flash.unwrap_or_default(),
+        is_authenticated: true,
+        needs_verification: AppState::needs_verification(&user),
+        csrf_token: csrf.0,
+    };
+    AppState::render(template.render())
+}
+
+// the open report a moderator acts on, or the page to show when it has been dealt with
+async fn moderation_target(
+    state: &AppState,
+    session: &Session,
+    id: i32,
+) -> Result<OpenReport, Response> {
+    match state.reports.find_open(id).await {
+        Ok(Some(report)) => Ok(report),
+        Ok(None) => {
+            // another moderator got there first
+            let _ = session
+                .insert("flash", "That report has been dealt with already.")
+                .await;
+            Err(Redirect::to("/moderation").into_response())
+        }
+        Err(e) => Err(AppState::server_error(Box::new(e))),
+    }
+}
+
+/// Closes the reports about the snippet without doing anything to it. A snippet hidden
+/// pending review is shown again.
+pub async fn moderation_dismiss_post(
+    State(state): State<Arc<AppState>>,
+    Extension(moderator): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Response {
+    let report = match moderation_target(&state, &session, id).await {
+        Ok(report) => report,
+        Err(response) => return response,
+    };
+    // only a snippet hidden pending review has anything to show again
+    if let Err(e) = state.snippets.set_hidden(report.snippet_id, false).await {
+        return AppState::server_error(Box::new(e));
+    }
+    match state
+        .reports
+        .resolve(report.snippet_id, moderator.id, Resolution::Dismissed)
+        .await
+    {
+        Ok(resolved) => tracing::info!(
+            "moderator {} dismissed {} report(s) about snippet {}",
+            moderator.id,
+            resolved,
+            report.snippet_id
+        ),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    let _ = session
+        .insert(
+            "flash",
+            format!(
+                "The reports about \"{}\" have been dismissed.",
+                report.title
+            ),
+        )
+        .await;
+    Redirect::to("/moderation").into_response()
+}
+
+/// Hides the snippet from everyone but its owner and moderators. The reports stay in the
+/// queue until the snippet is either deleted or the reports dismissed.
+pub async fn moderation_hide_post(
+    State(state): State<Arc<AppState>>,
+    Extension(moderator): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+) -> Response {
+    let report = match moderation_target(&state, &session, id).await {
+        Ok(report) => report,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.snippets.set_hidden(report.snippet_id, true).await {
+        return AppState::server_error(Box::new(e));
+    }
+    tracing::info!(
+        "moderator {} hid snippet {} pending review",
+        moderator.id,
+        report.snippet_id
+    );
+    let _ = session
+        .insert(
+            "flash",
+            format!("\"{}\" is hidden until it has been reviewed.", report.title),
+        )
+        .await;
+    Redirect::to("/moderation").into_response()
+}
+
+/// Deletes the snippet, closes its reports and, if asked to, warns or suspends its author.
+pub async fn moderation_delete_post(
+    State(state): State<Arc<AppState>>,
+    Extension(moderator): Extension<CurrentUser>,
+    session: Session,
+    Path(id): Path<i32>,
+    Form(form): Form<ModerationDeleteForm>,
+) -> Response {
+    let report = match moderation_target(&state, &session, id).await {
+        Ok(report) => report,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.snippets.set_deleted(report.snippet_id, true).await {
+        return AppState::server_error(Box::new(e));
+    }
+    state.live_sessions.close(report.snippet_id);
+    match state
+        .reports
+        .resolve(report.snippet_id, moderator.id, Resolution::Deleted)
+        .await
+    {
+        Ok(resolved) => tracing::info!(
+            "moderator {} deleted snippet {} over {} report(s)",
+            moderator.id,
+            report.snippet_id,
+            resolved
+        ),
+        Err(e) => return AppState::server_error(Box::new(e)),
+    }
+    let mut flash = format!("\"{}\" has been deleted.", report.title);
+    if let Some(author_id) = report.author_id
+        && (form.author == "warn" || form.author == "suspend")
+    {
+        let author = match state.users.get(author_id).await {
+            Ok(author) => author,
+            Err(e) => return AppState::server_error(Box::new(e)),
+        };
+        if form.author == "suspend" {
+            // staff are dealt with by an admin, not by each other
+            if author.role >= Role::Moderator {
+                flash.push_str(" Its author is a moderator or admin and was not suspended.");
+            } else {
+                if let Err(e) = state.users.set_disabled(author.id, true).await {
+                    return AppState::server_error(Box::new(e));
+                }
+                if let Err(e) = state.user_sessions.revoke_all(author.id).await {
+                    return AppState::server_error(Box::new(e));
+                }
+                state.live_sessions.end_sessions(author.id, "");
+                tracing::info!("moderator {} suspended user {}", moderator.id, author.id);
+                flash.push_str(&format!(" {} has been suspended.", author.email));
+            }
+        } else {
+            let email = Email {
+                to: author.email.clone(),
+                subject: "Your Snippetbox snippet has been removed".to_string(),
+                body: format!(
+                    "A moderator has removed your snippet \"{}\" after it was reported as {}.\n\n\
+                    Please make sure what you share follows the rules of the site. Accounts that \
+                    keep breaking them are suspended.\n",
+                    report.title,
+                    report.reason.label().to_lowercase()
+                ),
+            };
+            mailer::send_in_background(state.mailer.clone(), email);
+            tracing::info!("moderator {} warned user {}", moderator.id, author.id);
+            flash.push_str(&format!(" {} has been warned.", author.email));
+        }
+    }
+    let _ = session.insert("flash", flash).await;
+    Redirect::to("/moderation").into_response()
+}
+
+#[derive(Clone, Copy)]
+enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+impl FeedFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "atom",
+            FeedFormat::Rss => "rss",
+        }
+    }
+}
+
+pub async fn feed_atom(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    site_feed(state, headers, FeedFormat::Atom).await
+}
+
+pub async fn feed_rss(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    site_feed(state, headers, FeedFormat::Rss).await
+}
+
+pub async fn user_feed_atom(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<i32>,
+    headers: HeaderMap,
+) -> Response {
+    user_feed(state, user_id, headers, FeedFormat::Atom).await
+}
+
+pub async fn user_feed_rss(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<i32>,
+    headers: HeaderMap,
+) -> Response {
+    user_feed(state, user_id, headers, FeedFormat::Rss).await
+}
+
+pub async fn tag_feed_atom(
+    State(state): State<Arc<AppState>>,
+    Path(tag): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    tag_feed(state, tag, headers, FeedFormat::Atom).await
+}
+
+pub async fn tag_feed_rss(
+    State(state): State<Arc<AppState>>,
+    Path(tag): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    tag_feed(state, tag, headers, FeedFormat::Rss).await
+}
+
+async fn site_feed(state: Arc<AppState>, headers: HeaderMap, format: FeedFormat) -> Response {
+    render_feed(
+        &state,
+        &headers,
+        format,
+        "Snippetbox".to_string(),
+        "Snippetbox".to_string(),
+        format!("/feed.{}", format.extension()),
+        LatestFilter::All,
+    )
+    .await
+}
+
+async fn user_feed(
+    state: Arc<AppState>,
+    user_id: i32,
+    headers: HeaderMap,
+    format: FeedFormat,
+) -> Response {
+    let name = match state.users.name(user_id).await {
+        Ok(name) => name,
+        Err(sqlx::error::Error::RowNotFound) => {
+            return (StatusCode::NOT_FOUND, "user could not be found!").into_response();
+        }
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    render_feed(
+        &state,
+        &headers,
+        format,
+        format!("Snippets by {} - Snippetbox", name),
+        name,
+        format!("/user/{}/feed.{}", user_id, format.extension()),
+        LatestFilter::User(user_id),
+    )
+    .await
+}
+
+async fn tag_feed(
+    state: Arc<AppState>,
+    tag: String,
+    headers: HeaderMap,
+    format: FeedFormat,
+) -> Response {
+    let tag = tag.to_lowercase();
+    render_feed(
+        &state,
+        &headers,
+        format,
+        format!("Snippets tagged {} - Snippetbox", tag),
+        "Snippetbox".to_string(),
+        format!("/tag/{}/feed.{}", percent_encode(&tag), format.extension()),
+        LatestFilter::Tag(&tag),
+    )
+    .await
+}
+
+async fn render_feed(
+    state: &AppState,
+    headers: &HeaderMap,
+    format: FeedFormat,
+    title: String,
+    author: String,
+    self_path: String,
+    filter: LatestFilter<'_>,
+) -> Response {
+    let snippets = match state.snippets.latest_for(filter).await {
+        Ok(snippets) => snippets,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    let removed = match state.snippets.last_removed(filter).await {
+        Ok(removed) => removed,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    // the most recently added or edited snippet dates the whole feed
+    let updated = snippets
+        .iter()
+        .map(Snippet::modified)
+        .max()
+        .unwrap_or(DateTime::<Utc>::UNIX_EPOCH);
+    // a snippet leaving the feed changes it too, older ones move up in its place
+    let changed = removed.map_or(updated, |removed| removed.max(updated));
+    // the ids change whenever a snippet is added or leaves the feed, the revisions whenever
+    // one is edited. The title and author change with the name of the user whose feed it is
+    let listing: String = snippets
+        .iter()
+        .map(|snippet| format!("{}:{},", snippet.id, snippet.revision))
+        .collect();
+    let etag = format!(
+        "\"{}\"",
+        token::digest(&format!(
+            "{}|{}|{}|{}",
+            format.extension(),
+            title,
+            author,
+            listing
+        ))
+    );
+    let last_modified = changed.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+    if not_modified(headers, &etag, changed) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)],
+        )
+            .into_response();
+    }
+
+    let base_url = state.base_url.clone();
+    let render_result = match format {
+        FeedFormat::Atom => AtomTemplate {
+            title,
+            author,
+            base_url,
+            self_path,
+            updated,
+            snippets,
+        }
+        .render(),
+        FeedFormat::Rss => RssTemplate {
+            title,
+            author,
+            base_url,
+            self_path,
+            updated,
+            snippets,
+        }
+        .render(),
+    };
+    match render_result {
+        Ok(body) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (header::ETAG, etag),
+                (header::LAST_MODIFIED, last_modified),
+                (header::CACHE_CONTROL, "public, max-age=300".to_string()),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(e) => AppState::server_error(Box::new(e)),
+    }
+}
+
+// If-None-Match takes precedence over If-Modified-Since (RFC 9110, section 13.2.2)
+fn not_modified(headers: &HeaderMap, etag: &str, updated: DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let if_none_match = if_none_match.to_str().unwrap_or_default();
+        return if_none_match.trim() == "*"
+            || if_none_match
+                .split(',')
+                .any(|tag| tag.trim().trim_start_matches("W/") == etag);
+    }
+    if let Some(if_modified_since) = headers.get(header::IF_MODIFIED_SINCE)
+        && let Ok(since) =
+            DateTime::parse_from_rfc2822(if_modified_since.to_str().unwrap_or_default())
+    {
+        return updated.timestamp() <= since.timestamp();
     }
+    false
 }
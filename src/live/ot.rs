@@ -0,0 +1,361 @@
+// Operational transformation for plain text, following the model used by ot.js:
+// an operation walks over the whole document and either retains, inserts or deletes.
+// Lengths are counted in unicode scalar values on both the server and the browser.
+//
+// On the wire an operation is a JSON array where a positive number retains,
+// a negative number deletes and a string inserts, e.g. `[3, "abc", -2, 5]`.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser::SerializeSeq};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Component {
+    Retain(usize),
+    Insert(String),
+    Delete(usize),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum OtError {
+    #[error("the operation's base length does not match the document length")]
+    LengthMismatch,
+    #[error("the operations cannot be transformed against each other")]
+    Incompatible,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Operation {
+    components: Vec<Component>,
+    // length of the document the operation applies to
+    base_len: usize,
+    // length of the document after the operation has been applied
+    target_len: usize,
+}
+
+impl Operation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn retain(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        self.base_len += n;
+        self.target_len += n;
+        if let Some(Component::Retain(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Retain(n));
+        }
+        self
+    }
+
+    pub fn insert(&mut self, s: &str) -> &mut Self {
+        if s.is_empty() {
+            return self;
+        }
+        self.target_len += s.chars().count();
+        // keep inserts before deletes so that equivalent operations look the same
+        match self.components.as_mut_slice() {
+            [.., Component::Insert(last)] => last.push_str(s),
+            [.., Component::Insert(before), Component::Delete(_)] => before.push_str(s),
+            [.., Component::Delete(_)] => {
+                let delete = self.components.pop().unwrap();
+                self.components.push(Component::Insert(s.to_string()));
+                self.components.push(delete);
+            }
+            _ => self.components.push(Component::Insert(s.to_string())),
+        }
+        self
+    }
+
+    pub fn delete(&mut self, n: usize) -> &mut Self {
+        if n == 0 {
+            return self;
+        }
+        self.base_len += n;
+        if let Some(Component::Delete(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Delete(n));
+        }
+        self
+    }
+
+    pub fn apply(&self, doc: &str) -> Result<String, OtError> {
+        if doc.chars().count() != self.base_len {
+            return Err(OtError::LengthMismatch);
+        }
+        let mut chars = doc.chars();
+        let mut result = String::with_capacity(doc.len());
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => result.extend(chars.by_ref().take(*n)),
+                Component::Insert(s) => result.push_str(s),
+                Component::Delete(n) => {
+                    chars.by_ref().take(*n).for_each(drop);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Given two operations `a` and `b` that apply to the same document, returns `(a', b')`
+    /// such that applying `a` then `b'` gives the same result as applying `b` then `a'`.
+    /// When both insert at the same position, `a`'s insert goes first.
+    pub fn transform(a: &Operation, b: &Operation) -> Result<(Operation, Operation), OtError> {
+        if a.base_len != b.base_len {
+            return Err(OtError::LengthMismatch);
+        }
+        let mut a_prime = Operation::new();
+        let mut b_prime = Operation::new();
+        let mut a_iter = a.components.iter().cloned();
+        let mut b_iter = b.components.iter().cloned();
+        let mut a_next = a_iter.next();
+        let mut b_next = b_iter.next();
+        loop {
+            match (a_next.take(), b_next.take()) {
+                (None, None) => break,
+                (Some(Component::Insert(s)), b_component) => {
+                    a_prime.insert(&s);
+                    b_prime.retain(s.chars().count());
+                    a_next = a_iter.next();
+                    b_next = b_component;
+                }
+                (a_component, Some(Component::Insert(s))) => {
+                    a_prime.retain(s.chars().count());
+                    b_prime.insert(&s);
+                    a_next = a_component;
+                    b_next = b_iter.next();
+                }
+                (None, _) | (_, None) => return Err(OtError::Incompatible),
+                (Some(Component::Retain(x)), Some(Component::Retain(y))) => {
+                    let min = x.min(y);
+                    a_prime.retain(min);
+                    b_prime.retain(min);
+                    (a_next, b_next) = remainders(
+                        (x, Component::Retain),
+                        (y, Component::Retain),
+                        &mut a_iter,
+                        &mut b_iter,
+                    );
+                }
+                (Some(Component::Delete(x)), Some(Component::Delete(y))) => {
+                    // both deleted the same text, nothing left to do for that part
+                    (a_next, b_next) = remainders(
+                        (x, Component::Delete),
+                        (y, Component::Delete),
+                        &mut a_iter,
+                        &mut b_iter,
+                    );
+                }
+                (Some(Component::Delete(x)), Some(Component::Retain(y))) => {
+                    a_prime.delete(x.min(y));
+                    (a_next, b_next) = remainders(
+                        (x, Component::Delete),
+                        (y, Component::Retain),
+                        &mut a_iter,
+                        &mut b_iter,
+                    );
+                }
+                (Some(Component::Retain(x)), Some(Component::Delete(y))) => {
+                    b_prime.delete(x.min(y));
+                    (a_next, b_next) = remainders(
+                        (x, Component::Retain),
+                        (y, Component::Delete),
+                        &mut a_iter,
+                        &mut b_iter,
+                    );
+                }
+            }
+        }
+        Ok((a_prime, b_prime))
+    }
+}
+
+// Consumes the shorter of two overlapping components and returns what is left of the longer one.
+fn remainders(
+    (x, a_kind): (usize, fn(usize) -> Component),
+    (y, b_kind): (usize, fn(usize) -> Component),
+    a_iter: &mut impl Iterator<Item = Component>,
+    b_iter: &mut impl Iterator<Item = Component>,
+) -> (Option<Component>, Option<Component>) {
+    match x.cmp(&y) {
+        std::cmp::Ordering::Greater => (Some(a_kind(x - y)), b_iter.next()),
+        std::cmp::Ordering::Equal => (a_iter.next(), b_iter.next()),
+        std::cmp::Ordering::Less => (a_iter.next(), Some(b_kind(y - x))),
+    }
+}
+
+impl Serialize for Operation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.components.len()))?;
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => seq.serialize_element(&(*n as i64))?,
+                Component::Insert(s) => seq.serialize_element(s)?,
+                Component::Delete(n) => seq.serialize_element(&-(*n as i64))?,
+            }
+        }
+        seq.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawComponent {
+    Number(i64),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for Operation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Vec::<RawComponent>::deserialize(deserializer)?;
+        let mut operation = Operation::new();
+        for component in raw {
+            match component {
+                RawComponent::Number(0) => {
+                    return Err(de::Error::custom("an operation component cannot be 0"));
+                }
+                RawComponent::Number(n) if n > 0 => operation.retain(n as usize),
+                RawComponent::Number(n) => operation.delete(n.unsigned_abs() as usize),
+                RawComponent::Text(s) => operation.insert(&s),
+            };
+        }
+        Ok(operation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(build: impl FnOnce(&mut Operation)) -> Operation {
+        let mut operation = Operation::new();
+        build(&mut operation);
+        operation
+    }
+
+    // both orders of applying a pair of concurrent operations end in the same document
+    fn assert_converges(doc: &str, a: &Operation, b: &Operation, expected: &str) {
+        let (a_prime, b_prime) = Operation::transform(a, b).unwrap();
+        let a_then_b = b_prime.apply(&a.apply(doc).unwrap()).unwrap();
+        let b_then_a = a_prime.apply(&b.apply(doc).unwrap()).unwrap();
+        assert_eq!(a_then_b, expected);
+        assert_eq!(b_then_a, expected);
+    }
+
+    #[test]
+    fn apply_retains_inserts_and_deletes() {
+        let operation = op(|o| {
+            o.retain(2).insert("XY").delete(1).retain(2);
+        });
+        assert_eq!(operation.apply("abcde").unwrap(), "abXYde");
+    }
+
+    #[test]
+    fn apply_counts_unicode_scalar_values() {
+        let operation = op(|o| {
+            o.retain(1).delete(1).insert("ü").retain(1);
+        });
+        assert_eq!(operation.apply("a😀b").unwrap(), "aüb");
+    }
+
+    #[test]
+    fn apply_rejects_documents_of_another_length() {
+        let operation = op(|o| {
+            o.retain(3);
+        });
+        assert_eq!(operation.apply("ab"), Err(OtError::LengthMismatch));
+        assert_eq!(operation.apply("abcd"), Err(OtError::LengthMismatch));
+    }
+
+    #[test]
+    fn builder_merges_components_and_puts_inserts_before_deletes() {
+        let merged = op(|o| {
+            o.retain(1)
+                .retain(2)
+                .delete(1)
+                .insert("a")
+                .insert("b")
+                .delete(2);
+        });
+        let canonical = op(|o| {
+            o.retain(3).insert("ab").delete(3);
+        });
+        assert_eq!(merged, canonical);
+    }
+
+    #[test]
+    fn transform_inserts_at_different_positions() {
+        let a = op(|o| {
+            o.insert("A").retain(3);
+        });
+        let b = op(|o| {
+            o.retain(3).insert("B");
+        });
+        assert_converges("abc", &a, &b, "AabcB");
+    }
+
+    #[test]
+    fn transform_inserts_at_the_same_position_puts_the_first_operation_first() {
+        let a = op(|o| {
+            o.retain(1).insert("A").retain(2);
+        });
+        let b = op(|o| {
+            o.retain(1).insert("B").retain(2);
+        });
+        assert_converges("abc", &a, &b, "aABbc");
+    }
+
+    #[test]
+    fn transform_overlapping_deletes() {
+        let a = op(|o| {
+            o.retain(1).delete(3).retain(1);
+        });
+        let b = op(|o| {
+            o.retain(2).delete(3);
+        });
+        assert_converges("abcde", &a, &b, "a");
+    }
+
+    #[test]
+    fn transform_insert_inside_deleted_text() {
+        let a = op(|o| {
+            o.delete(4);
+        });
+        let b = op(|o| {
+            o.retain(2).insert("X").retain(2);
+        });
+        assert_converges("abcd", &a, &b, "X");
+    }
+
+    #[test]
+    fn transform_rejects_operations_on_different_documents() {
+        let a = op(|o| {
+            o.retain(2);
+        });
+        let b = op(|o| {
+            o.retain(3);
+        });
+        assert_eq!(Operation::transform(&a, &b), Err(OtError::LengthMismatch));
+    }
+
+    #[test]
+    fn serializes_to_the_wire_format() {
+        let operation = op(|o| {
+            o.retain(3).insert("abc").delete(2).retain(5);
+        });
+        let json = serde_json::to_string(&operation).unwrap();
+        assert_eq!(json, r#"[3,"abc",-2,5]"#);
+        let parsed: Operation = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, operation);
+    }
+
+    #[test]
+    fn deserializing_rejects_invalid_components() {
+        assert!(serde_json::from_str::<Operation>("[1,0,2]").is_err());
+        assert!(serde_json::from_str::<Operation>("[1,true]").is_err());
+    }
+}
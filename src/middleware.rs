@@ -1,66 +1,121 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use askama::Template;
 use axum::{
     Extension,
-    extract::{ConnectInfo, Request, State},
-    http::header::CACHE_CONTROL,
-    middleware::{self, Next},
+    body::Body,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER, SET_COOKIE},
+    },
+    middleware::Next,
     response::{IntoResponse, Redirect, Response},
 };
-use tower_http::auth;
 use tower_sessions::Session;
 
-use crate::{AppState, Authenticated, utils::login_form_validation::LoginTemplate};
+use askama::Template;
 
-pub async fn common_headers(request: Request, next: Next) -> Response {
-    // any code here will be executed before the processing of the request
-    //        // Any code here will execute on the way down the chain.
-    // for example : authorization middleware will do it's thing here
+use crate::{
+    AppState, CurrentUser,
+    client_ip::ClientIp,
+    handlers::log_in,
+    models::{remember::Redeemed, users::Role},
+    rate_limit::RouteGroup,
+    security_headers::{self, CSP_REPORT_PATH},
+    templates::ErrorTemplate,
+    utils::{csrf, remember},
+};
 
-    let mut response = next.run(request).await;
+// forms are buffered to look for the token, same limit as axum's default body limit
+const FORM_BODY_LIMIT: usize = 2 * 1024 * 1024;
 
-    // Any code here will execute on the way back up the chain.
+/// Marks a response that is meant to be shown inside a frame on other sites.
+/// `common_headers` leaves out `X-Frame-Options: deny` for it, the handler sets its own framing policy.
+#[derive(Clone)]
+pub struct Embeddable;
+
+pub async fn common_headers(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // templates rendered further down pick up the nonce with `security_headers::csp_nonce`
+    let (nonce, mut response) = security_headers::with_nonce(next.run(request)).await;
 
+    let embeddable = response.extensions().get::<Embeddable>().is_some();
     let headers = response.headers_mut();
-    headers.insert(
-        "Content-Security-Policy",
-        "default-src 'self'; style-src 'self' fonts.googleapis.com; font-src fonts.gstatic.com"
-            .parse()
-            .unwrap(),
-    );
-    headers.insert(
-        "Referrer-Policy",
-        "origin-when-cross-origin".parse().unwrap(),
-    );
+    state.security_headers.apply(headers, &nonce);
     headers.insert("X-Content-Type-Options", "nosniff".parse().unwrap());
-    headers.insert("X-Frame-Options", "deny".parse().unwrap());
+    if !embeddable {
+        headers.insert("X-Frame-Options", "deny".parse().unwrap());
+    }
     headers.insert("X-XSS-Protection", "0".parse().unwrap());
     headers.insert("Server", "Rust".parse().unwrap());
     response
 }
 
+/// Works out the client's address (see `client_ip::IpPolicy`) and puts it on the request as
+/// `ClientIp`, or turns the request away when the client is not let in. Has to wrap the router,
+/// denied clients do not get any further.
 pub async fn request_ip(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     mut request: Request,
     next: Next,
 ) -> Response {
-    let headers = request.headers_mut();
-    headers.append(
-        "user-ip",
-        addr.to_string()
-            .parse()
-            .unwrap_or_else(|_| "unknown".parse().unwrap()),
-    );
+    let ip = state.ip_policy.client_ip(peer.ip(), request.headers());
+    if !state.ip_policy.permits(ip) {
+        tracing::info!("request from {} refused by the address lists", ip);
+        return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+    }
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request).await
+}
+
+/// Answers 429 once the client has used up the budget of the route's group. Needs to run
+/// after `authenticate`, logged in users are limited by their id rather than their address.
+pub async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    user: Option<Extension<CurrentUser>>,
+    client_ip: ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(route) = request.extensions().get::<MatchedPath>() else {
+        return next.run(request).await;
+    };
+    // stylesheets and scripts come along with every page
+    if route.as_str().starts_with("/static") {
+        return next.run(request).await;
+    }
+    let group = RouteGroup::of(request.method(), route.as_str());
+    let client = match &user {
+        Some(Extension(user)) => format!("user:{}", user.id),
+        None => format!("ip:{}", client_ip),
+    };
+    if let Err(retry_after) = state.rate_limiter.check(group, &client).await {
+        tracing::info!(
+            "{} is over the {} rate limit on {}",
+            client,
+            group.as_str(),
+            request.uri().path()
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, retry_after.to_string())],
+            "Too many requests, please try again later.",
+        )
+            .into_response();
+    }
     next.run(request).await
 }
 
 pub async fn require_auth(
-    ext_state: Extension<Authenticated>,
+    user: Option<Extension<CurrentUser>>,
     mut request: Request,
     next: Next,
 ) -> Response {
-    if !AppState::is_authenticated(ext_state).await {
+    if !AppState::is_authenticated(&user) {
         return Redirect::to("/user/login").into_response();
     }
     let headers = request.headers_mut();
@@ -68,44 +123,237 @@ pub async fn require_auth(
     next.run(request).await
 }
 
+/// Keeps users that have not confirmed their email address away from the routes it is layered on.
+/// Needs to run after `require_auth`.
+pub async fn require_verified(
+    user: Option<Extension<CurrentUser>>,
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Response {
+    if AppState::needs_verification(&user) {
+        // the navigation offers to send the link again
+        let template = ErrorTemplate {
+            status: StatusCode::FORBIDDEN,
+            message: "Please verify your email address before creating snippets. Follow the link in the email we sent you when you signed up, or request a new one.".to_string(),
+            is_authenticated: true,
+            needs_verification: true,
+            csrf_token: csrf::current(&session).await.unwrap_or_default(),
+        };
+        return (StatusCode::FORBIDDEN, AppState::render(template.render())).into_response();
+    }
+    next.run(request).await
+}
+
+/// Keeps the routes it is layered on to users with at least the role given as its state,
+/// e.g. `from_fn_with_state(Role::Admin, require_role)`. Needs to run after `require_auth`.
+pub async fn require_role(
+    State(role): State<Role>,
+    user: Option<Extension<CurrentUser>>,
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(Extension(user)) = user else {
+        return Redirect::to("/user/login").into_response();
+    };
+    if !user.has_role(role) {
+        tracing::info!(
+            "user {} ({}) is not allowed on {} : {} role required",
+            user.id,
+            user.name,
+            request.uri().path(),
+            role.as_str()
+        );
+        return AppState::error_page(
+            StatusCode::FORBIDDEN,
+            "You are not allowed to see this page.",
+            true,
+            csrf::current(&session).await.unwrap_or_default(),
+        );
+    }
+    next.run(request).await
+}
+
 // #[axum::debug_middleware]
 #[warn(clippy::future_not_send)]
 pub async fn authenticate(
     State(state): State<Arc<AppState>>,
     session: Session,
+    client_ip: ClientIp,
     mut request: Request,
     next: Next,
 ) -> Response {
     let auth_res: Result<Option<i32>, tower_sessions::session::Error> =
         session.get("authenticatedUserID").await;
     let id;
-    if let Ok(id_opt) = auth_res {
-        if let Some(rid) = id_opt {
-            id = rid;
-            tracing::info!("id found in session : {}", id);
-        } else {
-            request
-                .extensions_mut()
-                .insert(Authenticated { val: false }); // vibe coded line of code. CHANGE
-            return next.run(request).await;
+    // the "remember me" cookie to send back, after the session was restored from it
+    let mut remember_cookie = None;
+    if let Ok(Some(rid)) = auth_res {
+        id = rid;
+        tracing::info!("id found in session : {}", id);
+    } else {
+        let restored = match remember::from_headers(request.headers()) {
+            Some(token) => {
+                match restore_remembered(&state, &session, request.headers(), client_ip, &token)
+                    .await
+                {
+                    Ok(restored) => restored,
+                    Err(response) => return response,
+                }
+            }
+            None => (None, None),
+        };
+        remember_cookie = restored.1;
+        let Some(rid) = restored.0 else {
+            let mut response = next.run(request).await;
+            if let Some(cookie) = remember_cookie {
+                response.headers_mut().append(SET_COOKIE, cookie);
+            }
+            return response;
+        };
+        id = rid;
+    }
+    // sessions end as soon as they are revoked (password reset), even if the cookie lives on
+    let session_id = session.id().map(|id| id.to_string()).unwrap_or_default();
+    let user = match state.user_sessions.lookup(&session_id, id).await {
+        Ok(user) => user,
+        Err(e) => return AppState::server_error(Box::new(e)),
+    };
+    if let Some(user) = user {
+        let ip = client_ip.to_string();
+        if let Err(e) = state
+            .user_sessions
+            .touch(&user, &session_id, Some(&ip))
+            .await
+        {
+            tracing::error!("could not note the session's last use : {}", e);
         }
+        request.extensions_mut().insert(CurrentUser {
+            id,
+            name: user.name,
+            role: user.role,
+            email_verified: user.email_verified,
+        });
     } else {
-        request
-            .extensions_mut()
-            .insert(Authenticated { val: false }); // vibe coded line of code. CHANGE
+        tracing::info!("session of user {} is no longer active", id);
+        if let Err(e) = session.flush().await {
+            tracing::error!("could not end revoked session : {}", e);
+        }
+    }
+    let mut response = next.run(request).await;
+    if let Some(cookie) = remember_cookie {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    response
+}
+
+/// Logs a remembered device back in after its session has expired. Returns the user,
+/// if any, and the cookie to send back: the next token, or a removal when the one in the
+/// cookie is no good.
+async fn restore_remembered(
+    state: &AppState,
+    session: &Session,
+    headers: &HeaderMap,
+    client_ip: ClientIp,
+    token: &str,
+) -> Result<(Option<i32>, Option<HeaderValue>), Response> {
+    match state.remember_tokens.redeem(token).await {
+        Ok(Redeemed::Valid {
+            user_id,
+            selector,
+            token,
+        }) => {
+            log_in(state, session, headers, client_ip, user_id).await?;
+            let session_id = session.id().map(|id| id.to_string()).unwrap_or_default();
+            if let Err(e) = state.remember_tokens.attach(&selector, &session_id).await {
+                tracing::error!("could not tie the remember token to its session : {}", e);
+            }
+            tracing::info!("user {} logged back in from a remembered device", user_id);
+            let cookie = remember::set_cookie(&token, &state.base_url);
+            Ok((Some(user_id), Some(cookie)))
+        }
+        Ok(Redeemed::Stolen { user_id }) => {
+            // the thief may be logged in already, whoever it is has to log in again
+            tracing::warn!(
+                "replaced remember token of user {} used again, ending all their sessions",
+                user_id
+            );
+            if let Err(e) = state.user_sessions.revoke_all(user_id).await {
+                return Err(AppState::server_error(Box::new(e)));
+            }
+            state.live_sessions.end_sessions(user_id, "");
+            Ok((None, Some(remember::clear_cookie())))
+        }
+        // a request that raced the one which replaced the token
+        Ok(Redeemed::Replaced) => Ok((None, None)),
+        Ok(Redeemed::Invalid) => Ok((None, Some(remember::clear_cookie()))),
+        Err(e) => Err(AppState::server_error(Box::new(e))),
+    }
+}
+
+/// Rejects state-changing requests that do not carry the session's anti-forgery token,
+/// either as the `csrf_token` form field or in the `X-CSRF-Token` header.
+pub async fn csrf_protect(session: Session, request: Request, next: Next) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) {
+        return next.run(request).await;
+    }
+    // browsers send violation reports without cookies or tokens, collecting them changes nothing
+    if request.uri().path() == CSP_REPORT_PATH {
         return next.run(request).await;
     }
-    let exists = state.users.exists(id).await;
-    if let Err(e) = exists {
-        return AppState::server_error(Box::new(e));
+    let expected: Option<String> = session.get(csrf::SESSION_KEY).await.unwrap_or_default();
+    let (parts, mut body) = request.into_parts();
+    let mut submitted = parts
+        .headers
+        .get(csrf::HEADER)
+        .and_then(|token| token.to_str().ok())
+        .map(str::to_string);
+    let is_form = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+    if submitted.is_none() && is_form {
+        let bytes = match axum::body::to_bytes(body, FORM_BODY_LIMIT).await {
+            Ok(bytes) => bytes,
+            Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        };
+        submitted = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&bytes)
+            .ok()
+            .and_then(|fields| {
+                fields
+                    .into_iter()
+                    .find(|(name, _)| name == csrf::FORM_FIELD)
+                    .map(|(_, token)| token)
+            });
+        // hand the untouched form on to the handler
+        body = Body::from(bytes);
     }
-    let exists = exists.unwrap();
-    if exists {
-        request.extensions_mut().insert(Authenticated { val: true }); // vibe coded line of code. CHANGE
-    } else {
-        request
-            .extensions_mut()
-            .insert(Authenticated { val: false }); // vibe coded line of code. CHANGE
+    let valid = match (expected, submitted) {
+        (Some(expected), Some(submitted)) => csrf::matches(&expected, &submitted),
+        _ => false,
+    };
+    if !valid {
+        tracing::info!(
+            "rejected {} {} : missing or invalid csrf token",
+            parts.method,
+            parts.uri.path()
+        );
+        let is_authenticated = session
+            .get::<i32>("authenticatedUserID")
+            .await
+            .is_ok_and(|id| id.is_some());
+        let csrf_token = csrf::current(&session).await.unwrap_or_default();
+        return AppState::error_page(
+            StatusCode::BAD_REQUEST,
+            "Your form has expired or did not come from this site. Please go back, reload the page and try again.",
+            is_authenticated,
+            csrf_token,
+        );
     }
-    next.run(request).await
+    next.run(Request::from_parts(parts, body)).await
 }
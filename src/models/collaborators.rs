@@ -0,0 +1,68 @@
+use sqlx::{MySql, Pool};
+
+/// Somebody the owner of a snippet invited to edit it live.
+#[derive(sqlx::FromRow)]
+pub struct Collaborator {
+    pub user_id: i32,
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Clone)]
+pub struct CollaboratorModel {
+    pool: Pool<MySql>,
+}
+
+impl CollaboratorModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// Invites the user, returns false when they were invited already.
+    pub async fn add(&self, snippet_id: i32, user_id: i32) -> Result<bool, sqlx::Error> {
+        let query = "INSERT IGNORE INTO snippet_collaborators (snippet_id, user_id, created) VALUES (?, ?, UTC_TIMESTAMP())";
+        let result = sqlx::query(query)
+            .bind(snippet_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn remove(&self, snippet_id: i32, user_id: i32) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM snippet_collaborators WHERE snippet_id = ? AND user_id = ?")
+            .bind(snippet_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Everybody invited to the snippet, in the order they were invited.
+    pub async fn list(&self, snippet_id: i32) -> Result<Vec<Collaborator>, sqlx::Error> {
+        let query = r#"SELECT users.id AS user_id, users.name, users.email
+            FROM snippet_collaborators
+            INNER JOIN users ON users.id = snippet_collaborators.user_id
+            WHERE snippet_collaborators.snippet_id = ?
+            ORDER BY snippet_collaborators.created, users.id"#;
+        sqlx::query_as(query)
+            .bind(snippet_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn is_collaborator(
+        &self,
+        snippet_id: i32,
+        user_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let found: Option<i32> = sqlx::query_scalar(
+            "SELECT user_id FROM snippet_collaborators WHERE snippet_id = ? AND user_id = ?",
+        )
+        .bind(snippet_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(found.is_some())
+    }
+}
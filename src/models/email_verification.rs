@@ -0,0 +1,95 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{MySql, Pool};
+
+use crate::utils::token;
+
+// how long a verification link stays valid
+const VERIFICATION_TOKEN_HOURS: i32 = 24;
+// resends are limited so the form cannot be used to flood somebody's inbox
+const RESEND_INTERVAL_MINUTES: i64 = 5;
+const MAX_SENDS_PER_DAY: i64 = 5;
+
+#[derive(Clone)]
+pub struct EmailVerificationModel {
+    pool: Pool<MySql>,
+}
+
+#[derive(sqlx::FromRow)]
+struct RecentSends {
+    count: i64,
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+}
+
+impl EmailVerificationModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// Issues a verification token for the user. The plain token only exists in the email.
+    pub async fn create(&self, user_id: i32) -> Result<String, sqlx::Error> {
+        sqlx::query("DELETE FROM email_verifications WHERE expires <= UTC_TIMESTAMP()")
+            .execute(&self.pool)
+            .await?;
+        let token = token::generate();
+        let query = r#"INSERT INTO email_verifications (token_hash, user_id, expires, created)
+            VALUES(?, ?, DATE_ADD(UTC_TIMESTAMP(), INTERVAL ? HOUR), UTC_TIMESTAMP())"#;
+        sqlx::query(query)
+            .bind(token::digest(&token))
+            .bind(user_id)
+            .bind(VERIFICATION_TOKEN_HOURS)
+            .execute(&self.pool)
+            .await?;
+        Ok(token)
+    }
+
+    /// Marks the user's email as verified and burns the user's tokens.
+    /// Returns the user's id, or `None` when the token is unknown or expired.
+    pub async fn verify(&self, token: &str) -> Result<Option<i32>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let query = r#"SELECT user_id FROM email_verifications
+            WHERE token_hash = ? AND expires > UTC_TIMESTAMP() FOR UPDATE"#;
+        let user_id: Option<i32> = sqlx::query_scalar(query)
+            .bind(token::digest(token))
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+        sqlx::query("UPDATE users SET email_verified = TRUE WHERE id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM email_verifications WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(user_id))
+    }
+
+    /// When the user has asked for too many links lately, returns when the next one may be sent.
+    pub async fn resend_blocked_until(
+        &self,
+        user_id: i32,
+    ) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        let query = r#"SELECT COUNT(*) AS count, MIN(created) AS first, MAX(created) AS last
+            FROM email_verifications
+            WHERE user_id = ? AND created > DATE_SUB(UTC_TIMESTAMP(), INTERVAL 1 DAY)"#;
+        let sends = sqlx::query_as::<_, RecentSends>(query)
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await?;
+        let mut blocked_until = None;
+        if sends.count >= MAX_SENDS_PER_DAY
+            && let Some(first) = sends.first
+        {
+            blocked_until = Some(first + Duration::days(1));
+        }
+        if let Some(last) = sends.last {
+            let next = last + Duration::minutes(RESEND_INTERVAL_MINUTES);
+            blocked_until = blocked_until.max(Some(next));
+        }
+        Ok(blocked_until.filter(|until| *until > Utc::now()))
+    }
+}
@@ -0,0 +1,89 @@
+use sqlx::{MySql, Pool};
+
+use crate::{passwords::Passwords, utils::token};
+
+#[derive(Clone)]
+pub struct IdentityModel {
+    pool: Pool<MySql>,
+    passwords: Passwords,
+}
+
+impl IdentityModel {
+    pub fn new(pool: Pool<MySql>, passwords: Passwords) -> Self {
+        Self { pool, passwords }
+    }
+
+    pub async fn user_for(&self, issuer: &str, subject: &str) -> Result<Option<i32>, sqlx::Error> {
+        sqlx::query_scalar("SELECT user_id FROM user_identities WHERE issuer = ? AND subject = ?")
+            .bind(issuer)
+            .bind(subject)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn user_by_email(&self, email: &str) -> Result<Option<i32>, sqlx::Error> {
+        sqlx::query_scalar("SELECT id FROM users WHERE email = ?")
+            .bind(email)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Links the provider account to an existing user. The provider vouched for the
+    /// email address, so the user counts as verified from now on.
+    pub async fn link(&self, issuer: &str, subject: &str, user_id: i32) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let query = r#"INSERT INTO user_identities (issuer, subject, user_id, created)
+            VALUES(?, ?, ?, UTC_TIMESTAMP())"#;
+        sqlx::query(query)
+            .bind(issuer)
+            .bind(subject)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE users SET email_verified = TRUE WHERE id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Creates a user for somebody who signs in through the provider for the first time.
+    /// The account gets a random password nobody knows, a real one can be set through
+    /// the password reset.
+    pub async fn create_user(
+        &self,
+        issuer: &str,
+        subject: &str,
+        name: &str,
+        email: &str,
+    ) -> Result<i32, Box<dyn std::error::Error + Send>> {
+        let hashed_password = self
+            .passwords
+            .hash(&token::generate())
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)?;
+        let boxed = |e: sqlx::Error| Box::new(e) as Box<dyn std::error::Error + Send>;
+        let mut tx = self.pool.begin().await.map_err(boxed)?;
+        let query = r#"INSERT INTO users (name, email, hashed_password, email_verified, created)
+            VALUES(?, ?, ?, TRUE, UTC_TIMESTAMP())"#;
+        let user_id = sqlx::query(query)
+            .bind(name)
+            .bind(email)
+            .bind(hashed_password)
+            .execute(&mut *tx)
+            .await
+            .map_err(boxed)?
+            .last_insert_id() as i32;
+        let query = r#"INSERT INTO user_identities (issuer, subject, user_id, created)
+            VALUES(?, ?, ?, UTC_TIMESTAMP())"#;
+        sqlx::query(query)
+            .bind(issuer)
+            .bind(subject)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(boxed)?;
+        tx.commit().await.map_err(boxed)?;
+        Ok(user_id)
+    }
+}
@@ -1,3 +1,24 @@
+pub mod collaborators;
+pub mod email_verification;
 pub mod errors;
+pub mod identities;
+pub mod passkeys;
+pub mod password_reset;
+pub mod rate_limits;
+pub mod remember;
+pub mod reports;
+pub mod sessions;
 pub mod snippet;
+pub mod submissions;
+pub mod throttle;
+pub mod two_factor;
 pub mod users;
+
+/// A `LIKE` pattern matching `term` anywhere, with the wildcards in it taken literally.
+pub fn contains_pattern(term: &str) -> String {
+    let escaped = term
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
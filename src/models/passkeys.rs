@@ -0,0 +1,162 @@
+use chrono::{DateTime, Utc};
+use sqlx::{MySql, Pool, mysql::MySqlDatabaseError};
+use webauthn_rs::prelude::{Passkey, Uuid};
+
+// mysql's error number for a duplicate key
+const ER_DUP_ENTRY: u16 = 1062;
+
+#[derive(sqlx::FromRow)]
+struct PasskeyRecord {
+    credential_id: Vec<u8>,
+    name: String,
+    passkey: String,
+    created: DateTime<Utc>,
+    last_used: Option<DateTime<Utc>>,
+}
+
+/// A passkey of a user, as listed on the account page.
+pub struct StoredPasskey {
+    pub credential_id: Vec<u8>,
+    pub name: String,
+    pub passkey: Passkey,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<PasskeyRecord> for StoredPasskey {
+    type Error = sqlx::Error;
+
+    fn try_from(record: PasskeyRecord) -> Result<Self, Self::Error> {
+        let passkey =
+            serde_json::from_str(&record.passkey).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        Ok(Self {
+            credential_id: record.credential_id,
+            name: record.name,
+            passkey,
+            created: record.created,
+            last_used: record.last_used,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct PasskeyModel {
+    pool: Pool<MySql>,
+}
+
+impl PasskeyModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// The handle authenticators know the user by, made up when the first passkey is added.
+    pub async fn handle(&self, user_id: i32) -> Result<Uuid, sqlx::Error> {
+        // two tabs adding passkeys at once still end up with the same handle
+        sqlx::query("UPDATE users SET passkey_handle = ? WHERE id = ? AND passkey_handle IS NULL")
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        let handle: String = sqlx::query_scalar("SELECT passkey_handle FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Uuid::parse_str(&handle).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+    }
+
+    pub async fn user_for(&self, handle: Uuid) -> Result<Option<i32>, sqlx::Error> {
+        sqlx::query_scalar("SELECT id FROM users WHERE passkey_handle = ?")
+            .bind(handle.to_string())
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// The user's passkeys, newest first.
+    pub async fn list(&self, user_id: i32) -> Result<Vec<StoredPasskey>, sqlx::Error> {
+        let query = r#"SELECT credential_id, name, passkey, created, last_used FROM passkeys
+            WHERE user_id = ? ORDER BY created DESC"#;
+        let records: Vec<PasskeyRecord> = sqlx::query_as(query)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+        records.into_iter().map(StoredPasskey::try_from).collect()
+    }
+
+    pub async fn find(
+        &self,
+        user_id: i32,
+        credential_id: &[u8],
+    ) -> Result<Option<StoredPasskey>, sqlx::Error> {
+        let query = r#"SELECT credential_id, name, passkey, created, last_used FROM passkeys
+            WHERE user_id = ? AND credential_id = ?"#;
+        let record: Option<PasskeyRecord> = sqlx::query_as(query)
+            .bind(user_id)
+            .bind(credential_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        record.map(StoredPasskey::try_from).transpose()
+    }
+
+    /// Stores a newly registered passkey. Returns false when the credential is registered
+    /// already, to this user or anybody else.
+    pub async fn insert(
+        &self,
+        user_id: i32,
+        name: &str,
+        passkey: &Passkey,
+    ) -> Result<bool, sqlx::Error> {
+        let serialised =
+            serde_json::to_string(passkey).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+        let query = r#"INSERT INTO passkeys (credential_id, user_id, name, passkey, created)
+            VALUES(?, ?, ?, ?, UTC_TIMESTAMP())"#;
+        let result = sqlx::query(query)
+            .bind(passkey.cred_id().as_slice())
+            .bind(user_id)
+            .bind(name)
+            .bind(serialised)
+            .execute(&self.pool)
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(e))
+                if e.try_downcast_ref::<MySqlDatabaseError>()
+                    .is_some_and(|e| e.number() == ER_DUP_ENTRY) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Notes a login with the passkey, along with its new signature counter if it changed.
+    pub async fn used(&self, passkey: &Passkey, changed: bool) -> Result<(), sqlx::Error> {
+        if changed {
+            let serialised =
+                serde_json::to_string(passkey).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+            let query = r#"UPDATE passkeys SET passkey = ?, last_used = UTC_TIMESTAMP()
+                WHERE credential_id = ?"#;
+            sqlx::query(query)
+                .bind(serialised)
+                .bind(passkey.cred_id().as_slice())
+                .execute(&self.pool)
+                .await?;
+        } else {
+            sqlx::query("UPDATE passkeys SET last_used = UTC_TIMESTAMP() WHERE credential_id = ?")
+                .bind(passkey.cred_id().as_slice())
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the user had such a passkey.
+    pub async fn delete(&self, user_id: i32, credential_id: &[u8]) -> Result<bool, sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM passkeys WHERE user_id = ? AND credential_id = ?")
+            .bind(user_id)
+            .bind(credential_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+}
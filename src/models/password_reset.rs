@@ -0,0 +1,86 @@
+use std::error::Error;
+
+use sqlx::{MySql, Pool};
+
+use crate::{passwords::Passwords, utils::token};
+
+// how long an emailed reset link stays valid
+pub const RESET_TOKEN_MINUTES: i32 = 60;
+
+#[derive(Clone)]
+pub struct PasswordResetModel {
+    pool: Pool<MySql>,
+    passwords: Passwords,
+}
+
+impl PasswordResetModel {
+    pub fn new(pool: Pool<MySql>, passwords: Passwords) -> Self {
+        Self { pool, passwords }
+    }
+
+    /// Issues a reset token for the user. The plain token only exists in the email.
+    pub async fn create(&self, user_id: i32) -> Result<String, sqlx::Error> {
+        sqlx::query("DELETE FROM password_resets WHERE expires <= UTC_TIMESTAMP()")
+            .execute(&self.pool)
+            .await?;
+        let token = token::generate();
+        let query = r#"INSERT INTO password_resets (token_hash, user_id, expires, created)
+            VALUES(?, ?, DATE_ADD(UTC_TIMESTAMP(), INTERVAL ? MINUTE), UTC_TIMESTAMP())"#;
+        sqlx::query(query)
+            .bind(token::digest(&token))
+            .bind(user_id)
+            .bind(RESET_TOKEN_MINUTES)
+            .execute(&self.pool)
+            .await?;
+        Ok(token)
+    }
+
+    pub async fn is_valid(&self, token: &str) -> Result<bool, sqlx::Error> {
+        let query = r#"SELECT EXISTS(SELECT true FROM password_resets
+            WHERE token_hash = ? AND expires > UTC_TIMESTAMP())"#;
+        let exists: i64 = sqlx::query_scalar(query)
+            .bind(token::digest(token))
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(exists == 1)
+    }
+
+    /// Sets the new password and burns every outstanding token of the user.
+    /// Returns the user's id, or `None` when the token is unknown or expired.
+    pub async fn reset_password(
+        &self,
+        token: &str,
+        password: &str,
+    ) -> Result<Option<i32>, Box<dyn Error + Send>> {
+        let hashed_password = self.passwords.hash(password).await.map_err(|e| {
+            tracing::error!("password reset failed : password hashing failed : {}", e);
+            Box::new(e) as Box<dyn Error + Send>
+        })?;
+        let boxed = |e: sqlx::Error| Box::new(e) as Box<dyn Error + Send>;
+        let mut tx = self.pool.begin().await.map_err(boxed)?;
+        let query = r#"SELECT user_id FROM password_resets
+            WHERE token_hash = ? AND expires > UTC_TIMESTAMP() FOR UPDATE"#;
+        let user_id: Option<i32> = sqlx::query_scalar(query)
+            .bind(token::digest(token))
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(boxed)?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+        // following the emailed link proves the address belongs to the user
+        sqlx::query("UPDATE users SET hashed_password = ?, email_verified = TRUE WHERE id = ?")
+            .bind(hashed_password)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(boxed)?;
+        sqlx::query("DELETE FROM password_resets WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(boxed)?;
+        tx.commit().await.map_err(boxed)?;
+        Ok(Some(user_id))
+    }
+}
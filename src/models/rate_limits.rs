@@ -0,0 +1,59 @@
+use sqlx::{MySql, Pool};
+
+use crate::rate_limit::Budget;
+
+/// Rate limit buckets in the database, for instances that share their limits.
+#[derive(Clone)]
+pub struct RateLimitModel {
+    pool: Pool<MySql>,
+}
+
+impl RateLimitModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// Takes a token from the bucket under `key`, or says how many seconds until there is one.
+    /// The database clock is used so instances with drifting clocks agree.
+    pub async fn take(&self, key: &str, budget: Budget) -> Result<Result<(), u64>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        // a new bucket starts out full
+        sqlx::query(
+            "INSERT IGNORE INTO rate_limits (bucket_key, tokens, updated) VALUES (?, ?, UTC_TIMESTAMP(6))",
+        )
+        .bind(key)
+        .bind(budget.capacity())
+        .execute(&mut *tx)
+        .await?;
+        let query = r#"SELECT tokens, TIMESTAMPDIFF(MICROSECOND, updated, UTC_TIMESTAMP(6))
+            FROM rate_limits WHERE bucket_key = ? FOR UPDATE"#;
+        let (tokens, elapsed): (f64, i64) =
+            sqlx::query_as(query).bind(key).fetch_one(&mut *tx).await?;
+        let decision = match budget.take(tokens, elapsed as f64 / 1_000_000.0) {
+            Ok(left) => {
+                let query = "UPDATE rate_limits SET tokens = ?, updated = UTC_TIMESTAMP(6) WHERE bucket_key = ?";
+                sqlx::query(query)
+                    .bind(left)
+                    .bind(key)
+                    .execute(&mut *tx)
+                    .await?;
+                Ok(())
+            }
+            Err(retry_after) => Err(retry_after),
+        };
+        tx.commit().await?;
+        Ok(decision)
+    }
+    /// Deletes the buckets of `group` that have filled up again, returns how many.
+    pub async fn prune(&self, group: &str, budget: Budget) -> Result<u64, sqlx::Error> {
+        let query = r#"DELETE FROM rate_limits WHERE bucket_key LIKE ?
+            AND tokens + TIMESTAMPDIFF(MICROSECOND, updated, UTC_TIMESTAMP(6)) / 1000000 * ? >= ?"#;
+        let result = sqlx::query(query)
+            .bind(format!("{}:%", group))
+            .bind(budget.per_second())
+            .bind(budget.capacity())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
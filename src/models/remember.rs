@@ -0,0 +1,172 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{MySql, Pool, Transaction};
+use subtle::ConstantTimeEq;
+
+use crate::utils::token;
+
+// how long a "remember me" login lasts, counted from the password login
+pub const REMEMBER_DAYS: i64 = 30;
+// a replaced token may still arrive with requests the browser sent in parallel
+const REPLACED_GRACE_SECONDS: i64 = 30;
+
+#[derive(sqlx::FromRow)]
+struct RememberToken {
+    validator_hash: String,
+    series: String,
+    user_id: i32,
+    replaced: Option<DateTime<Utc>>,
+    expires: DateTime<Utc>,
+}
+
+pub enum Redeemed {
+    /// The token was good and has been replaced by `token`.
+    Valid {
+        user_id: i32,
+        selector: String,
+        token: String,
+    },
+    /// A token that had already been replaced came back, somebody holds a copy of the
+    /// cookie. All of the user's tokens are gone.
+    Stolen {
+        user_id: i32,
+    },
+    /// Replaced moments ago, the browser already has the next token.
+    Replaced,
+    Invalid,
+}
+
+#[derive(Clone)]
+pub struct RememberTokenModel {
+    pool: Pool<MySql>,
+}
+
+impl RememberTokenModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// Starts a new series for a device that just logged in. The returned token goes into
+    /// the cookie, only the digest of its validator is stored.
+    pub async fn issue(&self, user_id: i32, session_id: &str) -> Result<String, sqlx::Error> {
+        sqlx::query("DELETE FROM remember_tokens WHERE expires <= UTC_TIMESTAMP()")
+            .execute(&self.pool)
+            .await?;
+        let mut tx = self.pool.begin().await?;
+        let expires = Utc::now() + Duration::days(REMEMBER_DAYS);
+        let (_, token) = insert(
+            &mut tx,
+            &token::generate(),
+            user_id,
+            Some(session_id),
+            expires,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    /// Trades a token from a cookie for the next one of its series.
+    pub async fn redeem(&self, token: &str) -> Result<Redeemed, sqlx::Error> {
+        let Some((selector, validator)) = token.split_once('.') else {
+            return Ok(Redeemed::Invalid);
+        };
+        let mut tx = self.pool.begin().await?;
+        let query = r#"SELECT validator_hash, series, user_id, replaced, expires
+            FROM remember_tokens WHERE selector = ? FOR UPDATE"#;
+        let record: Option<RememberToken> = sqlx::query_as(query)
+            .bind(selector)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(record) = record else {
+            return Ok(Redeemed::Invalid);
+        };
+        let matches: bool = token::digest(validator)
+            .as_bytes()
+            .ct_eq(record.validator_hash.as_bytes())
+            .into();
+        if !matches || record.expires <= Utc::now() {
+            return Ok(Redeemed::Invalid);
+        }
+        if let Some(replaced) = record.replaced {
+            if (Utc::now() - replaced).num_seconds() < REPLACED_GRACE_SECONDS {
+                return Ok(Redeemed::Replaced);
+            }
+            sqlx::query("DELETE FROM remember_tokens WHERE user_id = ?")
+                .bind(record.user_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            return Ok(Redeemed::Stolen {
+                user_id: record.user_id,
+            });
+        }
+        sqlx::query("UPDATE remember_tokens SET replaced = UTC_TIMESTAMP() WHERE selector = ?")
+            .bind(selector)
+            .execute(&mut *tx)
+            .await?;
+        let (selector, token) = insert(
+            &mut tx,
+            &record.series,
+            record.user_id,
+            None,
+            record.expires,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(Redeemed::Valid {
+            user_id: record.user_id,
+            selector,
+            token,
+        })
+    }
+
+    /// Remembers which session a redeemed token started.
+    pub async fn attach(&self, selector: &str, session_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE remember_tokens SET session_id = ? WHERE selector = ?")
+            .bind(session_id)
+            .bind(selector)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Ends the series of a token, e.g. on logout.
+    pub async fn forget(&self, token: &str) -> Result<(), sqlx::Error> {
+        let Some((selector, _)) = token.split_once('.') else {
+            return Ok(());
+        };
+        let query = r#"DELETE rt FROM remember_tokens rt
+            JOIN remember_tokens cur ON cur.series = rt.series
+            WHERE cur.selector = ?"#;
+        sqlx::query(query)
+            .bind(selector)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+async fn insert(
+    tx: &mut Transaction<'_, MySql>,
+    series: &str,
+    user_id: i32,
+    session_id: Option<&str>,
+    expires: DateTime<Utc>,
+) -> Result<(String, String), sqlx::Error> {
+    let selector = token::generate();
+    let validator = token::generate();
+    let query = r#"INSERT INTO remember_tokens
+        (selector, validator_hash, series, user_id, session_id, expires, created)
+        VALUES(?, ?, ?, ?, ?, ?, UTC_TIMESTAMP())"#;
+    sqlx::query(query)
+        .bind(&selector)
+        .bind(token::digest(&validator))
+        .bind(series)
+        .bind(user_id)
+        .bind(session_id)
+        .bind(expires)
+        .execute(&mut **tx)
+        .await?;
+    let token = format!("{}.{}", selector, validator);
+    Ok((selector, token))
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use sqlx::{MySql, Pool, Transaction};
+
+/// Why somebody reported a snippet.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReportReason {
+    Spam,
+    Malware,
+    Secret,
+    Other,
+}
+
+impl ReportReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReportReason::Spam => "spam",
+            ReportReason::Malware => "malware",
+            ReportReason::Secret => "secret",
+            ReportReason::Other => "other",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ReportReason::Spam => "Spam",
+            ReportReason::Malware => "Malware",
+            ReportReason::Secret => "Leaked secret",
+            ReportReason::Other => "Other",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "spam" => Some(ReportReason::Spam),
+            "malware" => Some(ReportReason::Malware),
+            "secret" => Some(ReportReason::Secret),
+            "other" => Some(ReportReason::Other),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for ReportReason {
+    fn from(reason: String) -> Self {
+        ReportReason::parse(&reason).unwrap_or(ReportReason::Other)
+    }
+}
+
+/// How a moderator dealt with the reports about a snippet.
+#[derive(Clone, Copy)]
+pub enum Resolution {
+    Dismissed,
+    Deleted,
+}
+
+impl Resolution {
+    fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Dismissed => "dismissed",
+            Resolution::Deleted => "deleted",
+        }
+    }
+}
+
+/// A report waiting for a moderator, along with the snippet it is about.
+#[derive(sqlx::FromRow)]
+pub struct OpenReport {
+    pub id: i32,
+    pub snippet_id: i32,
+    #[sqlx(try_from = "String")]
+    pub reason: ReportReason,
+    pub details: String,
+    pub created: DateTime<Utc>,
+    // `None` for anonymous reports and those of the spam filter
+    pub reporter: Option<String>,
+    pub reporter_ip: Option<String>,
+    pub title: String,
+    pub hidden: Option<DateTime<Utc>>,
+    pub author_id: Option<i32>,
+    pub author: Option<String>,
+}
+
+const OPEN_REPORT_QUERY: &str = r#"SELECT r.id, r.snippet_id, r.reason, r.details, r.created,
+        reporter.name AS reporter, r.reporter_ip, s.title, s.hidden, s.user_id AS author_id,
+        author.name AS author
+    FROM snippet_reports r
+    JOIN snippets s ON s.id = r.snippet_id
+    LEFT JOIN users reporter ON reporter.id = r.reporter_id
+    LEFT JOIN users author ON author.id = s.user_id
+    WHERE r.resolved IS NULL AND s.deleted IS NULL"#;
+
+#[derive(Clone)]
+pub struct ReportModel {
+    pool: Pool<MySql>,
+}
+
+impl ReportModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// Files a report. Returns false when the same user, or the same address for anonymous
+    /// reports, has an open report about the snippet already.
+    pub async fn create(
+        &self,
+        snippet_id: i32,
+        reporter_id: Option<i32>,
+        reporter_ip: Option<&str>,
+        reason: ReportReason,
+        details: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let query = r#"SELECT COUNT(*) FROM snippet_reports
+            WHERE snippet_id = ? AND resolved IS NULL
+            AND (reporter_id = ? OR (? IS NULL AND reporter_id IS NULL AND reporter_ip = ?))"#;
+        let existing: i64 = sqlx::query_scalar(query)
+            .bind(snippet_id)
+            .bind(reporter_id)
+            .bind(reporter_id)
+            .bind(reporter_ip)
+            .fetch_one(&self.pool)
+            .await?;
+        if existing > 0 {
+            return Ok(false);
+        }
+        let query = r#"INSERT INTO snippet_reports (snippet_id, reporter_id, reporter_ip, reason, details, created)
+            VALUES (?, ?, ?, ?, ?, UTC_TIMESTAMP())"#;
+        sqlx::query(query)
+            .bind(snippet_id)
+            .bind(reporter_id)
+            .bind(reporter_ip)
+            .bind(reason.as_str())
+            .bind(details)
+            .execute(&self.pool)
+            .await?;
+        Ok(true)
+    }
+
+    /// Reports nobody has dealt with yet, oldest first.
+    pub async fn open(&self, limit: i64, offset: i64) -> Result<Vec<OpenReport>, sqlx::Error> {
+        let query = format!("{OPEN_REPORT_QUERY} ORDER BY r.created, r.id LIMIT ? OFFSET ?");
+        sqlx::query_as(&query)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// The report, as long as it is still open.
+    pub async fn find_open(&self, id: i32) -> Result<Option<OpenReport>, sqlx::Error> {
+        let query = format!("{OPEN_REPORT_QUERY} AND r.id = ?");
+        sqlx::query_as(&query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Closes every open report about the snippet, returns how many there were.
+    pub async fn resolve(
+        &self,
+        snippet_id: i32,
+        moderator_id: i32,
+        resolution: Resolution,
+    ) -> Result<u64, sqlx::Error> {
+        let query = r#"UPDATE snippet_reports
+            SET resolved = UTC_TIMESTAMP(), resolved_by = ?, resolution = ?
+            WHERE snippet_id = ? AND resolved IS NULL"#;
+        let resolved = sqlx::query(query)
+            .bind(moderator_id)
+            .bind(resolution.as_str())
+            .bind(snippet_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(resolved)
+    }
+}
+
+/// Puts a snippet the spam filter held back into the queue, `details` says why. Runs in the
+/// transaction that creates the snippet, see `SnippetModel::insert`.
+pub async fn flag(
+    tx: &mut Transaction<'_, MySql>,
+    snippet_id: u64,
+    details: &str,
+) -> Result<(), sqlx::Error> {
+    let query = r#"INSERT INTO snippet_reports (snippet_id, reason, details, created)
+        VALUES (?, ?, ?, UTC_TIMESTAMP())"#;
+    sqlx::query(query)
+        .bind(snippet_id)
+        .bind(ReportReason::Spam.as_str())
+        .bind(details)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
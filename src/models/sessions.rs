@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use sqlx::{MySql, Pool};
+
+use crate::models::users::Role;
+
+// last_seen is only written when it is older than this, not on every request
+const LAST_SEEN_RESOLUTION_SECONDS: i64 = 60;
+
+#[derive(sqlx::FromRow)]
+pub struct SessionUser {
+    pub name: String,
+    #[sqlx(try_from = "String")]
+    pub role: Role,
+    pub email_verified: bool,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// One logged in device of a user, as listed on the sessions page.
+#[derive(sqlx::FromRow)]
+pub struct DeviceSession {
+    pub session_id: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Which rows of the session store belong to which user, so that all of a user's
+/// sessions can be ended at once (e.g. after a password reset). Ending a session also
+/// ends the "remember me" login of its device.
+#[derive(Clone)]
+pub struct UserSessionModel {
+    pool: Pool<MySql>,
+}
+
+impl UserSessionModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    pub async fn record(
+        &self,
+        session_id: &str,
+        user_id: i32,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        // sessions the store has expired in the meantime
+        let query = r#"DELETE us FROM user_sessions us
+            LEFT JOIN sessions s ON s.id = us.session_id
+            WHERE us.user_id = ? AND s.id IS NULL"#;
+        sqlx::query(query).bind(user_id).execute(&self.pool).await?;
+        let query = r#"INSERT INTO user_sessions (session_id, user_id, ip, user_agent, created, last_seen)
+            VALUES(?, ?, ?, ?, UTC_TIMESTAMP(), UTC_TIMESTAMP())"#;
+        sqlx::query(query)
+            .bind(session_id)
+            .bind(user_id)
+            .bind(ip)
+            .bind(user_agent.map(|user_agent| truncate(user_agent, 255)))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// The logged in user behind a session, `None` once the session has been revoked
+    /// or the user no longer exists or has been disabled.
+    pub async fn lookup(
+        &self,
+        session_id: &str,
+        user_id: i32,
+    ) -> Result<Option<SessionUser>, sqlx::Error> {
+        let query = r#"SELECT u.name, u.role, u.email_verified, us.last_seen FROM user_sessions us
+            JOIN users u ON u.id = us.user_id
+            WHERE us.session_id = ? AND us.user_id = ? AND u.disabled IS NULL"#;
+        sqlx::query_as(query)
+            .bind(session_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Notes that the session was just used, from `ip`. Cheap to call on every request.
+    pub async fn touch(
+        &self,
+        session: &SessionUser,
+        session_id: &str,
+        ip: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        if (Utc::now() - session.last_seen).num_seconds() < LAST_SEEN_RESOLUTION_SECONDS {
+            return Ok(());
+        }
+        let query = r#"UPDATE user_sessions SET last_seen = UTC_TIMESTAMP(), ip = COALESCE(?, ip)
+            WHERE session_id = ?"#;
+        sqlx::query(query)
+            .bind(ip)
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// The user's sessions that are still alive in the store, most recently used first.
+    pub async fn list(&self, user_id: i32) -> Result<Vec<DeviceSession>, sqlx::Error> {
+        let query = r#"SELECT us.session_id, us.ip, us.user_agent, us.created, us.last_seen
+            FROM user_sessions us
+            JOIN sessions s ON s.id = us.session_id
+            WHERE us.user_id = ? AND s.expiry_date > UTC_TIMESTAMP()
+            ORDER BY us.last_seen DESC"#;
+        sqlx::query_as(query)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    pub async fn forget(&self, session_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM user_sessions WHERE session_id = ?")
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes one session of the user from the session store. Returns whether there was one.
+    pub async fn revoke(&self, user_id: i32, session_id: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let query = r#"DELETE s FROM sessions s
+            JOIN user_sessions us ON us.session_id = s.id
+            WHERE us.user_id = ? AND us.session_id = ?"#;
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(session_id)
+            .execute(&mut *tx)
+            .await?;
+        // a remembered device would otherwise just log in again
+        let query = r#"DELETE rt FROM remember_tokens rt
+            JOIN remember_tokens cur ON cur.series = rt.series
+            WHERE cur.user_id = ? AND cur.session_id = ?"#;
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(session_id)
+            .execute(&mut *tx)
+            .await?;
+        let revoked = sqlx::query("DELETE FROM user_sessions WHERE user_id = ? AND session_id = ?")
+            .bind(user_id)
+            .bind(session_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(revoked > 0)
+    }
+
+    /// Deletes every session of the user from the session store, remembered devices included.
+    pub async fn revoke_all(&self, user_id: i32) -> Result<u64, sqlx::Error> {
+        self.revoke_all_except(user_id, "").await
+    }
+
+    /// Deletes every session of the user but `keep` from the session store.
+    pub async fn revoke_all_except(&self, user_id: i32, keep: &str) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let query = r#"DELETE s FROM sessions s
+            JOIN user_sessions us ON us.session_id = s.id
+            WHERE us.user_id = ? AND us.session_id <> ?"#;
+        let revoked = sqlx::query(query)
+            .bind(user_id)
+            .bind(keep)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        let query = r#"DELETE rt FROM remember_tokens rt
+            LEFT JOIN remember_tokens cur ON cur.series = rt.series AND cur.session_id = ?
+            WHERE rt.user_id = ? AND cur.selector IS NULL"#;
+        sqlx::query(query)
+            .bind(keep)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM user_sessions WHERE user_id = ? AND session_id <> ?")
+            .bind(user_id)
+            .bind(keep)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(revoked)
+    }
+}
+
+// cuts at a character boundary so the value fits its column
+fn truncate(value: &str, max_chars: usize) -> String {
+    value.chars().take(max_chars).collect()
+}
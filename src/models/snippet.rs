@@ -1,8 +1,10 @@
 use sqlx::{
-    MySql, Pool,
-    types::chrono::{DateTime, Utc},
+    MySql, Pool, QueryBuilder,
+    types::chrono::{DateTime, NaiveDate, Utc},
 };
 
+use crate::models::{contains_pattern, reports};
+
 #[derive(sqlx::FromRow, Debug)]
 pub struct Snippet {
     pub id: i32,
@@ -10,6 +12,110 @@ pub struct Snippet {
     pub content: String,
     pub created: DateTime<Utc>,
     pub expires: DateTime<Utc>,
+    pub user_id: Option<i32>,
+    pub visibility: String,
+    pub revision: i32,
+    // when the content was last changed or the snippet was restored, none if neither happened
+    pub updated: Option<DateTime<Utc>>,
+    // set while a moderator reviews reports about the snippet
+    pub hidden: Option<DateTime<Utc>>,
+}
+
+impl Snippet {
+    /// Private snippets and hidden ones can only be seen by their owner, everything else by
+    /// anyone with the link.
+    pub fn visible_to(&self, user_id: Option<i32>) -> bool {
+        let owner = user_id.is_some() && user_id == self.user_id;
+        owner || (self.visibility != VISIBILITY_PRIVATE && self.hidden.is_none())
+    }
+
+    /// When the content was last changed, or created if it never was.
+    pub fn modified(&self) -> DateTime<Utc> {
+        self.updated.unwrap_or(self.created)
+    }
+}
+
+// public snippets are listed everywhere, unlisted ones are reachable only through their link
+// and private ones only by their owner.
+pub const VISIBILITY_PUBLIC: &str = "public";
+pub const VISIBILITY_UNLISTED: &str = "unlisted";
+pub const VISIBILITY_PRIVATE: &str = "private";
+
+const SNIPPET_COLUMNS: &str = "snippets.id, snippets.title, snippets.content, snippets.created, snippets.expires, snippets.user_id, snippets.visibility, snippets.revision, snippets.updated, snippets.hidden";
+
+/// A snippet as listed in the admin area, whatever state it is in.
+#[derive(sqlx::FromRow)]
+pub struct SnippetSummary {
+    pub id: i32,
+    pub title: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub visibility: String,
+    pub deleted: Option<DateTime<Utc>>,
+    pub hidden: Option<DateTime<Utc>>,
+    pub user_id: Option<i32>,
+    // `None` for anonymous snippets and those of deleted users
+    pub author: Option<String>,
+}
+
+/// Which snippets `SnippetModel::browse` lists.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SnippetState {
+    All,
+    Live,
+    Expired,
+    Deleted,
+}
+
+impl SnippetState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SnippetState::All => "all",
+            SnippetState::Live => "live",
+            SnippetState::Expired => "expired",
+            SnippetState::Deleted => "deleted",
+        }
+    }
+
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "live" => SnippetState::Live,
+            "expired" => SnippetState::Expired,
+            "deleted" => SnippetState::Deleted,
+            _ => SnippetState::All,
+        }
+    }
+}
+
+/// Narrows down the listing returned by `SnippetModel::latest_for`.
+#[derive(Clone, Copy)]
+pub enum LatestFilter<'a> {
+    All,
+    User(i32),
+    Tag(&'a str),
+}
+
+impl LatestFilter<'_> {
+    // the public snippets the filter picks, live or not
+    fn query(&self, columns: &str) -> QueryBuilder<'_, MySql> {
+        let mut query = QueryBuilder::<MySql>::new(format!("SELECT {columns} FROM snippets"));
+        if let LatestFilter::Tag(_) = self {
+            query.push(" INNER JOIN snippet_tags ON snippet_tags.snippet_id = snippets.id");
+        }
+        query
+            .push(" WHERE snippets.visibility = ")
+            .push_bind(VISIBILITY_PUBLIC);
+        match self {
+            LatestFilter::All => {}
+            LatestFilter::User(user_id) => {
+                query.push(" AND snippets.user_id = ").push_bind(*user_id);
+            }
+            LatestFilter::Tag(tag) => {
+                query.push(" AND snippet_tags.tag = ").push_bind(*tag);
+            }
+        }
+        query
+    }
 }
 
 #[derive(Clone)]
@@ -22,34 +128,64 @@ impl SnippetModel {
         Self { pool }
     }
 
+    /// `held_back` says why the spam filter held the snippet back. Such a snippet is hidden
+    /// and in the moderation queue from the start, it is never visible to anybody else.
+    #[allow(clippy::too_many_arguments)]
     pub async fn insert(
         &self,
         title: String,
         content: String,
         expires: i32,
-    ) -> Result<u64, sqlx::Error> {
-        let query = r#"INSERT INTO snippets (title, content, created, expires) 
-                VALUES (?, ?, UTC_TIMESTAMP(), DATE_ADD(UTC_TIMESTAMP(), INTERVAL ? DAY))"#;
+        user_id: Option<i32>,
+        visibility: &str,
+        tags: &[String],
+        held_back: Option<&str>,
+    ) -> Result<(u64, DateTime<Utc>), sqlx::Error> {
+        let query = r#"INSERT INTO snippets (title, content, created, expires, user_id, visibility, hidden)
+                VALUES (?, ?, UTC_TIMESTAMP(), DATE_ADD(UTC_TIMESTAMP(), INTERVAL ? DAY), ?, ?,
+                IF(?, UTC_TIMESTAMP(), NULL))"#;
 
-        match sqlx::query(query)
+        let mut tx = self.pool.begin().await?;
+        let id = match sqlx::query(query)
             .bind(title)
             .bind(content)
             .bind(expires)
-            .execute(&self.pool)
+            .bind(user_id)
+            .bind(visibility)
+            .bind(held_back.is_some())
+            .execute(&mut *tx)
             .await
         {
-            Ok(r) => Ok(r.last_insert_id()),
+            Ok(r) => r.last_insert_id(),
             Err(e) => {
                 tracing::error!("record could not be inserted : {}", e);
                 return Err(e);
             }
+        };
+        for tag in tags {
+            sqlx::query("INSERT INTO snippet_tags (snippet_id, tag) VALUES (?, ?)")
+                .bind(id)
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
         }
+        if let Some(reasons) = held_back {
+            reports::flag(&mut tx, id, reasons).await?;
+        }
+        // as stored, which is what the listings show
+        let created = sqlx::query_scalar("SELECT created FROM snippets WHERE id = ?")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok((id, created))
     }
 
     pub async fn get(&self, id: &u32) -> Result<Snippet, sqlx::Error> {
-        let query = r#"SELECT id, title, content, created, expires FROM snippets
-            WHERE expires > UTC_TIMESTAMP() AND id = ?"#;
-        match sqlx::query_as::<_, Snippet>(query)
+        let query = format!(
+            "SELECT {SNIPPET_COLUMNS} FROM snippets WHERE expires > UTC_TIMESTAMP() AND deleted IS NULL AND id = ?"
+        );
+        match sqlx::query_as::<_, Snippet>(&query)
             .bind(id)
             .fetch_one(&self.pool)
             .await
@@ -57,29 +193,186 @@ impl SnippetModel {
             Ok(res) => Ok(res),
             Err(e) => {
                 if let sqlx::error::Error::RowNotFound = e {
-                    tracing::error!("record could not be found : {}", e.to_string());
+                    tracing::error!("record could not be found : {}", e);
                 } else {
-                    tracing::error!("query failed : {}", e.to_string());
+                    tracing::error!("query failed : {}", e);
                 }
                 Err(e)
             }
         }
     }
 
+    /// Saves what a live session made of the snippet, a draft it left behind is done with.
+    /// Returns false when the snippet has been deleted in the meantime.
+    pub async fn update_content(&self, id: i32, content: String) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let updated = sqlx::query(
+            "UPDATE snippets SET content = ?, revision = revision + 1, updated = UTC_TIMESTAMP() WHERE id = ? AND deleted IS NULL",
+        )
+            .bind(content)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("DELETE FROM snippet_drafts WHERE snippet_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(updated > 0)
+    }
+
+    /// Keeps text a live session could not save before it ended, because nobody confirmed
+    /// the secrets in it. Only the latest draft of a snippet is kept.
+    pub async fn save_draft(&self, id: i32, content: &str) -> Result<(), sqlx::Error> {
+        let query = r#"INSERT INTO snippet_drafts (snippet_id, content, saved)
+            VALUES (?, ?, UTC_TIMESTAMP())
+            ON DUPLICATE KEY UPDATE content = VALUES(content), saved = VALUES(saved)"#;
+        sqlx::query(query)
+            .bind(id)
+            .bind(content)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn draft(&self, id: i32) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT content FROM snippet_drafts WHERE snippet_id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn tags(&self, id: i32) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT tag FROM snippet_tags WHERE snippet_id = ? ORDER BY tag")
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
     pub async fn latest(&self) -> Result<Vec<Snippet>, sqlx::Error> {
-        let query = r#"SELECT id, title, content, created, expires FROM snippets
-            WHERE expires > UTC_TIMESTAMP() ORDER BY id DESC LIMIT 10"#;
+        self.latest_for(LatestFilter::All).await
+    }
+
+    /// The ten newest live public snippets, optionally restricted to one author or one tag.
+    pub async fn latest_for(&self, filter: LatestFilter<'_>) -> Result<Vec<Snippet>, sqlx::Error> {
+        let mut query = filter.query(SNIPPET_COLUMNS);
+        query
+            .push(" AND snippets.expires > UTC_TIMESTAMP() AND snippets.deleted IS NULL")
+            .push(" AND snippets.hidden IS NULL")
+            .push(" ORDER BY snippets.id DESC LIMIT 10");
 
-        match sqlx::query_as::<_, Snippet>(query)
+        match query
+            .build_query_as::<Snippet>()
             .fetch_all(&self.pool)
             .await
         {
             Ok(r) => Ok(r),
             Err(e) => {
-                tracing::error!("some problem : {}", e.to_string());
+                tracing::error!("some problem : {}", e);
                 Err(e)
             }
         }
         // defer rows.Close()
     }
+
+    /// When the last of the snippets `latest_for` could list expired, or was deleted, restored,
+    /// hidden or shown again, none if that never happened. Those change a listing just like
+    /// additions. Snippets deleted along with their owner's account leave nothing to go by.
+    pub async fn last_removed(
+        &self,
+        filter: LatestFilter<'_>,
+    ) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        let mut query = filter.query(
+            "MAX(CASE WHEN snippets.expires <= UTC_TIMESTAMP() THEN snippets.expires END), \
+            MAX(snippets.moderated)",
+        );
+        let (expired, moderated) = query
+            .build_query_as::<(Option<DateTime<Utc>>, Option<DateTime<Utc>>)>()
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(expired.max(moderated))
+    }
+
+    /// Every snippet whose title contains `term`, expired, private and deleted ones included,
+    /// newest first.
+    pub async fn browse(
+        &self,
+        term: &str,
+        state: SnippetState,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<SnippetSummary>, sqlx::Error> {
+        let mut query = QueryBuilder::<MySql>::new(
+            r#"SELECT s.id, s.title, s.created, s.expires, s.visibility, s.deleted, s.hidden,
+                s.user_id,
+                u.name AS author
+            FROM snippets s LEFT JOIN users u ON u.id = s.user_id
+            WHERE s.title LIKE "#,
+        );
+        query.push_bind(contains_pattern(term));
+        match state {
+            SnippetState::All => {}
+            SnippetState::Live => {
+                query.push(
+                    " AND s.expires > UTC_TIMESTAMP() AND s.deleted IS NULL AND s.hidden IS NULL",
+                );
+            }
+            SnippetState::Expired => {
+                query.push(" AND s.expires <= UTC_TIMESTAMP()");
+            }
+            SnippetState::Deleted => {
+                query.push(" AND s.deleted IS NOT NULL");
+            }
+        }
+        query
+            .push(" ORDER BY s.id DESC LIMIT ")
+            .push_bind(limit)
+            .push(" OFFSET ")
+            .push_bind(offset);
+        query.build_query_as().fetch_all(&self.pool).await
+    }
+
+    /// Hides the snippet everywhere, or brings it back. Returns false when there is no such
+    /// snippet, or it already was deleted or restored.
+    pub async fn set_deleted(&self, id: i32, deleted: bool) -> Result<bool, sqlx::Error> {
+        // coming back changes the listings as much as leaving them, the content stays the same
+        let query = if deleted {
+            "UPDATE snippets SET deleted = UTC_TIMESTAMP(), moderated = UTC_TIMESTAMP() WHERE id = ? AND deleted IS NULL"
+        } else {
+            "UPDATE snippets SET deleted = NULL, moderated = UTC_TIMESTAMP() WHERE id = ? AND deleted IS NOT NULL"
+        };
+        let result = sqlx::query(query).bind(id).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Hides the snippet from everyone but its owner and moderators, or shows it again.
+    /// Returns false when there is no such snippet, or it already was hidden or shown.
+    pub async fn set_hidden(&self, id: i32, hidden: bool) -> Result<bool, sqlx::Error> {
+        let query = if hidden {
+            "UPDATE snippets SET hidden = UTC_TIMESTAMP(), moderated = UTC_TIMESTAMP() WHERE id = ? AND hidden IS NULL"
+        } else {
+            "UPDATE snippets SET hidden = NULL, moderated = UTC_TIMESTAMP() WHERE id = ? AND hidden IS NOT NULL"
+        };
+        let result = sqlx::query(query).bind(id).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Snippets anyone with the link could open right now.
+    pub async fn live_count(&self) -> Result<i64, sqlx::Error> {
+        let query = "SELECT COUNT(*) FROM snippets WHERE expires > UTC_TIMESTAMP() AND deleted IS NULL AND hidden IS NULL";
+        sqlx::query_scalar(query).fetch_one(&self.pool).await
+    }
+
+    /// How many snippets were created on each of the last `days` days (UTC), oldest first.
+    /// Days without any are left out.
+    pub async fn created_per_day(&self, days: i64) -> Result<Vec<(NaiveDate, i64)>, sqlx::Error> {
+        let query = r#"SELECT DATE(created) AS day, COUNT(*) FROM snippets
+            WHERE created >= DATE_SUB(UTC_DATE(), INTERVAL ? DAY)
+            GROUP BY day ORDER BY day"#;
+        sqlx::query_as(query)
+            .bind(days - 1)
+            .fetch_all(&self.pool)
+            .await
+    }
 }
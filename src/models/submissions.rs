@@ -0,0 +1,84 @@
+use sqlx::{MySql, Pool};
+
+pub const KIND_SNIPPET: &str = "snippet";
+pub const KIND_SIGNUP: &str = "signup";
+
+/// What the spam filter has seen submitted, to count repeats and bursts against.
+#[derive(Clone)]
+pub struct SubmissionModel {
+    pool: Pool<MySql>,
+}
+
+impl SubmissionModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    pub async fn record(
+        &self,
+        kind: &str,
+        ip: Option<&str>,
+        user_id: Option<i32>,
+        content_hash: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let query = r#"INSERT INTO submissions (kind, ip, user_id, content_hash, created)
+            VALUES (?, ?, ?, ?, UTC_TIMESTAMP())"#;
+        sqlx::query(query)
+            .bind(kind)
+            .bind(ip)
+            .bind(user_id)
+            .bind(content_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Submissions of the kind from the address within the last `minutes`.
+    pub async fn count_from_ip(
+        &self,
+        kind: &str,
+        ip: &str,
+        minutes: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let query = r#"SELECT COUNT(*) FROM submissions
+            WHERE kind = ? AND ip = ? AND created > DATE_SUB(UTC_TIMESTAMP(), INTERVAL ? MINUTE)"#;
+        sqlx::query_scalar(query)
+            .bind(kind)
+            .bind(ip)
+            .bind(minutes)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// Submissions of the kind by the user within the last `minutes`.
+    pub async fn count_from_user(
+        &self,
+        kind: &str,
+        user_id: i32,
+        minutes: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let query = r#"SELECT COUNT(*) FROM submissions
+            WHERE kind = ? AND user_id = ? AND created > DATE_SUB(UTC_TIMESTAMP(), INTERVAL ? MINUTE)"#;
+        sqlx::query_scalar(query)
+            .bind(kind)
+            .bind(user_id)
+            .bind(minutes)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// Submissions with the same content within the last `minutes`, by anybody.
+    pub async fn count_with_hash(
+        &self,
+        content_hash: &str,
+        minutes: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let query = r#"SELECT COUNT(*) FROM submissions
+            WHERE content_hash = ? AND created > DATE_SUB(UTC_TIMESTAMP(), INTERVAL ? MINUTE)"#;
+        sqlx::query_scalar(query)
+            .bind(content_hash)
+            .bind(minutes)
+            .fetch_one(&self.pool)
+            .await
+    }
+}
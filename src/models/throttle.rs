@@ -0,0 +1,168 @@
+use sqlx::{
+    MySql, Pool,
+    types::chrono::{DateTime, Utc},
+};
+
+// attempts that are let through before the backoff kicks in
+const FREE_EMAIL_FAILURES: i32 = 5;
+// many people can share an address (offices, NAT), so be more lenient per ip
+const FREE_IP_FAILURES: i32 = 20;
+// the delay doubles with every further failure up to this many seconds
+const MAX_LOCKOUT_SECONDS: i64 = 60 * 60;
+
+const KIND_EMAIL: &str = "email";
+const KIND_IP: &str = "ip";
+
+/// An email or ip that may not try to log in for now, as listed for admins.
+#[derive(sqlx::FromRow)]
+pub struct Lockout {
+    pub kind: String,
+    #[sqlx(rename = "throttle_key")]
+    pub key: String,
+    pub failures: i32,
+    pub locked_until: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct LoginThrottleModel {
+    pool: Pool<MySql>,
+}
+
+fn lockout_seconds(failures: i32, free_failures: i32) -> Option<i64> {
+    let over = failures - free_failures;
+    if over < 0 {
+        return None;
+    }
+    Some(
+        2i64.saturating_pow(over.min(32) as u32)
+            .min(MAX_LOCKOUT_SECONDS),
+    )
+}
+
+impl LoginThrottleModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// When either the email or the ip is locked out, returns until when.
+    pub async fn locked_until(
+        &self,
+        email: &str,
+        ip: Option<&str>,
+    ) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        let query = r#"SELECT MAX(locked_until) FROM login_throttles
+            WHERE locked_until > UTC_TIMESTAMP()
+            AND ((kind = ? AND throttle_key = ?) OR (kind = ? AND throttle_key = ?))"#;
+        sqlx::query_scalar(query)
+            .bind(KIND_EMAIL)
+            .bind(email.trim().to_lowercase())
+            .bind(KIND_IP)
+            .bind(ip.unwrap_or_default())
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// The most failed logins within the last day, of the email or of the ip.
+    pub async fn failures(
+        &self,
+        email: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<i32, sqlx::Error> {
+        let query = r#"SELECT MAX(failures) FROM login_throttles
+            WHERE last_failure > UTC_TIMESTAMP() - INTERVAL 1 DAY
+            AND ((kind = ? AND throttle_key = ?) OR (kind = ? AND throttle_key = ?))"#;
+        sqlx::query_scalar(query)
+            .bind(KIND_EMAIL)
+            .bind(email.map(|email| email.trim().to_lowercase()))
+            .bind(KIND_IP)
+            .bind(ip)
+            .fetch_one(&self.pool)
+            .await
+            .map(|failures: Option<i32>| failures.unwrap_or(0))
+    }
+
+    pub async fn record_failure(&self, email: &str, ip: Option<&str>) -> Result<(), sqlx::Error> {
+        self.record(
+            KIND_EMAIL,
+            &email.trim().to_lowercase(),
+            FREE_EMAIL_FAILURES,
+        )
+        .await?;
+        if let Some(ip) = ip {
+            self.record(KIND_IP, ip, FREE_IP_FAILURES).await?;
+        }
+        Ok(())
+    }
+
+    async fn record(&self, kind: &str, key: &str, free_failures: i32) -> Result<(), sqlx::Error> {
+        // the upsert locks the row until the commit, concurrent failures are counted one by one
+        let mut tx = self.pool.begin().await?;
+        // failures older than a day are forgotten
+        let query = r#"INSERT INTO login_throttles (kind, throttle_key, failures, last_failure)
+            VALUES (?, ?, 1, UTC_TIMESTAMP())
+            ON DUPLICATE KEY UPDATE
+                failures = IF(last_failure < UTC_TIMESTAMP() - INTERVAL 1 DAY, 1, failures + 1),
+                last_failure = UTC_TIMESTAMP()"#;
+        sqlx::query(query)
+            .bind(kind)
+            .bind(key)
+            .execute(&mut *tx)
+            .await?;
+        let failures: i32 = sqlx::query_scalar(
+            "SELECT failures FROM login_throttles WHERE kind = ? AND throttle_key = ?",
+        )
+        .bind(kind)
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+        if let Some(seconds) = lockout_seconds(failures, free_failures) {
+            tracing::info!(
+                "locking out {} {} for {} seconds after {} failed logins",
+                kind,
+                key,
+                seconds,
+                failures
+            );
+            sqlx::query(
+                r#"UPDATE login_throttles SET locked_until = DATE_ADD(UTC_TIMESTAMP(), INTERVAL ? SECOND)
+                WHERE kind = ? AND throttle_key = ?"#,
+            )
+            .bind(seconds)
+            .bind(kind)
+            .bind(key)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// The emails and ips locked out right now, those locked out longest first.
+    pub async fn active_lockouts(&self) -> Result<Vec<Lockout>, sqlx::Error> {
+        let query = r#"SELECT kind, throttle_key, failures, locked_until FROM login_throttles
+            WHERE locked_until > UTC_TIMESTAMP()
+            ORDER BY locked_until DESC"#;
+        sqlx::query_as(query).fetch_all(&self.pool).await
+    }
+
+    /// Forgets the failures of an email or ip, lifting its lockout. Returns whether there
+    /// were any.
+    pub async fn unlock(&self, kind: &str, key: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM login_throttles WHERE kind = ? AND throttle_key = ?")
+            .bind(kind)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Forgets the failures of the email after a successful login. Those of the ip are left to
+    /// age out, one good password must not wipe the record of an address guessing at others.
+    pub async fn reset(&self, email: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM login_throttles WHERE kind = ? AND throttle_key = ?")
+            .bind(KIND_EMAIL)
+            .bind(email.trim().to_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use sqlx::{MySql, Pool};
+
+use crate::utils::{token, totp};
+
+#[derive(sqlx::FromRow)]
+pub struct TotpSecret {
+    pub secret: String,
+    pub last_step: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct TwoFactorModel {
+    pool: Pool<MySql>,
+}
+
+impl TwoFactorModel {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// The user's authenticator secret, `None` when two-factor authentication is off.
+    pub async fn secret(&self, user_id: i32) -> Result<Option<TotpSecret>, sqlx::Error> {
+        let query = r#"SELECT totp_secret AS secret, totp_last_step AS last_step FROM users
+            WHERE id = ? AND totp_secret IS NOT NULL"#;
+        sqlx::query_as(query)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    /// Turns two-factor authentication on (or replaces the authenticator) together
+    /// with a fresh set of recovery codes.
+    pub async fn enable(
+        &self,
+        user_id: i32,
+        secret: &str,
+        recovery_codes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE users SET totp_secret = ?, totp_last_step = NULL WHERE id = ?")
+            .bind(secret)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        for code in recovery_codes {
+            sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES(?, ?)")
+                .bind(user_id)
+                .bind(token::digest(&totp::normalize_recovery_code(code)))
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    pub async fn disable(&self, user_id: i32) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE users SET totp_secret = NULL, totp_last_step = NULL WHERE id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Records the time step of an accepted code. Returns false when a code of the same
+    /// or a later step has been accepted in the meantime, i.e. the code is being replayed.
+    pub async fn use_step(&self, user_id: i32, step: i64) -> Result<bool, sqlx::Error> {
+        let query = r#"UPDATE users SET totp_last_step = ?
+            WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)"#;
+        let result = sqlx::query(query)
+            .bind(step)
+            .bind(user_id)
+            .bind(step)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Burns the recovery code, returns false if the user has no such (unused) code.
+    pub async fn use_recovery_code(&self, user_id: i32, code: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM recovery_codes WHERE user_id = ? AND code_hash = ?")
+            .bind(user_id)
+            .bind(token::digest(&totp::normalize_recovery_code(code)))
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn recovery_codes_left(&self, user_id: i32) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+    }
+}
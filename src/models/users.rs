@@ -1,20 +1,70 @@
 use sqlx::{
-    MySql, Pool,
+    MySql, Pool, QueryBuilder,
     types::chrono::{DateTime, Utc},
 };
 
-use std::{error::Error, f64::consts::E};
+use std::error::Error;
 
-use bcrypt::hash;
+use crate::{
+    models::{contains_pattern, errors::ErrInvalidCredentials, snippet::VISIBILITY_PRIVATE},
+    passwords::Passwords,
+};
 
-use crate::models::errors::ErrInvalidCredentials;
+/// What a user may do besides using the site. Roles are ranked, each includes the ones
+/// before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
 
-struct User {
-    id: i32,
-    name: String,
-    email: String,
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl From<String> for Role {
+    // anything unexpected in the column grants nothing
+    fn from(role: String) -> Self {
+        match role.as_str() {
+            "moderator" => Role::Moderator,
+            "admin" => Role::Admin,
+            _ => Role::User,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(sqlx::FromRow)]
+pub struct User {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
     hashed_password: Vec<u8>,
-    created: DateTime<Utc>,
+    pub email_verified: bool,
+    #[sqlx(try_from = "String")]
+    pub role: Role,
+    pub created: DateTime<Utc>,
+}
+
+/// A user as listed in the admin area.
+#[derive(sqlx::FromRow)]
+pub struct UserSummary {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    #[sqlx(try_from = "String")]
+    pub role: Role,
+    pub email_verified: bool,
+    pub created: DateTime<Utc>,
+    pub disabled: Option<DateTime<Utc>>,
+    pub snippets: i64,
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -23,19 +73,15 @@ struct UserRecord {
     hashed_password: Vec<u8>,
 }
 
-#[derive(sqlx::FromRow, Debug)]
-struct Exists {
-    flag: i32,
-}
-
 #[derive(Clone)]
 pub struct UserModel {
     pool: Pool<MySql>,
+    passwords: Passwords,
 }
 
 impl UserModel {
-    pub fn new(pool: Pool<MySql>) -> Self {
-        Self { pool }
+    pub fn new(pool: Pool<MySql>, passwords: Passwords) -> Self {
+        Self { pool, passwords }
     }
 
     pub async fn insert(
@@ -45,7 +91,7 @@ impl UserModel {
         password: String,
     ) -> Result<u64, Box<dyn Error + Send>> {
         // error can pop-up during hashing or insertion.
-        let hashed_password = hash(password, 12);
+        let hashed_password = self.passwords.hash(&password).await;
         if let Err(e) = hashed_password {
             tracing::error!(
                 "encountered problems during user creation : password hashing failed : {}",
@@ -85,14 +131,15 @@ impl UserModel {
             .await
         {
             Ok(res) => {
-                match bcrypt::verify(
-                    password,
-                    String::from_utf8(res.hashed_password).unwrap().as_str(),
-                ) {
-                    Ok(x) => {
-                        if x {
+                let hashed_password = String::from_utf8_lossy(&res.hashed_password).to_string();
+                match self.passwords.verify(password, &hashed_password).await {
+                    Ok(verification) => {
+                        if verification.matches {
                             tracing::info!("login successful for {} ", email);
-                            return Ok(res.id);
+                            if verification.needs_rehash {
+                                self.rehash(res.id, password, &hashed_password).await;
+                            }
+                            Ok(res.id)
                         } else {
                             tracing::info!(
                                 "login attempt failed due to invalid credentials for {}",
@@ -102,36 +149,220 @@ impl UserModel {
                         }
                     }
                     Err(e) => {
-                        if let bcrypt::BcryptError::InvalidHash(inv_pwd_msg) = &e {
-                            tracing::info!(
-                                "could not hash password correctly for user : {}, error: {}",
-                                email,
-                                inv_pwd_msg.clone()
-                            );
-                        }
-                        return Err(Box::new(e));
+                        tracing::info!(
+                            "could not verify the password of user : {}, error: {}",
+                            email,
+                            e
+                        );
+                        Err(Box::new(e))
                     }
                 }
             }
-            Err(e) => return Err(Box::new(e)),
+            Err(sqlx::Error::RowNotFound) => {
+                // spend the same time as for a wrong password so unknown emails cannot be told apart
+                self.passwords.verify_dummy(password).await;
+                tracing::info!("login attempt failed for unknown email {}", email);
+                Err(Box::new(ErrInvalidCredentials))
+            }
+            Err(e) => Err(Box::new(e)),
         }
     }
 
-    pub async fn exists(&self, id: i32) -> Result<bool, sqlx::Error> {
-        let query = "SELECT EXISTS(SELECT true FROM users WHERE id = ?) as flag";
-        let query_res = sqlx::query_as::<_, Exists>(query)
+    /// Re-checks the password of a logged in user before sensitive changes.
+    pub async fn check_password(
+        &self,
+        id: i32,
+        password: &str,
+    ) -> Result<bool, Box<dyn Error + Send>> {
+        let query = "SELECT id, hashed_password FROM users WHERE id = ?";
+        let record = sqlx::query_as::<_, UserRecord>(query)
             .bind(id)
             .fetch_one(&self.pool)
-            .await;
-        match query_res {
-            Ok(res) => {
-                if res.flag == 1 {
-                    Ok(true)
-                } else {
-                    Ok(false)
-                }
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn Error + Send>)?;
+        let hashed_password = String::from_utf8_lossy(&record.hashed_password).to_string();
+        self.passwords
+            .verify(password, &hashed_password)
+            .await
+            .map(|verification| verification.matches)
+            .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+    }
+
+    /// Replaces a hash of an older scheme, or older settings, after the password was
+    /// verified against it. A failure only means trying again on the next login.
+    async fn rehash(&self, id: i32, password: &str, old_hash: &str) {
+        let hashed_password = match self.passwords.hash(password).await {
+            Ok(hashed_password) => hashed_password,
+            Err(e) => {
+                tracing::error!("could not rehash the password of user {} : {}", id, e);
+                return;
             }
-            Err(e) => Err(e),
+        };
+        // unless the password was changed in the meantime
+        let query = "UPDATE users SET hashed_password = ? WHERE id = ? AND hashed_password = ?";
+        match sqlx::query(query)
+            .bind(hashed_password)
+            .bind(id)
+            .bind(old_hash)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(_) => tracing::info!("password hash of user {} upgraded", id),
+            Err(e) => tracing::error!("could not store the new hash of user {} : {}", id, e),
+        }
+    }
+
+    pub async fn get(&self, id: i32) -> Result<User, sqlx::Error> {
+        let query = "SELECT id, name, email, hashed_password, email_verified, role, created FROM users WHERE id = ?";
+        sqlx::query_as::<_, User>(query)
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    pub async fn name(&self, id: i32) -> Result<String, sqlx::Error> {
+        sqlx::query_scalar("SELECT name FROM users WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// The id and the stored spelling of the email address, if there is such a user.
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<(i32, String)>, sqlx::Error> {
+        sqlx::query_as("SELECT id, email FROM users WHERE email = ?")
+            .bind(email)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn update_name(&self, id: i32, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE users SET name = ? WHERE id = ?")
+            .bind(name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns false when there is no user with that address.
+    pub async fn set_role_by_email(&self, email: &str, role: Role) -> Result<bool, sqlx::Error> {
+        let user = self.find_by_email(email).await?;
+        let Some((id, _)) = user else {
+            return Ok(false);
+        };
+        sqlx::query("UPDATE users SET role = ? WHERE id = ?")
+            .bind(role.as_str())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(true)
+    }
+
+    /// Users whose name or email address contains `term`, newest first. An empty term lists everybody.
+    pub async fn search(
+        &self,
+        term: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UserSummary>, sqlx::Error> {
+        let query = r#"SELECT u.id, u.name, u.email, u.role, u.email_verified, u.created, u.disabled,
+                (SELECT COUNT(*) FROM snippets s WHERE s.user_id = u.id) AS snippets
+            FROM users u
+            WHERE u.name LIKE ? OR u.email LIKE ?
+            ORDER BY u.id DESC LIMIT ? OFFSET ?"#;
+        let pattern = contains_pattern(term);
+        sqlx::query_as(query)
+            .bind(&pattern)
+            .bind(&pattern)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// The number of users, and how many of them are disabled.
+    pub async fn counts(&self) -> Result<(i64, i64), sqlx::Error> {
+        sqlx::query_as("SELECT COUNT(*), COUNT(disabled) FROM users")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// Whether the user may not log in. Users that no longer exist may not either.
+    pub async fn is_disabled(&self, id: i32) -> Result<bool, sqlx::Error> {
+        let disabled: Option<Option<DateTime<Utc>>> =
+            sqlx::query_scalar("SELECT disabled FROM users WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(disabled.is_none_or(|disabled| disabled.is_some()))
+    }
+
+    /// Disables or enables the account. Ending the sessions of a disabled user is up to the caller.
+    pub async fn set_disabled(&self, id: i32, disabled: bool) -> Result<(), sqlx::Error> {
+        let query = if disabled {
+            "UPDATE users SET disabled = UTC_TIMESTAMP() WHERE id = ? AND disabled IS NULL"
+        } else {
+            "UPDATE users SET disabled = NULL WHERE id = ?"
+        };
+        sqlx::query(query).bind(id).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Moves the account to a new address, which has to be verified again. Links that
+    /// went to the old address stop working.
+    pub async fn update_email(&self, id: i32, email: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE users SET email = ?, email_verified = FALSE WHERE id = ?")
+            .bind(email)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM email_verifications WHERE user_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM password_resets WHERE user_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    pub async fn update_password(
+        &self,
+        id: i32,
+        password: &str,
+    ) -> Result<(), Box<dyn Error + Send>> {
+        let hashed_password = self.passwords.hash(password).await.map_err(|e| {
+            tracing::error!("password change failed : password hashing failed : {}", e);
+            Box::new(e) as Box<dyn Error + Send>
+        })?;
+        sqlx::query("UPDATE users SET hashed_password = ? WHERE id = ?")
+            .bind(hashed_password)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn Error + Send>)?;
+        Ok(())
+    }
+
+    /// Deletes the user. Their snippets are deleted as well, or kept without an owner
+    /// when `keep_snippets` is set. Private snippets are never kept, nobody could see them.
+    pub async fn delete(&self, id: i32, keep_snippets: bool) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut query = QueryBuilder::<MySql>::new("DELETE FROM snippets WHERE user_id = ");
+        query.push_bind(id);
+        if keep_snippets {
+            query
+                .push(" AND visibility = ")
+                .push_bind(VISIBILITY_PRIVATE);
         }
+        // the owner column is cleared by the foreign key for whatever is left
+        query.build().execute(&mut *tx).await?;
+        sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
     }
 }
@@ -0,0 +1,271 @@
+// Passkeys (WebAuthn). They are added on the account page while logged in. Signing in uses
+// discoverable credentials: the authenticator tells which user it holds a passkey for, so
+// nobody types an email address first. Between its two steps a ceremony's state lives in
+// the session.
+use std::sync::Arc;
+
+use thiserror::Error;
+use webauthn_rs::prelude::{
+    AuthenticationResult, CreationChallengeResponse, DiscoverableAuthentication, DiscoverableKey,
+    Passkey, PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse, Url, Uuid, Webauthn, WebauthnBuilder, WebauthnError,
+};
+use webauthn_rs_proto::ResidentKeyRequirement;
+
+#[derive(Debug, Error)]
+pub enum PasskeyError {
+    #[error("invalid passkey configuration : {0}")]
+    Configuration(String),
+    #[error("passkey ceremony failed : {0}")]
+    Ceremony(#[from] WebauthnError),
+}
+
+#[derive(Clone)]
+pub struct Passkeys {
+    webauthn: Arc<Webauthn>,
+}
+
+impl Passkeys {
+    /// The relying party is the host of `base_url`, passkeys only work on that origin.
+    pub fn new(base_url: &str) -> Result<Self, PasskeyError> {
+        let origin =
+            Url::parse(base_url).map_err(|e| PasskeyError::Configuration(e.to_string()))?;
+        let rp_id = origin
+            .host_str()
+            .ok_or_else(|| PasskeyError::Configuration(format!("{} has no host", base_url)))?
+            .to_string();
+        let webauthn = WebauthnBuilder::new(&rp_id, &origin)
+            .and_then(|builder| builder.rp_name("Snippetbox").build())
+            .map_err(|e| PasskeyError::Configuration(e.to_string()))?;
+        Ok(Self {
+            webauthn: Arc::new(webauthn),
+        })
+    }
+
+    /// Options for the browser to create a passkey with, and the state to keep until it answers.
+    /// The user's existing passkeys are excluded so one authenticator is not registered twice.
+    pub fn start_registration(
+        &self,
+        handle: Uuid,
+        email: &str,
+        name: &str,
+        existing: &[Passkey],
+    ) -> Result<(CreationChallengeResponse, PasskeyRegistration), PasskeyError> {
+        let exclude = existing
+            .iter()
+            .map(|passkey| passkey.cred_id().clone())
+            .collect();
+        let (mut options, state) =
+            self.webauthn
+                .start_passkey_registration(handle, email, name, Some(exclude))?;
+        // signing in does not ask who is signing in, the authenticator has to keep the credential
+        if let Some(selection) = options.public_key.authenticator_selection.as_mut() {
+            selection.resident_key = Some(ResidentKeyRequirement::Required);
+            selection.require_resident_key = true;
+        }
+        Ok((options, state))
+    }
+
+    pub fn finish_registration(
+        &self,
+        credential: &RegisterPublicKeyCredential,
+        state: &PasskeyRegistration,
+    ) -> Result<Passkey, PasskeyError> {
+        Ok(self
+            .webauthn
+            .finish_passkey_registration(credential, state)?)
+    }
+
+    pub fn start_login(
+        &self,
+    ) -> Result<(RequestChallengeResponse, DiscoverableAuthentication), PasskeyError> {
+        let (mut options, state) = self.webauthn.start_discoverable_authentication()?;
+        // asked for with a button, not offered in the autofill of the email field
+        options.mediation = None;
+        Ok((options, state))
+    }
+
+    /// The user handle and credential id the browser answered with, to look the passkey up by.
+    pub fn identify<'a>(
+        &self,
+        credential: &'a PublicKeyCredential,
+    ) -> Result<(Uuid, &'a [u8]), PasskeyError> {
+        Ok(self
+            .webauthn
+            .identify_discoverable_authentication(credential)?)
+    }
+
+    /// Checks the signature against the stored passkey. The signature counter in the result
+    /// goes back into the stored passkey.
+    pub fn finish_login(
+        &self,
+        credential: &PublicKeyCredential,
+        state: DiscoverableAuthentication,
+        passkey: &Passkey,
+    ) -> Result<AuthenticationResult, PasskeyError> {
+        Ok(self.webauthn.finish_discoverable_authentication(
+            credential,
+            state,
+            &[DiscoverableKey::from(passkey)],
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webauthn_authenticator_rs::{WebauthnAuthenticator, softpasskey::SoftPasskey};
+    use webauthn_rs_proto::{AllowCredentials, UserVerificationPolicy};
+
+    use super::*;
+
+    const BASE_URL: &str = "https://snippetbox.example";
+
+    // `SoftPasskey` keeps no credentials of its own: it can only sign for credentials it is
+    // told about, and does not hand back who they belong to. For discoverable logins the
+    // tests play that part, the signature they check is the software authenticator's.
+    struct Authenticator {
+        soft: WebauthnAuthenticator<SoftPasskey>,
+        origin: Url,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Self {
+                // the server asks for user verification, the software one pretends it did it
+                soft: WebauthnAuthenticator::new(SoftPasskey::new(true)),
+                origin: Url::parse(BASE_URL).unwrap(),
+            }
+        }
+
+        fn register(
+            &mut self,
+            mut options: CreationChallengeResponse,
+        ) -> RegisterPublicKeyCredential {
+            if let Some(selection) = options.public_key.authenticator_selection.as_mut() {
+                selection.resident_key = None;
+                selection.require_resident_key = false;
+            }
+            self.soft
+                .do_registration(self.origin.clone(), options)
+                .unwrap()
+        }
+
+        fn sign_in(
+            &mut self,
+            mut options: RequestChallengeResponse,
+            passkey: &Passkey,
+            handle: Uuid,
+        ) -> PublicKeyCredential {
+            options.public_key.allow_credentials = vec![AllowCredentials {
+                type_: "public-key".to_string(),
+                id: passkey.cred_id().clone().into(),
+                transports: None,
+            }];
+            let mut credential = self
+                .soft
+                .do_authentication(self.origin.clone(), options)
+                .unwrap();
+            credential.response.user_handle = Some(handle.as_bytes().to_vec().into());
+            credential
+        }
+    }
+
+    fn register(passkeys: &Passkeys, authenticator: &mut Authenticator, handle: Uuid) -> Passkey {
+        let (options, state) = passkeys
+            .start_registration(handle, "jane@example.com", "Jane", &[])
+            .unwrap();
+        let credential = authenticator.register(options);
+        passkeys.finish_registration(&credential, &state).unwrap()
+    }
+
+    #[test]
+    fn registration_asks_for_a_discoverable_credential() {
+        let passkeys = Passkeys::new(BASE_URL).unwrap();
+        let (options, _) = passkeys
+            .start_registration(Uuid::new_v4(), "jane@example.com", "Jane", &[])
+            .unwrap();
+        let selection = options.public_key.authenticator_selection.unwrap();
+        assert_eq!(
+            selection.resident_key,
+            Some(ResidentKeyRequirement::Required)
+        );
+        assert!(selection.require_resident_key);
+        assert_eq!(
+            selection.user_verification,
+            UserVerificationPolicy::Required
+        );
+        assert_eq!(options.public_key.rp.id, "snippetbox.example");
+    }
+
+    #[test]
+    fn registration_gives_a_passkey_and_excludes_it_next_time() {
+        let passkeys = Passkeys::new(BASE_URL).unwrap();
+        let mut authenticator = Authenticator::new();
+        let handle = Uuid::new_v4();
+        let passkey = register(&passkeys, &mut authenticator, handle);
+
+        let (options, _) = passkeys
+            .start_registration(
+                handle,
+                "jane@example.com",
+                "Jane",
+                std::slice::from_ref(&passkey),
+            )
+            .unwrap();
+        let excluded = options.public_key.exclude_credentials.unwrap();
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(&excluded[0].id, passkey.cred_id());
+    }
+
+    #[test]
+    fn discoverable_login_identifies_the_user_and_checks_the_signature() {
+        let passkeys = Passkeys::new(BASE_URL).unwrap();
+        let mut authenticator = Authenticator::new();
+        let handle = Uuid::new_v4();
+        let passkey = register(&passkeys, &mut authenticator, handle);
+
+        let (options, state) = passkeys.start_login().unwrap();
+        // nobody said who is signing in
+        assert!(options.public_key.allow_credentials.is_empty());
+        assert!(options.mediation.is_none());
+        let credential = authenticator.sign_in(options, &passkey, handle);
+
+        let (identified, credential_id) = passkeys.identify(&credential).unwrap();
+        assert_eq!(identified, handle);
+        assert_eq!(credential_id, passkey.cred_id().as_ref());
+        let result = passkeys.finish_login(&credential, state, &passkey).unwrap();
+        assert_eq!(result.cred_id(), passkey.cred_id());
+        assert!(result.user_verified());
+    }
+
+    #[test]
+    fn login_with_a_challenge_from_another_ceremony_is_rejected() {
+        let passkeys = Passkeys::new(BASE_URL).unwrap();
+        let mut authenticator = Authenticator::new();
+        let handle = Uuid::new_v4();
+        let passkey = register(&passkeys, &mut authenticator, handle);
+
+        let (options, _) = passkeys.start_login().unwrap();
+        let (_, other_state) = passkeys.start_login().unwrap();
+        let credential = authenticator.sign_in(options, &passkey, handle);
+        assert!(
+            passkeys
+                .finish_login(&credential, other_state, &passkey)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn login_is_checked_against_the_stored_passkey() {
+        let passkeys = Passkeys::new(BASE_URL).unwrap();
+        let mut authenticator = Authenticator::new();
+        let handle = Uuid::new_v4();
+        let passkey = register(&passkeys, &mut authenticator, handle);
+        let mut other_authenticator = Authenticator::new();
+        let other = register(&passkeys, &mut other_authenticator, handle);
+
+        let (options, state) = passkeys.start_login().unwrap();
+        let credential = authenticator.sign_in(options, &passkey, handle);
+        assert!(passkeys.finish_login(&credential, state, &other).is_err());
+    }
+}
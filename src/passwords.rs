@@ -0,0 +1,175 @@
+// Password hashing. New passwords are hashed with Argon2id, hashes from older schemes
+// (bcrypt) are still accepted and replaced by the current scheme on the next login.
+// Hashing is slow on purpose, so it runs on the blocking thread pool and a burst of
+// logins does not hold up the runtime's worker threads.
+use std::sync::Arc;
+
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, Version,
+    password_hash::{PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PasswordError {
+    #[error("invalid hashing parameters : {0}")]
+    Parameters(String),
+    #[error("password hashing failed : {0}")]
+    Hashing(String),
+    #[error("stored password hash has an unknown format")]
+    UnknownFormat,
+    #[error("hashing task failed : {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+pub trait PasswordScheme: Send + Sync {
+    /// Whether the stored hash was made by this scheme.
+    fn recognises(&self, hash: &str) -> bool;
+    fn hash(&self, password: &str) -> Result<String, PasswordError>;
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordError>;
+    /// Whether a hash of this scheme was made with other settings than the current ones.
+    fn outdated(&self, _hash: &str) -> bool {
+        false
+    }
+}
+
+pub struct Argon2id {
+    params: Params,
+}
+
+impl Argon2id {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, PasswordError> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|e| PasswordError::Parameters(e.to_string()))?;
+        Ok(Self { params })
+    }
+
+    fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl PasswordScheme for Argon2id {
+    fn recognises(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2id$")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.hasher()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| PasswordError::Hashing(e.to_string()))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordError> {
+        let parsed = PasswordHash::new(hash).map_err(|e| PasswordError::Hashing(e.to_string()))?;
+        // the settings are read from the hash, not taken from `self.params`
+        match self.hasher().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(PasswordError::Hashing(e.to_string())),
+        }
+    }
+
+    fn outdated(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+/// The scheme every password was hashed with before Argon2id, only used for verifying.
+pub struct Bcrypt {
+    pub cost: u32,
+}
+
+impl PasswordScheme for Bcrypt {
+    fn recognises(&self, hash: &str) -> bool {
+        ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+    }
+
+    fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        bcrypt::hash(password, self.cost).map_err(|e| PasswordError::Hashing(e.to_string()))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordError> {
+        bcrypt::verify(password, hash).map_err(|e| PasswordError::Hashing(e.to_string()))
+    }
+}
+
+pub struct Verification {
+    pub matches: bool,
+    // the password was right, but its hash should be replaced with one of the current scheme
+    pub needs_rehash: bool,
+}
+
+#[derive(Clone)]
+pub struct Passwords {
+    current: Arc<dyn PasswordScheme>,
+    legacy: Arc<Vec<Box<dyn PasswordScheme>>>,
+    // verified against when the email is unknown, so that takes as long as a wrong password
+    dummy_hash: Arc<String>,
+}
+
+impl Passwords {
+    pub fn new(
+        current: Arc<dyn PasswordScheme>,
+        legacy: Vec<Box<dyn PasswordScheme>>,
+    ) -> Result<Self, PasswordError> {
+        let dummy_hash = current.hash("not a real password")?;
+        Ok(Self {
+            current,
+            legacy: Arc::new(legacy),
+            dummy_hash: Arc::new(dummy_hash),
+        })
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let current = self.current.clone();
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || current.hash(&password)).await?
+    }
+
+    pub async fn verify(&self, password: &str, hash: &str) -> Result<Verification, PasswordError> {
+        let this = self.clone();
+        let password = password.to_string();
+        let hash = hash.to_string();
+        tokio::task::spawn_blocking(move || {
+            if this.current.recognises(&hash) {
+                let matches = this.current.verify(&password, &hash)?;
+                let needs_rehash = matches && this.current.outdated(&hash);
+                return Ok(Verification {
+                    matches,
+                    needs_rehash,
+                });
+            }
+            let scheme = this
+                .legacy
+                .iter()
+                .find(|scheme| scheme.recognises(&hash))
+                .ok_or(PasswordError::UnknownFormat)?;
+            let matches = scheme.verify(&password, &hash)?;
+            Ok(Verification {
+                matches,
+                needs_rehash: matches,
+            })
+        })
+        .await?
+    }
+
+    /// Spends the time of a real verification without any hash to check against.
+    pub async fn verify_dummy(&self, password: &str) {
+        let _ = self.verify(password, &self.dummy_hash.clone()).await;
+    }
+}
@@ -0,0 +1,348 @@
+// Proof-of-work instead of a CAPTCHA. The server hands out a signed challenge, `pow.js` looks
+// for a number that makes the SHA-256 of challenge and number start with enough zero bits, and
+// the form is only accepted with such a number. Costs a browser a second, a bot farm a lot more
+// as the difficulty rises with what the address has been up to lately.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::utils::token;
+
+// how long a browser has to solve a challenge and submit the form
+const CHALLENGE_SECONDS: i64 = 10 * 60;
+// every extra bit doubles the work, past this many it takes people too long
+const MAX_EXTRA_BITS: u32 = 6;
+
+#[derive(Debug, Error)]
+pub enum PowError {
+    #[error("no proof of work submitted")]
+    Missing,
+    #[error("malformed challenge")]
+    Malformed,
+    #[error("challenge for another form or address")]
+    Signature,
+    #[error("challenge expired")]
+    Expired,
+    #[error("solution does not meet the difficulty")]
+    Unsolved,
+    #[error("challenge used before")]
+    Reused,
+}
+
+/// The forms that can ask for proof of work.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PowForm {
+    Signup,
+    Login,
+}
+
+impl PowForm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PowForm::Signup => "signup",
+            PowForm::Login => "login",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "signup" => Some(PowForm::Signup),
+            "login" => Some(PowForm::Login),
+            _ => None,
+        }
+    }
+}
+
+/// What `pow.js` gets to work on.
+#[derive(Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+}
+
+#[derive(Clone)]
+pub struct ProofOfWork {
+    key: Arc<Vec<u8>>,
+    difficulty: u32,
+    login_after: Option<i32>,
+    // challenges that have been accepted, until they expire. Only this instance knows about
+    // them, behind a load balancer a solution can be used once per instance.
+    used: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl ProofOfWork {
+    /// `key` signs the challenges, instances behind a load balancer need the same one.
+    /// Without it a random key is used and challenges do not survive a restart.
+    /// `difficulty` is the number of leading zero bits asked of clients without recent activity.
+    /// Logins only need proof of work after `login_after` failed attempts, never without it.
+    pub fn new(key: Option<&str>, difficulty: u32, login_after: Option<i32>) -> Self {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => token::generate().into_bytes(),
+        };
+        Self {
+            key: Arc::new(key),
+            difficulty,
+            login_after,
+            used: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether logging in takes proof of work after this many failed attempts.
+    pub fn login_required(&self, failures: i32) -> bool {
+        self.login_after.is_some_and(|after| failures >= after)
+    }
+
+    /// A challenge for the form, bound to the client's address. `activity` is how many signups
+    /// or failed logins came from the address lately, every doubling of it adds a bit.
+    pub fn issue(&self, form: PowForm, ip: Option<&str>, activity: i64) -> Challenge {
+        let extra = (activity.max(0) as u64 + 1).ilog2().min(MAX_EXTRA_BITS);
+        let difficulty = self.difficulty + extra;
+        let expires = Utc::now().timestamp() + CHALLENGE_SECONDS;
+        let payload = format!(
+            "{}.{}.{}.{}",
+            form.as_str(),
+            expires,
+            difficulty,
+            token::generate()
+        );
+        let signature = self.sign(&payload, ip).finalize().into_bytes();
+        Challenge {
+            challenge: format!("{}.{}", payload, hex(&signature)),
+            difficulty,
+        }
+    }
+
+    /// Accepts a solved challenge of the form once, from the address it was issued to.
+    pub fn verify(
+        &self,
+        form: PowForm,
+        ip: Option<&str>,
+        challenge: &str,
+        solution: &str,
+    ) -> Result<(), PowError> {
+        if challenge.is_empty() || solution.is_empty() {
+            return Err(PowError::Missing);
+        }
+        let (payload, signature) = challenge.rsplit_once('.').ok_or(PowError::Malformed)?;
+        let fields: Vec<&str> = payload.split('.').collect();
+        let [challenge_form, expires, difficulty, _nonce] = fields[..] else {
+            return Err(PowError::Malformed);
+        };
+        let expires: i64 = expires.parse().map_err(|_| PowError::Malformed)?;
+        let difficulty: u32 = difficulty.parse().map_err(|_| PowError::Malformed)?;
+        let signature = unhex(signature).ok_or(PowError::Malformed)?;
+        if challenge_form != form.as_str()
+            || self.sign(payload, ip).verify_slice(&signature).is_err()
+        {
+            return Err(PowError::Signature);
+        }
+        let now = Utc::now().timestamp();
+        if expires < now {
+            return Err(PowError::Expired);
+        }
+        if solution.len() > 20 || !solution.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(PowError::Malformed);
+        }
+        let hash = Sha256::digest(format!("{}:{}", challenge, solution).as_bytes());
+        if leading_zero_bits(&hash) < difficulty {
+            return Err(PowError::Unsolved);
+        }
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, expires| *expires >= now);
+        if used.insert(payload.to_string(), expires).is_some() {
+            return Err(PowError::Reused);
+        }
+        Ok(())
+    }
+
+    fn sign(&self, payload: &str, ip: Option<&str>) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac takes any key length");
+        mac.update(payload.as_bytes());
+        mac.update(b"|");
+        mac.update(ip.unwrap_or_default().as_bytes());
+        mac
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Option<&str> = Some("192.0.2.1");
+
+    // what `pow.js` does
+    fn solve(challenge: &Challenge) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| {
+                let hash = Sha256::digest(format!("{}:{}", challenge.challenge, n).as_bytes());
+                leading_zero_bits(&hash) >= challenge.difficulty
+            })
+            .unwrap()
+    }
+
+    // a challenge as `issue` would have made it, but expiring at `expires`
+    fn challenge_expiring(pow: &ProofOfWork, expires: i64) -> Challenge {
+        let payload = format!("signup.{}.{}.nonce", expires, pow.difficulty);
+        let signature = pow.sign(&payload, IP).finalize().into_bytes();
+        Challenge {
+            challenge: format!("{}.{}", payload, hex(&signature)),
+            difficulty: pow.difficulty,
+        }
+    }
+
+    #[test]
+    fn a_solved_challenge_is_accepted_once() {
+        let pow = ProofOfWork::new(Some("key"), 4, None);
+        let challenge = pow.issue(PowForm::Signup, IP, 0);
+        let solution = solve(&challenge);
+        assert!(
+            pow.verify(PowForm::Signup, IP, &challenge.challenge, &solution)
+                .is_ok()
+        );
+        assert!(matches!(
+            pow.verify(PowForm::Signup, IP, &challenge.challenge, &solution),
+            Err(PowError::Reused)
+        ));
+    }
+
+    #[test]
+    fn unsolved_challenges_are_refused() {
+        let pow = ProofOfWork::new(Some("key"), 8, None);
+        let challenge = pow.issue(PowForm::Signup, IP, 0);
+        // the first number that does not solve it
+        let wrong = (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| {
+                let hash = Sha256::digest(format!("{}:{}", challenge.challenge, n).as_bytes());
+                leading_zero_bits(&hash) < challenge.difficulty
+            })
+            .unwrap();
+        assert!(matches!(
+            pow.verify(PowForm::Signup, IP, &challenge.challenge, &wrong),
+            Err(PowError::Unsolved)
+        ));
+        assert!(matches!(
+            pow.verify(PowForm::Signup, IP, &challenge.challenge, ""),
+            Err(PowError::Missing)
+        ));
+        assert!(matches!(
+            pow.verify(PowForm::Signup, IP, &challenge.challenge, "-1"),
+            Err(PowError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn challenges_only_count_for_their_address_and_form() {
+        let pow = ProofOfWork::new(Some("key"), 4, None);
+        let challenge = pow.issue(PowForm::Signup, IP, 0);
+        let solution = solve(&challenge);
+        for (form, ip) in [
+            (PowForm::Signup, Some("192.0.2.2")),
+            (PowForm::Signup, None),
+            (PowForm::Login, IP),
+        ] {
+            assert!(matches!(
+                pow.verify(form, ip, &challenge.challenge, &solution),
+                Err(PowError::Signature)
+            ));
+        }
+        // nor for another key, an instance that did not issue them
+        let other = ProofOfWork::new(Some("other key"), 4, None);
+        assert!(matches!(
+            other.verify(PowForm::Signup, IP, &challenge.challenge, &solution),
+            Err(PowError::Signature)
+        ));
+        // the difficulty is signed too, it cannot be lowered
+        let easier = challenge.challenge.replacen(".4.", ".0.", 1);
+        assert!(matches!(
+            pow.verify(PowForm::Signup, IP, &easier, &solution),
+            Err(PowError::Signature)
+        ));
+    }
+
+    #[test]
+    fn expired_challenges_are_refused() {
+        let pow = ProofOfWork::new(Some("key"), 4, None);
+        let challenge = challenge_expiring(&pow, Utc::now().timestamp() - 1);
+        let solution = solve(&challenge);
+        assert!(matches!(
+            pow.verify(PowForm::Signup, IP, &challenge.challenge, &solution),
+            Err(PowError::Expired)
+        ));
+        let challenge = challenge_expiring(&pow, Utc::now().timestamp() + 60);
+        let solution = solve(&challenge);
+        assert!(
+            pow.verify(PowForm::Signup, IP, &challenge.challenge, &solution)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn malformed_challenges_are_refused() {
+        let pow = ProofOfWork::new(Some("key"), 4, None);
+        for challenge in ["nonsense", "signup.1.4.nonce.zz", "signup.x.4.nonce.00"] {
+            assert!(matches!(
+                pow.verify(PowForm::Signup, IP, challenge, "1"),
+                Err(PowError::Malformed)
+            ));
+        }
+    }
+
+    #[test]
+    fn activity_makes_challenges_harder() {
+        let pow = ProofOfWork::new(None, 10, Some(3));
+        assert_eq!(pow.issue(PowForm::Signup, IP, 0).difficulty, 10);
+        assert_eq!(pow.issue(PowForm::Signup, IP, 1).difficulty, 11);
+        assert_eq!(pow.issue(PowForm::Signup, IP, 7).difficulty, 13);
+        assert_eq!(
+            pow.issue(PowForm::Signup, IP, 1_000_000).difficulty,
+            10 + MAX_EXTRA_BITS
+        );
+        assert!(!pow.login_required(2));
+        assert!(pow.login_required(3));
+        assert!(!ProofOfWork::new(None, 10, None).login_required(100));
+    }
+
+    #[test]
+    fn zero_bits_are_counted_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+        assert_eq!(unhex(&hex(&[0, 1, 254])), Some(vec![0, 1, 254]));
+        assert_eq!(unhex("abc"), None);
+    }
+}
@@ -0,0 +1,193 @@
+// Link preview images (Open Graph / Twitter cards) for snippets: the title and the first
+// lines of code drawn with an 8x8 bitmap font and encoded as PNG.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use font8x8::{BASIC_FONTS, LATIN_FONTS, UnicodeFonts};
+
+// the size recommended for Open Graph images
+pub const WIDTH: usize = 1200;
+pub const HEIGHT: usize = 630;
+const MARGIN: usize = 60;
+const TITLE_SCALE: usize = 5;
+const CODE_SCALE: usize = 3;
+const FOOTER_SCALE: usize = 2;
+const MAX_CODE_LINES: usize = 10;
+const CACHE_CAPACITY: usize = 256;
+
+type Rgb = [u8; 3];
+const BACKGROUND: Rgb = [0xF1, 0xF3, 0xF6];
+const CARD: Rgb = [0xFF, 0xFF, 0xFF];
+const BORDER: Rgb = [0xE4, 0xE5, 0xE7];
+const TITLE: Rgb = [0x34, 0x49, 0x5E];
+const CODE: Rgb = [0x6A, 0x6C, 0x6F];
+// the same colours as the stripes in the site header
+const STRIPES: [Rgb; 8] = [
+    [0x34, 0x49, 0x5E],
+    [0x9B, 0x59, 0xB6],
+    [0x34, 0x98, 0xDB],
+    [0x62, 0xCB, 0x31],
+    [0xFF, 0xB6, 0x06],
+    [0xE6, 0x7E, 0x22],
+    [0xE7, 0x4C, 0x3C],
+    [0xC0, 0x39, 0x2B],
+];
+
+struct Canvas {
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        let mut canvas = Self {
+            pixels: vec![0; WIDTH * HEIGHT * 3],
+        };
+        canvas.fill(0, 0, WIDTH, HEIGHT, BACKGROUND);
+        canvas
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, colour: Rgb) {
+        for row in y..(y + height).min(HEIGHT) {
+            for column in x..(x + width).min(WIDTH) {
+                let offset = (row * WIDTH + column) * 3;
+                self.pixels[offset..offset + 3].copy_from_slice(&colour);
+            }
+        }
+    }
+
+    fn text(&mut self, x: usize, y: usize, text: &str, scale: usize, colour: Rgb) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph = BASIC_FONTS
+                .get(c)
+                .or_else(|| LATIN_FONTS.get(c))
+                .or_else(|| BASIC_FONTS.get('?'))
+                .unwrap_or_default();
+            let left = x + i * 8 * scale;
+            for (row, bits) in glyph.iter().enumerate() {
+                for column in 0..8 {
+                    // bit 0 is the leftmost pixel
+                    if bits & (1 << column) != 0 {
+                        self.fill(left + column * scale, y + row * scale, scale, scale, colour);
+                    }
+                }
+            }
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, WIDTH as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+}
+
+// how many glyphs of the given scale fit between the margins
+fn columns(scale: usize) -> usize {
+    (WIDTH - 2 * MARGIN) / (8 * scale)
+}
+
+fn truncate(line: &str, max: usize) -> String {
+    // tabs would render as '?'
+    let line = line.replace('\t', "    ");
+    if line.chars().count() <= max {
+        return line;
+    }
+    let mut truncated: String = line.chars().take(max.saturating_sub(3)).collect();
+    truncated.push_str("...");
+    truncated
+}
+
+/// Draws the preview card. CPU bound, so call it from a blocking task.
+pub fn render(id: i32, title: &str, content: &str) -> Result<Vec<u8>, png::EncodingError> {
+    let mut canvas = Canvas::new();
+    let stripe_width = WIDTH / STRIPES.len();
+    for (i, colour) in STRIPES.iter().enumerate() {
+        canvas.fill(i * stripe_width, 0, stripe_width + 1, 12, *colour);
+    }
+
+    let title_height = 8 * TITLE_SCALE;
+    canvas.text(
+        MARGIN,
+        MARGIN,
+        &truncate(title, columns(TITLE_SCALE)),
+        TITLE_SCALE,
+        TITLE,
+    );
+
+    let card_top = MARGIN + title_height + 30;
+    let card_height = HEIGHT - card_top - MARGIN - 30;
+    canvas.fill(
+        MARGIN - 2,
+        card_top - 2,
+        WIDTH - 2 * MARGIN + 4,
+        card_height + 4,
+        BORDER,
+    );
+    canvas.fill(MARGIN, card_top, WIDTH - 2 * MARGIN, card_height, CARD);
+    let line_height = 8 * CODE_SCALE + 8;
+    let max_lines = MAX_CODE_LINES.min((card_height - 20) / line_height);
+    for (i, line) in content.lines().take(max_lines).enumerate() {
+        canvas.text(
+            MARGIN + 16,
+            card_top + 16 + i * line_height,
+            &truncate(line, columns(CODE_SCALE) - 1),
+            CODE_SCALE,
+            CODE,
+        );
+    }
+
+    let footer = format!("Snippetbox #{}", id);
+    canvas.text(MARGIN, HEIGHT - MARGIN + 4, &footer, FOOTER_SCALE, CODE);
+    canvas.encode()
+}
+
+/// Rendered previews keyed by snippet id and revision, the oldest entries are dropped first.
+#[derive(Clone)]
+pub struct PreviewCache {
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+struct CacheInner {
+    images: HashMap<(i32, i32), Bytes>,
+    order: VecDeque<(i32, i32)>,
+}
+
+impl PreviewCache {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(CacheInner {
+                images: HashMap::new(),
+                order: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn get(&self, id: i32, revision: i32) -> Option<Bytes> {
+        self.inner
+            .lock()
+            .unwrap()
+            .images
+            .get(&(id, revision))
+            .cloned()
+    }
+
+    pub fn insert(&self, id: i32, revision: i32, image: Bytes) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.images.insert((id, revision), image).is_none() {
+            inner.order.push_back((id, revision));
+        }
+        while inner.order.len() > CACHE_CAPACITY {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.images.remove(&oldest);
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+// Token buckets limiting how fast one client can make requests. Every route group has its
+// own budget, clients are told apart by user id once logged in and by address before that.
+// Buckets live in memory unless instances behind a load balancer need to share them, then
+// they are kept in MySQL.
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+
+use crate::models::rate_limits::RateLimitModel;
+
+/// The routes that share a budget.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RouteGroup {
+    // creating and reporting snippets
+    Create,
+    // logging in, signing up and resetting passwords
+    Auth,
+    // feeds, oEmbed, previews and the event stream, mostly fetched by programs
+    Api,
+    // everything else
+    Read,
+}
+
+impl RouteGroup {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RouteGroup::Create => "create",
+            RouteGroup::Auth => "auth",
+            RouteGroup::Api => "api",
+            RouteGroup::Read => "read",
+        }
+    }
+
+    /// The group of a route, by the path it was registered under.
+    pub fn of(method: &axum::http::Method, route: &str) -> Self {
+        let post = method == axum::http::Method::POST;
+        if post && (route == "/snippet/create" || route.starts_with("/snippet/report/")) {
+            return RouteGroup::Create;
+        }
+        if route.starts_with("/user/login")
+            || route.starts_with("/user/signup")
+            || route.starts_with("/user/forgot-password")
+            || route.starts_with("/user/reset-password")
+        {
+            return RouteGroup::Auth;
+        }
+        if route == "/oembed"
+            || route.ends_with("/feed.atom")
+            || route.ends_with("/feed.rss")
+            || route == "/snippet/events"
+            || route.starts_with("/snippet/preview/")
+            || route.starts_with("/snippet/embed/")
+        {
+            return RouteGroup::Api;
+        }
+        RouteGroup::Read
+    }
+}
+
+/// How many requests a client may make in a burst, and how fast that allowance comes back.
+/// Written like "10/min", for 10 requests at once and one more every six seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    capacity: f64,
+    per_second: f64,
+}
+
+impl FromStr for Budget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a budget like 10/min", value);
+        let (requests, period) = value.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds = match period.trim() {
+            "s" | "sec" | "second" => 1.0,
+            "m" | "min" | "minute" => 60.0,
+            "h" | "hour" => 60.0 * 60.0,
+            _ => return Err(invalid()),
+        };
+        if requests == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            capacity: requests as f64,
+            per_second: requests as f64 / seconds,
+        })
+    }
+}
+
+impl Budget {
+    /// Takes a token from a bucket that held `tokens` `elapsed` seconds ago. Returns what is
+    /// left, or how many seconds until there is a token again.
+    pub fn take(&self, tokens: f64, elapsed: f64) -> Result<f64, u64> {
+        let tokens = (tokens + elapsed.max(0.0) * self.per_second).min(self.capacity);
+        if tokens >= 1.0 {
+            Ok(tokens - 1.0)
+        } else {
+            Err(((1.0 - tokens) / self.per_second).ceil().max(1.0) as u64)
+        }
+    }
+
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.per_second
+    }
+
+    /// Whether a bucket that held `tokens` `elapsed` seconds ago has filled up again. A full
+    /// bucket is the same as none, it can be dropped.
+    pub fn is_full(&self, tokens: f64, elapsed: f64) -> bool {
+        tokens + elapsed.max(0.0) * self.per_second >= self.capacity
+    }
+}
+
+pub trait BucketStore: Send + Sync {
+    /// Takes a token from the bucket under `key`, or says how many seconds to wait.
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        budget: Budget,
+    ) -> BoxFuture<'a, Result<Result<(), u64>, sqlx::Error>>;
+
+    /// Drops the buckets of `group` that have filled up again.
+    fn prune<'a>(
+        &'a self,
+        group: RouteGroup,
+        budget: Budget,
+    ) -> BoxFuture<'a, Result<u64, sqlx::Error>>;
+}
+
+/// Buckets of this instance only.
+#[derive(Default)]
+pub struct MemoryBuckets {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl BucketStore for MemoryBuckets {
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        budget: Budget,
+    ) -> BoxFuture<'a, Result<Result<(), u64>, sqlx::Error>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().unwrap();
+            let (tokens, elapsed) = match buckets.get(key) {
+                Some((tokens, updated)) => (*tokens, now.duration_since(*updated).as_secs_f64()),
+                None => (budget.capacity(), 0.0),
+            };
+            match budget.take(tokens, elapsed) {
+                Ok(left) => {
+                    buckets.insert(key.to_string(), (left, now));
+                    Ok(Ok(()))
+                }
+                Err(retry_after) => Ok(Err(retry_after)),
+            }
+        })
+    }
+
+    fn prune<'a>(
+        &'a self,
+        group: RouteGroup,
+        budget: Budget,
+    ) -> BoxFuture<'a, Result<u64, sqlx::Error>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let prefix = format!("{}:", group.as_str());
+            let mut buckets = self.buckets.lock().unwrap();
+            let before = buckets.len();
+            buckets.retain(|key, (tokens, updated)| {
+                !key.starts_with(&prefix)
+                    || !budget.is_full(*tokens, now.duration_since(*updated).as_secs_f64())
+            });
+            Ok((before - buckets.len()) as u64)
+        })
+    }
+}
+
+impl BucketStore for RateLimitModel {
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        budget: Budget,
+    ) -> BoxFuture<'a, Result<Result<(), u64>, sqlx::Error>> {
+        Box::pin(RateLimitModel::take(self, key, budget))
+    }
+
+    fn prune<'a>(
+        &'a self,
+        group: RouteGroup,
+        budget: Budget,
+    ) -> BoxFuture<'a, Result<u64, sqlx::Error>> {
+        Box::pin(RateLimitModel::prune(self, group.as_str(), budget))
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn BucketStore>,
+    budgets: Arc<HashMap<RouteGroup, Budget>>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn BucketStore>, budgets: HashMap<RouteGroup, Budget>) -> Self {
+        Self {
+            store,
+            budgets: Arc::new(budgets),
+        }
+    }
+
+    /// Counts a request of the client against the group's budget. Returns how many seconds
+    /// the client has to wait when it is used up. When the store fails the request is let
+    /// through, a broken limiter should not take the site down.
+    pub async fn check(&self, group: RouteGroup, client: &str) -> Result<(), u64> {
+        let Some(budget) = self.budgets.get(&group) else {
+            return Ok(());
+        };
+        let key = format!("{}:{}", group.as_str(), client);
+        match self.store.take(&key, *budget).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                tracing::error!("rate limit for {} could not be checked : {}", key, e);
+                Ok(())
+            }
+        }
+    }
+    /// Drops the buckets that have filled up again once every `period`, for ever. Without it
+    /// every client ever seen keeps a bucket.
+    pub async fn continuously_prune(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            for (group, budget) in self.budgets.iter() {
+                if let Err(e) = self.store.prune(*group, *budget).await {
+                    tracing::error!(
+                        "full {} rate limit buckets could not be dropped : {}",
+                        group.as_str(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(value: &str) -> Budget {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn budgets_are_parsed() {
+        let per_minute = budget("10/min");
+        assert_eq!(per_minute.capacity(), 10.0);
+        assert_eq!(per_minute.per_second(), 10.0 / 60.0);
+        assert_eq!(budget(" 5 / s ").per_second(), 5.0);
+        assert_eq!(budget("3600/hour").per_second(), 1.0);
+        for invalid in ["", "10", "0/min", "-1/min", "ten/min", "10/day", "10/"] {
+            assert!(invalid.parse::<Budget>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn buckets_refill_up_to_their_capacity() {
+        let budget = budget("2/min");
+        // a burst of two, then the third has to wait for a token to come back
+        let tokens = budget.take(budget.capacity(), 0.0).unwrap();
+        let tokens = budget.take(tokens, 0.0).unwrap();
+        assert_eq!(tokens, 0.0);
+        assert_eq!(budget.take(tokens, 0.0), Err(30));
+        assert_eq!(budget.take(tokens, 15.0), Err(15));
+        assert_eq!(budget.take(tokens, 30.0), Ok(0.0));
+        // however long the client was away, it gets no more than a full bucket
+        assert_eq!(budget.take(tokens, 3600.0), Ok(1.0));
+        assert!(!budget.is_full(tokens, 59.0));
+        assert!(budget.is_full(tokens, 60.0));
+    }
+
+    #[test]
+    fn retry_after_is_at_least_a_second() {
+        let budget = budget("100/s");
+        assert_eq!(budget.take(0.5, 0.0), Err(1));
+        // a clock going backwards does not add tokens
+        assert_eq!(budget.take(0.0, -10.0), Err(1));
+    }
+
+    #[tokio::test]
+    async fn clients_and_groups_have_their_own_buckets() {
+        let limiter = RateLimiter::new(
+            Arc::new(MemoryBuckets::default()),
+            HashMap::from([(RouteGroup::Auth, budget("1/min"))]),
+        );
+        assert_eq!(limiter.check(RouteGroup::Auth, "ip:10.0.0.1").await, Ok(()));
+        assert_eq!(
+            limiter.check(RouteGroup::Auth, "ip:10.0.0.1").await,
+            Err(60)
+        );
+        assert_eq!(limiter.check(RouteGroup::Auth, "ip:10.0.0.2").await, Ok(()));
+        // groups without a budget are not limited
+        for _ in 0..10 {
+            assert_eq!(limiter.check(RouteGroup::Read, "ip:10.0.0.1").await, Ok(()));
+        }
+    }
+
+    #[tokio::test]
+    async fn only_full_buckets_are_pruned() {
+        let buckets = MemoryBuckets::default();
+        let budget = budget("1/hour");
+        buckets.take("auth:used", budget).await.unwrap().unwrap();
+        buckets
+            .buckets
+            .lock()
+            .unwrap()
+            .insert("auth:full".to_string(), (1.0, Instant::now()));
+        buckets
+            .buckets
+            .lock()
+            .unwrap()
+            .insert("read:full".to_string(), (1.0, Instant::now()));
+        assert_eq!(buckets.prune(RouteGroup::Auth, budget).await.unwrap(), 1);
+        let buckets = buckets.buckets.lock().unwrap();
+        assert!(buckets.contains_key("auth:used"));
+        assert!(buckets.contains_key("read:full"));
+    }
+
+    #[test]
+    fn routes_are_grouped() {
+        use axum::http::Method;
+        assert_eq!(
+            RouteGroup::of(&Method::POST, "/snippet/create"),
+            RouteGroup::Create
+        );
+        assert_eq!(
+            RouteGroup::of(&Method::GET, "/snippet/create"),
+            RouteGroup::Read
+        );
+        assert_eq!(
+            RouteGroup::of(&Method::POST, "/user/login"),
+            RouteGroup::Auth
+        );
+        assert_eq!(
+            RouteGroup::of(&Method::GET, "/tag/{tag}/feed.atom"),
+            RouteGroup::Api
+        );
+    }
+}
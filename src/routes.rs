@@ -1,16 +1,40 @@
 use crate::handlers::{
-    hn, user_login, user_login_post, user_logout_post, user_signup, user_signup_post,
+    account, account_delete_post, account_email_post, account_name_post,
+    account_passkey_delete_post, account_passkey_register_finish, account_passkey_register_start,
+    account_password_post, account_session_revoke_post, account_sessions,
+    account_sessions_revoke_others_post, admin, admin_lockout_unlock_post,
+    admin_snippet_delete_post, admin_snippet_restore_post, admin_snippets, admin_user_disable_post,
+    admin_user_enable_post, admin_user_reset_password_post, admin_users, feed_atom, feed_rss, hn,
+    moderation, moderation_delete_post, moderation_dismiss_post, moderation_hide_post,
+    snippet_report_post, tag_feed_atom, tag_feed_rss, user_challenge, user_feed_atom,
+    user_feed_rss, user_forgot_password, user_forgot_password_post, user_login, user_login_oidc,
+    user_login_oidc_callback, user_login_passkey_finish, user_login_passkey_start, user_login_post,
+    user_logout_post, user_reset_password, user_reset_password_post, user_signup, user_signup_post,
+    user_verify_email, user_verify_email_resend,
+};
+use crate::middleware::{
+    authenticate, common_headers, csrf_protect, rate_limit, request_ip, require_auth, require_role,
+    require_verified,
 };
-use crate::middleware::{authenticate, common_headers, request_ip, require_auth};
 use crate::{
     AppState,
-    handlers::{home, snippet_create, snippet_create_post, snippet_view},
+    client_ip::ClientIp,
+    handlers::{
+        CSP_REPORT_LIMIT, csp_report, home, oembed, snippet_collaborator_add_post,
+        snippet_collaborator_remove_post, snippet_create, snippet_create_post, snippet_embed,
+        snippet_events, snippet_live, snippet_live_ws, snippet_preview, snippet_view,
+        two_factor_confirm, two_factor_confirm_post, two_factor_disable_post,
+        two_factor_enrol_post, two_factor_settings, user_login_two_factor,
+        user_login_two_factor_post,
+    },
+    models::users::Role,
+    security_headers::CSP_REPORT_PATH,
 };
-use axum::middleware::from_fn;
 use axum::{
-    Router,
-    extract::MatchedPath,
+    Extension, Router,
+    extract::{DefaultBodyLimit, MatchedPath},
     http::Request,
+    response::Redirect,
     routing::{get, post},
 };
 use std::sync::Arc;
@@ -42,27 +66,168 @@ impl AppRouter {
         // https://github.com/tokio-rs/axum/blob/8762520da82cd99b78b35869069b36cfa305d4b9/axum/src/docs/middleware.md?plain=1#L58
         // let option_timeout = Some(std::time::Duration::new(10, 0));
         // let timeout_layer = option_timeout.map(TimeoutLayer::new);
+        // accounts with passwords only exist where password logins are allowed
+        let password_routes = if shared_state.login_options.password {
+            Router::new()
+                .route("/user/signup", get(user_signup))
+                .route("/user/signup", post(user_signup_post))
+                .route("/user/login", post(user_login_post))
+                .route("/user/challenge/{form}", get(user_challenge))
+                .route("/user/forgot-password", get(user_forgot_password))
+                .route("/user/forgot-password", post(user_forgot_password_post))
+                .route("/user/reset-password", get(user_reset_password))
+                .route("/user/reset-password", post(user_reset_password_post))
+        } else {
+            Router::new().route(
+                "/user/signup",
+                get(|| async { Redirect::to("/user/login") }),
+            )
+        };
+        let sso_routes = if shared_state.oidc.is_some() {
+            Router::new()
+                .route("/user/login/oidc", get(user_login_oidc))
+                .route("/user/login/oidc/callback", get(user_login_oidc_callback))
+        } else {
+            Router::new()
+        };
+
+        let admin_routes = Router::new()
+            .route("/admin", get(admin))
+            .route("/admin/users", get(admin_users))
+            .route("/admin/users/{id}/disable", post(admin_user_disable_post))
+            .route("/admin/users/{id}/enable", post(admin_user_enable_post))
+            .route(
+                "/admin/users/{id}/reset-password",
+                post(admin_user_reset_password_post),
+            )
+            .route("/admin/lockouts/unlock", post(admin_lockout_unlock_post))
+            .route("/admin/snippets", get(admin_snippets))
+            .route(
+                "/admin/snippets/{id}/delete",
+                post(admin_snippet_delete_post),
+            )
+            .route(
+                "/admin/snippets/{id}/restore",
+                post(admin_snippet_restore_post),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                Role::Admin,
+                require_role,
+            ));
+
+        let moderation_routes = Router::new()
+            .route("/moderation", get(moderation))
+            .route(
+                "/moderation/reports/{id}/dismiss",
+                post(moderation_dismiss_post),
+            )
+            .route("/moderation/reports/{id}/hide", post(moderation_hide_post))
+            .route(
+                "/moderation/reports/{id}/delete",
+                post(moderation_delete_post),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                Role::Moderator,
+                require_role,
+            ));
+
         let timeout = std::time::Duration::new(10, 0);
         let tl = TimeoutLayer::new(timeout);
         let router = Router::new()
             .route_with_tsr("/snippet/create", get(snippet_create))
             .route("/snippet/create", post(snippet_create_post))
+            .route_layer(axum::middleware::from_fn(require_verified)) // only the create routes above
             .route("/user/logout", post(user_logout_post))
+            .route("/user/verify-email/resend", post(user_verify_email_resend))
+            .route("/account", get(account))
+            .route("/account/name", post(account_name_post))
+            .route("/account/email", post(account_email_post))
+            .route("/account/password", post(account_password_post))
+            .route("/account/delete", post(account_delete_post))
+            .route(
+                "/account/passkeys/register/start",
+                post(account_passkey_register_start),
+            )
+            .route(
+                "/account/passkeys/register/finish",
+                post(account_passkey_register_finish),
+            )
+            .route(
+                "/account/passkeys/delete",
+                post(account_passkey_delete_post),
+            )
+            .route("/account/sessions", get(account_sessions))
+            .route(
+                "/account/sessions/revoke",
+                post(account_session_revoke_post),
+            )
+            .route(
+                "/account/sessions/revoke-others",
+                post(account_sessions_revoke_others_post),
+            )
+            .route("/account/two-factor", get(two_factor_settings))
+            .route("/account/two-factor/enrol", post(two_factor_enrol_post))
+            .route("/account/two-factor/confirm", get(two_factor_confirm))
+            .route("/account/two-factor/confirm", post(two_factor_confirm_post))
+            .route("/account/two-factor/disable", post(two_factor_disable_post))
+            .route("/snippet/live/{id}", get(snippet_live))
+            .route("/snippet/live/{id}/ws", get(snippet_live_ws))
+            .route(
+                "/snippet/collaborators/{id}",
+                post(snippet_collaborator_add_post),
+            )
+            .route(
+                "/snippet/collaborators/{id}/remove",
+                post(snippet_collaborator_remove_post),
+            )
+            .merge(admin_routes)
+            .merge(moderation_routes)
             .route_layer(axum::middleware::from_fn(require_auth)) // every route above this layer will have this middleware attached to it
             .route("/a", get(hn))
             .route("/", get(home))
             .route_with_tsr("/snippet/view/{id}", get(snippet_view))
-            .route("/user/signup", get(user_signup))
-            .route("/user/signup", post(user_signup_post))
+            .route("/snippet/report/{id}", post(snippet_report_post))
+            .route("/snippet/events", get(snippet_events))
+            .route("/snippet/embed/{id}", get(snippet_embed))
+            .route("/snippet/preview/{id}/{revision}", get(snippet_preview))
+            .route("/oembed", get(oembed))
+            .route(
+                CSP_REPORT_PATH,
+                post(csp_report).layer(DefaultBodyLimit::max(CSP_REPORT_LIMIT)),
+            )
             .route("/user/login", get(user_login))
-            .route("/user/login", post(user_login_post))
+            .route("/user/login/passkey/start", post(user_login_passkey_start))
+            .route(
+                "/user/login/passkey/finish",
+                post(user_login_passkey_finish),
+            )
+            .route("/user/login/two-factor", get(user_login_two_factor))
+            .route("/user/login/two-factor", post(user_login_two_factor_post))
+            .route("/user/verify-email", get(user_verify_email))
+            .route("/feed.atom", get(feed_atom))
+            .route("/feed.rss", get(feed_rss))
+            .route("/user/{id}/feed.atom", get(user_feed_atom))
+            .route("/user/{id}/feed.rss", get(user_feed_rss))
+            .route("/tag/{tag}/feed.atom", get(tag_feed_atom))
+            .route("/tag/{tag}/feed.rss", get(tag_feed_rss))
+            .merge(password_routes)
+            .merge(sso_routes)
             .nest_service("/static", ServeDir::new("static"))
+            .layer(Extension(shared_state.login_options.clone()))
+            .layer(axum::middleware::from_fn_with_state(
+                shared_state.clone(),
+                rate_limit,
+            )) // needs the user `authenticate` finds
             .layer(axum::middleware::from_fn_with_state(
                 shared_state.clone(),
                 authenticate,
             )) // since this uses session it needs to be work after the session layer
+            .layer(axum::middleware::from_fn(csrf_protect))
             .layer(session_layer)
-            .layer(axum::middleware::from_fn(common_headers))
+            .layer(axum::middleware::from_fn_with_state(
+                shared_state.clone(),
+                common_headers,
+            ))
             .layer(CatchPanicLayer::new())
             .layer(tl)
             .layer(
@@ -73,7 +238,10 @@ impl AppRouter {
                         .extensions()
                         .get::<MatchedPath>()
                         .map(MatchedPath::as_str);
-                    let request_ip = request.headers().get("user-ip").unwrap().to_str().unwrap();
+                    let request_ip = request
+                        .extensions()
+                        .get::<ClientIp>()
+                        .map(ToString::to_string);
                     let scheme = format!("{:#?}", request.version());
                     info_span!(
                         "http_request",
@@ -84,9 +252,16 @@ impl AppRouter {
                     )
                 }),
             )
-            // layering happens in the opposite order of declaration so this needs to be after the logging layer
-            .layer(axum::middleware::from_fn(request_ip))
-            .with_state(shared_state);
+            .with_state(shared_state.clone());
+        // layers of a router only run once a route has been matched, denied clients are
+        // turned away before that by wrapping the whole router
+        let router =
+            Router::new()
+                .fallback_service(router)
+                .layer(axum::middleware::from_fn_with_state(
+                    shared_state,
+                    request_ip,
+                ));
 
         AppRouter { router }
     }
@@ -0,0 +1,176 @@
+// The security headers every response gets, see `middleware::common_headers`. Each request
+// gets a fresh CSP nonce, templates put it on their script tags with `csp_nonce()` and only
+// scripts carrying it run, inline ones included.
+use axum::http::{HeaderMap, HeaderValue};
+
+use crate::utils::token;
+
+pub const CSP_REPORT_PATH: &str = "/csp-report";
+
+tokio::task_local! {
+    static CSP_NONCE: String;
+}
+
+/// The nonce of the request being handled, for `<script nonce='...'>`.
+pub fn csp_nonce() -> String {
+    CSP_NONCE.try_with(String::clone).unwrap_or_default()
+}
+
+/// Runs `future` (the rest of the request) with a fresh nonce, returns it along with the output.
+pub async fn with_nonce<F: Future>(future: F) -> (String, F::Output) {
+    let nonce = token::generate();
+    let output = CSP_NONCE.scope(nonce.clone(), future).await;
+    (nonce, output)
+}
+
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    csp: String,
+    // a policy tried out next to the enforced one, browsers only report what it would block
+    csp_report_only: String,
+    hsts: Option<String>,
+    permissions_policy: String,
+    referrer_policy: String,
+}
+
+impl SecurityHeaders {
+    /// `csp` and `csp_report_only` get the request's nonce added to their `script-src` (or a
+    /// `script-src` with it) and violations reported to `/csp-report`. The report-only policy
+    /// is sent alongside the enforced one, it does not replace it. The server only speaks TLS,
+    /// so `Strict-Transport-Security` goes out unless `hsts_max_age` is None or 0.
+    /// Empty policies leave out their header.
+    pub fn new(
+        csp: &str,
+        csp_report_only: &str,
+        hsts_max_age: Option<u64>,
+        hsts_include_subdomains: bool,
+        permissions_policy: &str,
+        referrer_policy: &str,
+    ) -> Self {
+        let hsts = hsts_max_age.filter(|max_age| *max_age > 0).map(|max_age| {
+            let mut hsts = format!("max-age={}", max_age);
+            if hsts_include_subdomains {
+                hsts.push_str("; includeSubDomains");
+            }
+            hsts
+        });
+        Self {
+            csp: csp.trim().trim_end_matches(';').to_string(),
+            csp_report_only: csp_report_only.trim().trim_end_matches(';').to_string(),
+            hsts,
+            permissions_policy: permissions_policy.trim().to_string(),
+            referrer_policy: referrer_policy.trim().to_string(),
+        }
+    }
+
+    fn csp(policy: &str, nonce: &str) -> String {
+        let nonce = format!("'nonce-{}'", nonce);
+        let mut directives: Vec<String> = policy
+            .split(';')
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty())
+            .map(str::to_string)
+            .collect();
+        match directives
+            .iter_mut()
+            .find(|directive| directive.split_whitespace().next() == Some("script-src"))
+        {
+            Some(script_src) => {
+                script_src.push(' ');
+                script_src.push_str(&nonce);
+            }
+            None => directives.push(format!("script-src 'self' {}", nonce)),
+        }
+        if !directives
+            .iter()
+            .any(|directive| directive.starts_with("report-uri"))
+        {
+            directives.push(format!("report-uri {}", CSP_REPORT_PATH));
+        }
+        directives.join("; ")
+    }
+
+    /// Adds the headers to a response. Handlers with special needs (embeds) set their own
+    /// `Content-Security-Policy`, it is left alone along with the report-only one.
+    pub fn apply(&self, headers: &mut HeaderMap, nonce: &str) {
+        if !headers.contains_key("content-security-policy") {
+            for (name, policy) in [
+                ("content-security-policy", &self.csp),
+                ("content-security-policy-report-only", &self.csp_report_only),
+            ] {
+                if !policy.is_empty() {
+                    insert(headers, name, &Self::csp(policy, nonce));
+                }
+            }
+        }
+        if let Some(hsts) = &self.hsts {
+            insert(headers, "strict-transport-security", hsts);
+        }
+        insert(headers, "permissions-policy", &self.permissions_policy);
+        insert(headers, "referrer-policy", &self.referrer_policy);
+    }
+}
+
+fn insert(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(security_headers: &SecurityHeaders) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        security_headers.apply(&mut headers, "abc");
+        headers
+    }
+
+    #[test]
+    fn hsts_is_sent_unless_turned_off() {
+        let sent = SecurityHeaders::new("", "", Some(31536000), true, "", "");
+        assert_eq!(
+            headers(&sent)["strict-transport-security"],
+            "max-age=31536000; includeSubDomains"
+        );
+        for max_age in [None, Some(0)] {
+            let off = SecurityHeaders::new("", "", max_age, false, "", "");
+            assert!(!headers(&off).contains_key("strict-transport-security"));
+        }
+    }
+
+    #[test]
+    fn the_report_only_policy_goes_alongside_the_enforced_one() {
+        let security_headers = SecurityHeaders::new(
+            "default-src 'self'; script-src 'self';",
+            "default-src 'none'",
+            None,
+            false,
+            "",
+            "",
+        );
+        let headers = headers(&security_headers);
+        assert_eq!(
+            headers["content-security-policy"],
+            "default-src 'self'; script-src 'self' 'nonce-abc'; report-uri /csp-report"
+        );
+        assert_eq!(
+            headers["content-security-policy-report-only"],
+            "default-src 'none'; script-src 'self' 'nonce-abc'; report-uri /csp-report"
+        );
+
+        // a handler's own policy wins, nothing is reported against ours either
+        let mut own = HeaderMap::new();
+        own.insert(
+            "content-security-policy",
+            HeaderValue::from_static("frame-ancestors *"),
+        );
+        security_headers.apply(&mut own, "abc");
+        assert_eq!(own["content-security-policy"], "frame-ancestors *");
+        assert!(!own.contains_key("content-security-policy-report-only"));
+    }
+}
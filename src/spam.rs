@@ -0,0 +1,358 @@
+// Spam heuristics for signups and for snippets of new accounts. Every detector looks at a
+// submission and may add points with a reason; past the threshold a snippet is held back
+// for moderators and a signup refused. Detectors are a trait, more can be plugged in with
+// `SpamFilter::with`.
+use std::{collections::HashSet, fs, io, path::Path, sync::Arc};
+
+use futures::future::BoxFuture;
+use regex::Regex;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    models::submissions::{KIND_SIGNUP, KIND_SNIPPET, SubmissionModel},
+    utils::token,
+};
+
+pub type DetectorError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum SpamConfigError {
+    #[error("could not read the spam blocklist : {0}")]
+    Read(#[from] io::Error),
+    #[error("invalid spam blocklist : {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SubmissionKind {
+    Snippet,
+    Signup,
+}
+
+impl SubmissionKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            SubmissionKind::Snippet => KIND_SNIPPET,
+            SubmissionKind::Signup => KIND_SIGNUP,
+        }
+    }
+}
+
+/// What is being submitted, as far as the detectors are concerned.
+pub struct Submission<'a> {
+    pub kind: SubmissionKind,
+    pub ip: Option<&'a str>,
+    // `None` for signups
+    pub user_id: Option<i32>,
+    // the title and content of a snippet, the name of someone signing up
+    pub text: &'a str,
+    pub email: Option<&'a str>,
+}
+
+impl Submission<'_> {
+    /// Digest of the snippet text with case and whitespace differences ironed out.
+    pub fn content_hash(&self) -> Option<String> {
+        if self.kind != SubmissionKind::Snippet {
+            return None;
+        }
+        let normalised = self
+            .text
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        Some(token::digest(&normalised))
+    }
+}
+
+/// Points towards a submission being spam, and why.
+pub struct Signal {
+    pub score: u32,
+    pub reason: String,
+}
+
+pub trait Detector: Send + Sync {
+    fn inspect<'a>(
+        &'a self,
+        submission: &'a Submission<'a>,
+    ) -> BoxFuture<'a, Result<Option<Signal>, DetectorError>>;
+}
+
+pub struct Verdict {
+    pub score: u32,
+    pub reasons: Vec<String>,
+    pub suspicious: bool,
+}
+
+#[derive(Clone)]
+pub struct SpamFilter {
+    submissions: SubmissionModel,
+    detectors: Vec<Arc<dyn Detector>>,
+    threshold: u32,
+}
+
+impl SpamFilter {
+    /// A filter with the built-in detectors. `blocklist` is a JSON file like
+    /// `{"words": ["casino"], "domains": ["spam.example"]}`.
+    pub fn new(
+        submissions: SubmissionModel,
+        threshold: u32,
+        blocklist: Option<&Path>,
+    ) -> Result<Self, SpamConfigError> {
+        let blocklist = match blocklist {
+            Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+            None => Blocklist::default(),
+        };
+        Ok(Self {
+            submissions: submissions.clone(),
+            detectors: Vec::new(),
+            threshold,
+        }
+        .with(LinkDensity::new())
+        .with(RepeatedContent {
+            submissions: submissions.clone(),
+        })
+        .with(Velocity { submissions })
+        .with(blocklist.detector()))
+    }
+
+    /// Adds a detector, e.g. one that knows about the spam a deployment keeps getting.
+    pub fn with(mut self, detector: impl Detector + 'static) -> Self {
+        self.detectors.push(Arc::new(detector));
+        self
+    }
+
+    /// Runs every detector over the submission and remembers it for the ones that count
+    /// repeats. A detector that fails is skipped, spam checks should not take the site down.
+    pub async fn check(&self, submission: &Submission<'_>) -> Verdict {
+        let mut score = 0;
+        let mut reasons = Vec::new();
+        for detector in &self.detectors {
+            match detector.inspect(submission).await {
+                Ok(Some(signal)) => {
+                    score += signal.score;
+                    reasons.push(signal.reason);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::error!("spam detector failed : {}", e),
+            }
+        }
+        if let Err(e) = self
+            .submissions
+            .record(
+                submission.kind.as_str(),
+                submission.ip,
+                submission.user_id,
+                submission.content_hash().as_deref(),
+            )
+            .await
+        {
+            tracing::error!("could not record the submission : {}", e);
+        }
+        Verdict {
+            score,
+            reasons,
+            suspicious: score >= self.threshold,
+        }
+    }
+}
+
+// Snippets that are mostly links.
+struct LinkDensity {
+    links: Regex,
+}
+
+impl LinkDensity {
+    fn new() -> Self {
+        Self {
+            links: Regex::new(r"(?i)\b(?:https?://|www\.)\S+").unwrap(),
+        }
+    }
+}
+
+impl Detector for LinkDensity {
+    fn inspect<'a>(
+        &'a self,
+        submission: &'a Submission<'a>,
+    ) -> BoxFuture<'a, Result<Option<Signal>, DetectorError>> {
+        Box::pin(async move {
+            let links = self.links.find_iter(submission.text).count();
+            let words = submission.text.split_whitespace().count();
+            let signal = if links >= 3 && links * 4 >= words {
+                Some(Signal {
+                    score: 40,
+                    reason: format!("{} links in {} words", links, words),
+                })
+            } else if links >= 15 {
+                Some(Signal {
+                    score: 30,
+                    reason: format!("{} links", links),
+                })
+            } else {
+                None
+            };
+            Ok(signal)
+        })
+    }
+}
+
+// The same snippet pasted again and again, by one account or many.
+struct RepeatedContent {
+    submissions: SubmissionModel,
+}
+
+impl Detector for RepeatedContent {
+    fn inspect<'a>(
+        &'a self,
+        submission: &'a Submission<'a>,
+    ) -> BoxFuture<'a, Result<Option<Signal>, DetectorError>> {
+        Box::pin(async move {
+            let Some(hash) = submission.content_hash() else {
+                return Ok(None);
+            };
+            let repeats = self.submissions.count_with_hash(&hash, 24 * 60).await?;
+            let score = match repeats {
+                0 => return Ok(None),
+                1 => 20,
+                _ => 50,
+            };
+            Ok(Some(Signal {
+                score,
+                reason: format!("same content submitted {} time(s) in the last day", repeats),
+            }))
+        })
+    }
+}
+
+// Bursts of signups or snippets from one address, or of snippets from one new account.
+struct Velocity {
+    submissions: SubmissionModel,
+}
+
+impl Detector for Velocity {
+    fn inspect<'a>(
+        &'a self,
+        submission: &'a Submission<'a>,
+    ) -> BoxFuture<'a, Result<Option<Signal>, DetectorError>> {
+        Box::pin(async move {
+            let kind = submission.kind.as_str();
+            // (submissions from the address, within minutes) that are suspicious
+            let (ip_limit, minutes) = match submission.kind {
+                SubmissionKind::Snippet => (10, 60),
+                SubmissionKind::Signup => (3, 24 * 60),
+            };
+            if let Some(ip) = submission.ip {
+                let recent = self.submissions.count_from_ip(kind, ip, minutes).await?;
+                if recent >= ip_limit {
+                    return Ok(Some(Signal {
+                        score: 50,
+                        reason: format!("{} {}s from {} recently", recent, kind, ip),
+                    }));
+                }
+            }
+            if let Some(user_id) = submission.user_id {
+                let recent = self.submissions.count_from_user(kind, user_id, 60).await?;
+                if recent >= 5 {
+                    return Ok(Some(Signal {
+                        score: 30,
+                        reason: format!("{} {}s from the account in the last hour", recent, kind),
+                    }));
+                }
+            }
+            Ok(None)
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct Blocklist {
+    #[serde(default)]
+    words: Vec<String>,
+    #[serde(default)]
+    domains: Vec<String>,
+}
+
+impl Blocklist {
+    fn detector(self) -> BlocklistDetector {
+        BlocklistDetector {
+            words: self
+                .words
+                .iter()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            domains: self
+                .domains
+                .iter()
+                .map(|domain| domain.trim().trim_start_matches('.').to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+            hosts: Regex::new(r"(?i)(?:https?://|www\.|@)([a-z0-9.-]+\.[a-z]{2,})").unwrap(),
+        }
+    }
+}
+
+// Words and domains a deployment does not want to see, domains match their subdomains too.
+struct BlocklistDetector {
+    words: HashSet<String>,
+    domains: Vec<String>,
+    hosts: Regex,
+}
+
+impl BlocklistDetector {
+    fn blocked_domain(&self, host: &str) -> Option<&str> {
+        let host = host.to_lowercase();
+        self.domains
+            .iter()
+            .find(|domain| {
+                host == **domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|rest| rest.ends_with('.'))
+            })
+            .map(String::as_str)
+    }
+}
+
+impl Detector for BlocklistDetector {
+    fn inspect<'a>(
+        &'a self,
+        submission: &'a Submission<'a>,
+    ) -> BoxFuture<'a, Result<Option<Signal>, DetectorError>> {
+        Box::pin(async move {
+            let hosts = self
+                .hosts
+                .captures_iter(submission.text)
+                .filter_map(|captures| captures.get(1))
+                .map(|host| host.as_str())
+                .chain(
+                    submission
+                        .email
+                        .and_then(|email| email.rsplit_once('@').map(|(_, host)| host)),
+                );
+            for host in hosts {
+                if let Some(domain) = self.blocked_domain(host) {
+                    return Ok(Some(Signal {
+                        score: 60,
+                        reason: format!("blocked domain {}", domain),
+                    }));
+                }
+            }
+            let text = submission.text.to_lowercase();
+            let mut words: Vec<&str> = text
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| self.words.contains(*word))
+                .collect();
+            words.sort();
+            words.dedup();
+            if words.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(Signal {
+                score: (30 * words.len() as u32).min(60),
+                reason: format!("blocked words: {}", words.join(", ")),
+            }))
+        })
+    }
+}
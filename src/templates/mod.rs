@@ -1,13 +1,18 @@
 use askama::Template;
+use axum::http::StatusCode;
 use chrono::Datelike; // not used directly anywhere but it is used in codegen of askama for the current year
 use sqlx::types::chrono::{DateTime, Utc};
 
+use crate::models::collaborators::Collaborator;
 use crate::models::snippet::Snippet; // bring trait in scope
 
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "partials/nav.html")]
 pub struct Nav {
     pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
 }
 
 #[derive(Template)]
@@ -19,6 +24,8 @@ pub struct HomeTemplate {
     // in your template
     pub flash: String,
     pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
 }
 
 #[derive(Template)] // this will generate the code...
@@ -31,7 +38,20 @@ pub struct ViewTemplate {
     created: DateTime<Utc>,
     expires: DateTime<Utc>,
     flash: String,
+    tags: Vec<String>,
+    base_url: String,
+    revision: i32,
+    // shown to its owner and moderators while reports about it are reviewed
+    hidden: bool,
+    can_report: bool,
+    // the owner and the collaborators they invited can edit it live
+    can_edit_live: bool,
+    // only the owner gets to see and manage the collaborators
+    owner: bool,
+    collaborators: Vec<Collaborator>,
     pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
 }
 
 impl ViewTemplate {
@@ -50,7 +70,17 @@ impl ViewTemplate {
             created,
             expires,
             flash: "".to_string(),
+            tags: Vec::new(),
+            base_url: "".to_string(),
+            revision: 1,
+            hidden: false,
+            can_report: false,
+            can_edit_live: false,
+            owner: false,
+            collaborators: Vec::new(),
             is_authenticated,
+            needs_verification: false,
+            csrf_token: "".to_string(),
         }
     }
 
@@ -58,6 +88,59 @@ impl ViewTemplate {
         self.flash = flash;
     }
 
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.tags = tags;
+    }
+
+    pub fn set_needs_verification(&mut self, needs_verification: bool) {
+        self.needs_verification = needs_verification;
+    }
+
+    pub fn set_csrf_token(&mut self, csrf_token: String) {
+        self.csrf_token = csrf_token;
+    }
+
+    pub fn set_revision(&mut self, revision: i32) {
+        self.revision = revision;
+    }
+
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    pub fn set_can_report(&mut self, can_report: bool) {
+        self.can_report = can_report;
+    }
+
+    pub fn set_can_edit_live(&mut self, can_edit_live: bool) {
+        self.can_edit_live = can_edit_live;
+    }
+
+    pub fn set_owner(&mut self, owner: bool, collaborators: Vec<Collaborator>) {
+        self.owner = owner;
+        self.collaborators = collaborators;
+    }
+
+    /// Short plain-text summary for link previews.
+    fn description(&self) -> String {
+        let collapsed = self
+            .content
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if collapsed.chars().count() <= 200 {
+            return collapsed;
+        }
+        let mut description: String = collapsed.chars().take(197).collect();
+        description.push_str("...");
+        description
+    }
+
+    // needed for absolute links in the oEmbed discovery and Open Graph tags
+    pub fn set_base_url(&mut self, base_url: String) {
+        self.base_url = base_url;
+    }
+
     pub fn convert_to_view(value: Snippet, is_authenticated: bool) -> Self {
         ViewTemplate::new(
             value.title.clone(),
@@ -69,3 +152,218 @@ impl ViewTemplate {
         )
     }
 }
+
+#[derive(Template)]
+#[template(path = "pages/live.html")]
+pub struct LiveTemplate {
+    pub title: String,
+    pub id: i32,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/error.html")]
+pub struct ErrorTemplate {
+    pub status: StatusCode,
+    pub message: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/login_two_factor.html")]
+pub struct LoginTwoFactorTemplate {
+    pub error: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/two_factor.html")]
+pub struct TwoFactorTemplate {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+    pub error: String,
+    pub flash: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/two_factor_confirm.html")]
+pub struct TwoFactorConfirmTemplate {
+    // rendered on the server, so that the secret never goes to a third party
+    pub qr_svg: String,
+    pub secret: String,
+    pub error: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/recovery_codes.html")]
+pub struct RecoveryCodesTemplate {
+    pub codes: Vec<String>,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+/// A logged in device on the sessions page. Sessions are addressed by a digest of their
+/// id, the id itself would let anybody who sees the page take the session over.
+pub struct SessionRow {
+    pub handle: String,
+    pub ip: String,
+    pub user_agent: String,
+    pub created: String,
+    pub last_seen: String,
+    pub current: bool,
+}
+
+#[derive(Template)]
+#[template(path = "pages/sessions.html")]
+pub struct SessionsTemplate {
+    pub sessions: Vec<SessionRow>,
+    pub flash: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/admin.html")]
+pub struct AdminTemplate {
+    pub users: i64,
+    pub disabled_users: i64,
+    pub live_snippets: i64,
+    // (day, snippets created that day), oldest first
+    pub per_day: Vec<(String, i64)>,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+pub struct AdminUserRow {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub role: &'static str,
+    pub email_verified: bool,
+    pub created: String,
+    pub disabled: bool,
+    pub snippets: i64,
+}
+
+pub struct AdminLockoutRow {
+    pub kind: String,
+    pub key: String,
+    pub failures: i32,
+    pub locked_until: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/admin_users.html")]
+pub struct AdminUsersTemplate {
+    pub users: Vec<AdminUserRow>,
+    pub lockouts: Vec<AdminLockoutRow>,
+    pub query: String,
+    // links to the neighbouring pages, empty where there is none
+    pub previous: String,
+    pub next: String,
+    // the admin looking at the list, who cannot disable themselves
+    pub admin_id: i32,
+    pub flash: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+pub struct AdminSnippetRow {
+    pub id: i32,
+    pub title: String,
+    pub author: String,
+    pub visibility: String,
+    pub created: String,
+    pub expires: String,
+    // live, hidden, expired or deleted
+    pub status: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "pages/admin_snippets.html")]
+pub struct AdminSnippetsTemplate {
+    pub snippets: Vec<AdminSnippetRow>,
+    pub query: String,
+    pub state: &'static str,
+    pub previous: String,
+    pub next: String,
+    pub flash: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+pub struct ReportRow {
+    pub id: i32,
+    pub snippet_id: i32,
+    pub title: String,
+    pub reason: &'static str,
+    pub details: String,
+    pub reporter: String,
+    pub created: String,
+    pub hidden: bool,
+    pub author: String,
+    // anonymous snippets have nobody to warn or suspend
+    pub has_author: bool,
+}
+
+#[derive(Template)]
+#[template(path = "pages/moderation.html")]
+pub struct ModerationTemplate {
+    pub reports: Vec<ReportRow>,
+    pub previous: String,
+    pub next: String,
+    pub flash: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+// Standalone page meant to be shown in an iframe on other sites, it does not extend base.html.
+#[derive(Template)]
+#[template(path = "embed.html")]
+pub struct EmbedTemplate {
+    pub title: String,
+    pub id: i32,
+    pub content: String,
+    pub base_url: String,
+}
+
+// Both feed flavours render the same data, only the markup differs.
+#[derive(Template)]
+#[template(path = "feeds/atom.xml")]
+pub struct AtomTemplate {
+    pub title: String,
+    pub author: String,
+    pub base_url: String,
+    pub self_path: String,
+    pub updated: DateTime<Utc>,
+    pub snippets: Vec<Snippet>,
+}
+
+#[derive(Template)]
+#[template(path = "feeds/rss.xml")]
+pub struct RssTemplate {
+    pub title: String,
+    pub author: String,
+    pub base_url: String,
+    pub self_path: String,
+    pub updated: DateTime<Utc>,
+    pub snippets: Vec<Snippet>,
+}
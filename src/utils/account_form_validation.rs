@@ -0,0 +1,109 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use askama::Template;
+use chrono::Datelike; // not used directly anywhere but it is used in codegen of askama for the current year
+use serde::Deserialize;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind::Field};
+
+use super::signup_form_validation::{validate_email, validate_name, validate_password};
+
+pub const SNIPPETS_DELETE: &str = "delete";
+pub const SNIPPETS_ANONYMISE: &str = "anonymise";
+
+/// A passkey on the account page, addressed by its url safe base64 credential id.
+pub struct PasskeyRow {
+    pub id: String,
+    pub name: String,
+    pub created: String,
+    pub last_used: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/account.html")]
+pub struct AccountTemplate {
+    pub name: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub passkeys: Vec<PasskeyRow>,
+    // links to the moderation queue and the admin area
+    pub is_moderator: bool,
+    pub is_admin: bool,
+    pub user_errors: HashMap<String, String>,
+    pub flash: String,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+impl AccountTemplate {
+    fn get(&self, key: &str) -> &str {
+        if let Some(msg) = self.user_errors.get(key) {
+            msg
+        } else {
+            ""
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Validate, Clone)]
+pub struct NameData {
+    #[validate(custom(function = "validate_name"))]
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Validate, Clone)]
+pub struct EmailData {
+    #[validate(custom(function = "validate_email"))]
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug, Validate, Clone)]
+pub struct PasswordData {
+    pub current_password: String,
+    #[validate(custom(function = "validate_password"))]
+    pub password: String,
+}
+
+// sent as json by the script that runs the registration ceremony
+#[derive(Deserialize, Debug, Validate, Clone)]
+pub struct PasskeyData {
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "The name must be between 1 and 100 characters long"
+    ))]
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug, Validate, Clone)]
+pub struct DeleteAccountData {
+    pub password: String,
+    // what happens to the user's snippets
+    #[validate(custom(function = "validate_snippets_choice"))]
+    pub snippets: String,
+}
+
+fn validate_snippets_choice(choice: &str) -> Result<(), ValidationError> {
+    if choice != SNIPPETS_DELETE && choice != SNIPPETS_ANONYMISE {
+        return Err(ValidationError::new("snippets value")
+            .with_message(Cow::Borrowed("Choose what happens to your snippets")));
+    }
+    Ok(())
+}
+
+/// The messages of failed fields, keyed by `<prefix>_<field>` since one page holds several forms.
+pub fn field_errors(prefix: &str, e: &ValidationErrors) -> HashMap<String, String> {
+    let mut user_errors = HashMap::new();
+    for (field, kind) in e.errors() {
+        let mut error_string = "".to_string();
+        if let Field(errors) = kind {
+            errors.iter().for_each(|err| {
+                error_string += err.message.as_deref().unwrap_or("This field is invalid")
+            });
+        }
+        user_errors.insert(format!("{}_{}", prefix, field), error_string);
+    }
+    user_errors
+}
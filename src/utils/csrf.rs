@@ -0,0 +1,96 @@
+// Synchronizer tokens against cross-site request forgery. The token lives in the session,
+// every form carries it in a hidden field and `middleware::csrf_protect` compares the two.
+use axum::{
+    extract::FromRequestParts,
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use subtle::ConstantTimeEq;
+use tower_sessions::Session;
+
+use crate::AppState;
+
+use super::token;
+
+pub const SESSION_KEY: &str = "csrfToken";
+pub const FORM_FIELD: &str = "csrf_token";
+// for requests that are not urlencoded forms
+pub const HEADER: &str = "x-csrf-token";
+
+/// The current session's token for rendering forms. Extracting it creates the token
+/// on first use, so sessions are only started for visitors that actually get a form.
+#[derive(Clone, Debug, Default)]
+pub struct CsrfToken(pub String);
+
+impl<S> FromRequestParts<S> for CsrfToken
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        current(&session)
+            .await
+            .map(CsrfToken)
+            .map_err(|e| AppState::server_error(Box::new(e)))
+    }
+}
+
+/// Returns the session's token, creating one on first use.
+pub async fn current(session: &Session) -> Result<String, tower_sessions::session::Error> {
+    if let Some(token) = session.get::<String>(SESSION_KEY).await? {
+        return Ok(token);
+    }
+    rotate(session).await
+}
+
+/// Replaces the session's token. Call this together with `session.cycle_id()`
+/// whenever the privilege level changes (login, logout).
+pub async fn rotate(session: &Session) -> Result<String, tower_sessions::session::Error> {
+    let token = token::generate();
+    session.insert(SESSION_KEY, &token).await?;
+    Ok(token)
+}
+
+/// Compares in constant time. There is nothing to match without an expected token.
+pub fn matches(expected: &str, submitted: &str) -> bool {
+    !expected.is_empty() && bool::from(expected.as_bytes().ct_eq(submitted.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tower_sessions::MemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn only_the_same_token_matches() {
+        let token = token::generate();
+        assert!(matches(&token, &token.clone()));
+        assert!(!matches(&token, ""));
+        assert!(!matches(&token, &token[..token.len() - 1]));
+        assert!(!matches(&token, &format!("{}x", token)));
+        assert!(!matches(&token, &token.to_uppercase()));
+        assert!(!matches(&token, &token::generate()));
+        // no token matches nothing, an empty field included
+        assert!(!matches("", "x"));
+        assert!(!matches("", ""));
+    }
+
+    #[tokio::test]
+    async fn the_token_stays_until_rotated() {
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+        let first = current(&session).await.unwrap();
+        assert!(!first.is_empty());
+        assert_eq!(current(&session).await.unwrap(), first);
+
+        let rotated = rotate(&session).await.unwrap();
+        assert!(!matches(&rotated, &first));
+        assert_eq!(current(&session).await.unwrap(), rotated);
+    }
+}
@@ -7,12 +7,14 @@ use axum::{
 use chrono::Datelike; // not used directly anywhere but it is used in codegen of askama for the current year
 use serde::Deserialize;
 use std::{borrow::Cow, collections::HashMap};
+use tower_sessions::Session;
 use validator::ValidationErrorsKind::Field;
 use validator::{Validate, ValidationError};
 
 use crate::AppState;
+use crate::models::snippet::{VISIBILITY_PRIVATE, VISIBILITY_PUBLIC, VISIBILITY_UNLISTED};
 
-use super::validation_errors::ServerError;
+use super::{csrf, validation_errors::ServerError};
 
 #[derive(Template, Deserialize, Debug)]
 #[template(path = "pages/create.html")]
@@ -21,7 +23,15 @@ pub struct CreateTemplate {
     pub title: String,
     pub content: String,
     pub expires: u16,
+    pub visibility: String,
+    pub tags: String,
+    // what looks like leaked secrets in the content, publishing needs confirming then
+    pub secret_findings: Vec<String>,
+    // the deployment refuses such snippets outright
+    pub secrets_blocked: bool,
     pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
 }
 
 impl CreateTemplate {
@@ -47,6 +57,38 @@ pub struct SnippetData {
     // validate , value in 1,7,365
     #[validate(custom(function = "validate_expires"))]
     pub expires: u16,
+    #[serde(default = "default_visibility")]
+    #[validate(custom(function = "validate_visibility"))]
+    pub visibility: String,
+    // comma separated, e.g. "rust, async"
+    #[serde(default)]
+    #[validate(custom(function = "validate_tags"))]
+    pub tags: String,
+    // ticked after being warned about secrets in the content
+    #[serde(default)]
+    pub confirm_secrets: bool,
+}
+
+impl SnippetData {
+    /// Normalised, de-duplicated tags entered in the form.
+    pub fn tag_list(&self) -> Vec<String> {
+        parse_tags(&self.tags)
+    }
+}
+
+fn parse_tags(tags: &str) -> Vec<String> {
+    let mut list: Vec<String> = tags
+        .split(',')
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    list.sort();
+    list.dedup();
+    list
+}
+
+fn default_visibility() -> String {
+    VISIBILITY_PUBLIC.to_string()
 }
 
 fn validate_expires(expires: u16) -> Result<(), ValidationError> {
@@ -57,6 +99,40 @@ fn validate_expires(expires: u16) -> Result<(), ValidationError> {
     Ok(())
 }
 
+fn validate_visibility(visibility: &str) -> Result<(), ValidationError> {
+    if visibility != VISIBILITY_PUBLIC
+        && visibility != VISIBILITY_UNLISTED
+        && visibility != VISIBILITY_PRIVATE
+    {
+        return Err(
+            ValidationError::new("visibility value").with_message(Cow::Borrowed(
+                "This field must equal public, unlisted or private",
+            )),
+        );
+    }
+    Ok(())
+}
+
+fn validate_tags(tags: &str) -> Result<(), ValidationError> {
+    let list = parse_tags(tags);
+    if list.len() > 10 {
+        return Err(ValidationError::new("tag count")
+            .with_message(Cow::Borrowed("A snippet cannot have more than 10 tags")));
+    }
+    let valid = list.iter().all(|tag| {
+        tag.len() <= 50
+            && tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    });
+    if !valid {
+        return Err(ValidationError::new("tag value").with_message(Cow::Borrowed(
+            "Tags can only contain letters, digits, '-' and '_' and be at most 50 characters long",
+        )));
+    }
+    Ok(())
+}
+
 impl<S> FromRequest<S> for SnippetData
 where
     S: Send + Sync,
@@ -65,6 +141,11 @@ where
     type Rejection = RejectionWithUserInput;
 
     async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // the form is rendered again on errors and needs a token for the next attempt
+        let csrf_token = match req.extensions().get::<Session>().cloned() {
+            Some(session) => csrf::current(&session).await.unwrap_or_default(),
+            None => "".to_string(),
+        };
         let form_result = Form::<SnippetData>::from_request(req, state).await;
         match form_result {
             Ok(Form(value)) => {
@@ -72,6 +153,7 @@ where
                     Err(RejectionWithUserInput {
                         error: ServerError::ValidationError(e),
                         value: Some(value),
+                        csrf_token,
                     })
                 } else {
                     Ok(value)
@@ -80,6 +162,7 @@ where
             Err(form_rejection) => Err(RejectionWithUserInput {
                 error: ServerError::AxumFormRejection(form_rejection),
                 value: None,
+                csrf_token,
             }),
         }
     }
@@ -89,6 +172,7 @@ pub struct RejectionWithUserInput {
     // FIX - ServerError::ValidationError should always be paired with a non-None value for the 'value' field of this struct
     error: ServerError,
     value: Option<SnippetData>,
+    csrf_token: String,
 }
 
 impl IntoResponse for RejectionWithUserInput {
@@ -101,8 +185,14 @@ impl IntoResponse for RejectionWithUserInput {
                     title: value.title,
                     content: value.content,
                     expires: value.expires,
+                    visibility: value.visibility,
+                    tags: value.tags,
+                    secret_findings: Vec::new(),
+                    secrets_blocked: false,
                     // this is super shady
                     is_authenticated: true,
+                    needs_verification: false,
+                    csrf_token: self.csrf_token,
                 };
                 // this can be deserialized with serde... figure it out
                 // this is going to be the worst part of this code base until and unless I learn serde
@@ -111,9 +201,8 @@ impl IntoResponse for RejectionWithUserInput {
                 for error in field_errors.keys() {
                     let mut error_string = "".to_string();
                     if let Some(Field(e)) = field_errors.get(error) {
-                        e.into_iter().for_each(|err| {
-                            error_string += &err.message.as_ref().unwrap().to_string()
-                        });
+                        e.iter()
+                            .for_each(|err| error_string += err.message.as_ref().unwrap().as_ref());
                     }
                     create_template
                         .user_errors
@@ -12,20 +12,29 @@ use validator::{Validate, ValidationError}; // not used directly anywhere but it
 
 use askama::Template;
 use serde::Deserialize;
+use tower_sessions::Session;
 
-use crate::AppState;
+use crate::{AppState, LoginOptions};
 
-use super::validation_errors::ServerError;
+use super::{csrf, validation_errors::ServerError};
 
 #[derive(Template)] // this will generate the code...
 #[template(path = "pages/login.html")]
 // struct HelloTemplate<'a> {
 pub struct LoginTemplate {
     email: String,
+    #[allow(dead_code)]
     password: String,
     pub user_errors: HashMap<String, String>,
     pub flash: String,
     pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+    password_login: bool,
+    // name of the single sign-on provider, empty without one
+    sso: String,
+    // the password form has to be sent with proof of work, see `pow.js`
+    pow: bool,
 }
 
 impl LoginTemplate {
@@ -36,9 +45,26 @@ impl LoginTemplate {
             user_errors: HashMap::new(),
             flash: "".to_string(),
             is_authenticated: false,
+            needs_verification: false,
+            csrf_token: "".to_string(),
+            password_login: true,
+            sso: "".to_string(),
+            pow: false,
         }
     }
 
+    /// Shows only the ways of logging in the deployment offers.
+    pub fn with_options(mut self, options: &LoginOptions) -> Self {
+        self.password_login = options.password;
+        self.sso = options.sso.clone().unwrap_or_default();
+        self
+    }
+
+    pub fn with_pow(mut self, pow: bool) -> Self {
+        self.pow = pow;
+        self
+    }
+
     fn get(&self, key: &str) -> &str {
         if let Some(msg) = self.user_errors.get(key) {
             msg
@@ -55,12 +81,20 @@ pub struct LoginData {
     // validate , value in 1,7,365
     #[validate(custom(function = "validate_email"))]
     pub email: String,
+    // the "remember me" checkbox, only sent when ticked
+    #[serde(default)]
+    pub remember: Option<String>,
+    // filled in by `pow.js` when the form asks for proof of work
+    #[serde(default)]
+    pub pow_challenge: String,
+    #[serde(default)]
+    pub pow_solution: String,
 }
 
-fn validate_email(email: &String) -> Result<(), ValidationError> {
+fn validate_email(email: &str) -> Result<(), ValidationError> {
     let re = Regex::new("^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*$").unwrap();
     let caps = re.captures(email);
-    if let None = caps {
+    if caps.is_none() {
         return Err(ValidationError::new("email value")
             .with_message(Cow::Borrowed("This field must contain a valid email")));
     }
@@ -75,6 +109,12 @@ where
     type Rejection = RejectionWithUserInput;
 
     async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // the form is rendered again on errors and needs a token for the next attempt
+        let csrf_token = match req.extensions().get::<Session>().cloned() {
+            Some(session) => csrf::current(&session).await.unwrap_or_default(),
+            None => "".to_string(),
+        };
+        let options = req.extensions().get::<LoginOptions>().cloned();
         let form_result = Form::<LoginData>::from_request(req, state).await;
         match form_result {
             Ok(Form(value)) => {
@@ -82,6 +122,8 @@ where
                     Err(RejectionWithUserInput {
                         error: ServerError::ValidationError(e),
                         value: Some(value),
+                        csrf_token,
+                        options,
                     })
                 } else {
                     Ok(value)
@@ -90,6 +132,8 @@ where
             Err(form_rejection) => Err(RejectionWithUserInput {
                 error: ServerError::AxumFormRejection(form_rejection),
                 value: None,
+                csrf_token,
+                options,
             }),
         }
     }
@@ -99,6 +143,8 @@ pub struct RejectionWithUserInput {
     // FIX - ServerError::ValidationError should always be paired with a non-None value for the 'value' field of this struct
     error: ServerError,
     value: Option<LoginData>,
+    csrf_token: String,
+    options: Option<LoginOptions>,
 }
 
 impl IntoResponse for RejectionWithUserInput {
@@ -107,14 +153,17 @@ impl IntoResponse for RejectionWithUserInput {
             ServerError::ValidationError(e) => {
                 let value = self.value.unwrap();
                 let mut login_template = LoginTemplate::new(value.email, value.password);
+                if let Some(options) = &self.options {
+                    login_template = login_template.with_options(options);
+                }
+                login_template.csrf_token = self.csrf_token;
                 // this can be deserialized with serde.
                 let field_errors = e.errors();
                 for error in field_errors.keys() {
                     let mut error_string = "".to_string();
                     if let Some(Field(e)) = field_errors.get(error) {
-                        e.into_iter().for_each(|err| {
-                            error_string += &err.message.as_ref().unwrap().to_string()
-                        });
+                        e.iter()
+                            .for_each(|err| error_string += err.message.as_ref().unwrap().as_ref());
                     }
                     login_template
                         .user_errors
@@ -1,4 +1,24 @@
+pub mod account_form_validation;
+pub mod csrf;
 pub mod form_validation;
 pub mod login_form_validation;
+pub mod password_reset_form_validation;
+pub mod remember;
 pub mod signup_form_validation;
+pub mod token;
+pub mod totp;
 pub mod validation_errors;
+
+/// Escapes everything but the unreserved characters of RFC 3986, for a path segment or a
+/// query value.
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,200 @@
+use axum::{
+    Form,
+    extract::{FromRequest, Request, rejection::FormRejection},
+    response::{IntoResponse, Response},
+};
+use validator::ValidationErrorsKind::Field;
+
+use chrono::Datelike;
+use regex::Regex;
+use std::{borrow::Cow, collections::HashMap};
+use validator::{Validate, ValidationError, ValidationErrors}; // not used directly anywhere but it is used in codegen of askama for the current year
+
+use askama::Template;
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use crate::AppState;
+
+use super::{csrf, validation_errors::ServerError};
+
+#[derive(Template)]
+#[template(path = "pages/forgot_password.html")]
+pub struct ForgotPasswordTemplate {
+    pub email: String,
+    pub user_errors: HashMap<String, String>,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+impl ForgotPasswordTemplate {
+    fn get(&self, key: &str) -> &str {
+        if let Some(msg) = self.user_errors.get(key) {
+            msg
+        } else {
+            ""
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "pages/reset_password.html")]
+pub struct ResetPasswordTemplate {
+    pub token: String,
+    pub user_errors: HashMap<String, String>,
+    pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
+}
+
+impl ResetPasswordTemplate {
+    fn get(&self, key: &str) -> &str {
+        if let Some(msg) = self.user_errors.get(key) {
+            msg
+        } else {
+            ""
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Validate, Clone)]
+pub struct ForgotPasswordData {
+    #[validate(custom(function = "validate_email"))]
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug, Validate, Clone)]
+pub struct ResetPasswordData {
+    pub token: String,
+    // the same rule as on signup
+    #[validate(length(min = 8, message = "This field must be at least 8 characters long"))]
+    pub password: String,
+}
+
+fn validate_email(email: &str) -> Result<(), ValidationError> {
+    let re = Regex::new("^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*$").unwrap();
+    let caps = re.captures(email);
+    if caps.is_none() {
+        return Err(ValidationError::new("email value")
+            .with_message(Cow::Borrowed("This field must contain a valid email")));
+    }
+    Ok(())
+}
+
+fn field_errors(e: &ValidationErrors) -> HashMap<String, String> {
+    let mut user_errors = HashMap::new();
+    let field_errors = e.errors();
+    for error in field_errors.keys() {
+        let mut error_string = "".to_string();
+        if let Some(Field(e)) = field_errors.get(error) {
+            e.iter()
+                .for_each(|err| error_string += err.message.as_ref().unwrap().as_ref());
+        }
+        user_errors.insert(error.to_string(), error_string);
+    }
+    user_errors
+}
+
+// the forms are rendered again on errors and need a token for the next attempt
+async fn csrf_token(session: Option<Session>) -> String {
+    match session {
+        Some(session) => csrf::current(&session).await.unwrap_or_default(),
+        None => "".to_string(),
+    }
+}
+
+impl<S> FromRequest<S> for ForgotPasswordData
+where
+    S: Send + Sync,
+    Form<ForgotPasswordData>: FromRequest<S, Rejection = FormRejection>,
+{
+    type Rejection = RejectionWithUserInput;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let csrf_token = csrf_token(req.extensions().get::<Session>().cloned()).await;
+        match Form::<ForgotPasswordData>::from_request(req, state).await {
+            Ok(Form(value)) => match value.validate() {
+                Ok(_) => Ok(value),
+                Err(e) => Err(RejectionWithUserInput {
+                    error: ServerError::ValidationError(e),
+                    value: Some(UserInput::Forgot(value)),
+                    csrf_token,
+                }),
+            },
+            Err(form_rejection) => Err(RejectionWithUserInput {
+                error: ServerError::AxumFormRejection(form_rejection),
+                value: None,
+                csrf_token,
+            }),
+        }
+    }
+}
+
+impl<S> FromRequest<S> for ResetPasswordData
+where
+    S: Send + Sync,
+    Form<ResetPasswordData>: FromRequest<S, Rejection = FormRejection>,
+{
+    type Rejection = RejectionWithUserInput;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let csrf_token = csrf_token(req.extensions().get::<Session>().cloned()).await;
+        match Form::<ResetPasswordData>::from_request(req, state).await {
+            Ok(Form(value)) => match value.validate() {
+                Ok(_) => Ok(value),
+                Err(e) => Err(RejectionWithUserInput {
+                    error: ServerError::ValidationError(e),
+                    value: Some(UserInput::Reset(value)),
+                    csrf_token,
+                }),
+            },
+            Err(form_rejection) => Err(RejectionWithUserInput {
+                error: ServerError::AxumFormRejection(form_rejection),
+                value: None,
+                csrf_token,
+            }),
+        }
+    }
+}
+
+pub enum UserInput {
+    Forgot(ForgotPasswordData),
+    Reset(ResetPasswordData),
+}
+
+pub struct RejectionWithUserInput {
+    // ServerError::ValidationError is always paired with a value
+    error: ServerError,
+    value: Option<UserInput>,
+    csrf_token: String,
+}
+
+impl IntoResponse for RejectionWithUserInput {
+    fn into_response(self) -> Response {
+        match (self.error, self.value) {
+            (ServerError::ValidationError(e), Some(UserInput::Forgot(value))) => {
+                let template = ForgotPasswordTemplate {
+                    email: value.email,
+                    user_errors: field_errors(&e),
+                    is_authenticated: false,
+                    needs_verification: false,
+                    csrf_token: self.csrf_token,
+                };
+                AppState::render(template.render())
+            }
+            (ServerError::ValidationError(e), Some(UserInput::Reset(value))) => {
+                let template = ResetPasswordTemplate {
+                    token: value.token,
+                    user_errors: field_errors(&e),
+                    is_authenticated: false,
+                    needs_verification: false,
+                    csrf_token: self.csrf_token,
+                };
+                AppState::render(template.render())
+            }
+            (ServerError::ValidationError(e), None) => AppState::server_error(Box::new(e)),
+            (ServerError::AxumFormRejection(e), _) => AppState::server_error(Box::new(e)),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// The "remember me" cookie. It carries a selector and a validator (see
+// `models::remember`) and brings a device back in once its session has expired.
+use axum::http::{HeaderMap, HeaderValue, header};
+use time::Duration;
+use tower_sessions::cookie::{Cookie, SameSite};
+
+use crate::models::remember::REMEMBER_DAYS;
+
+pub const COOKIE: &str = "remember";
+
+/// The token in the request's remember cookie, if there is one.
+pub fn from_headers(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|cookie| cookie.name() == COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// `Set-Cookie` value handing the token to the browser. The cookie is only sent over
+/// https when the site is served that way.
+pub fn set_cookie(token: &str, base_url: &str) -> HeaderValue {
+    let secure = base_url.starts_with("https://");
+    let cookie = Cookie::build((COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::days(REMEMBER_DAYS));
+    // selector and validator are url safe base64 joined by a dot
+    HeaderValue::from_str(&cookie.to_string()).unwrap()
+}
+
+/// `Set-Cookie` value removing the cookie from the browser.
+pub fn clear_cookie() -> HeaderValue {
+    let cookie = Cookie::build((COOKIE, ""))
+        .path("/")
+        .http_only(true)
+        .max_age(Duration::ZERO);
+    HeaderValue::from_str(&cookie.to_string()).unwrap()
+}
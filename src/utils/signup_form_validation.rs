@@ -12,10 +12,11 @@ use validator::{Validate, ValidationError}; // not used directly anywhere but it
 
 use askama::Template;
 use serde::Deserialize;
+use tower_sessions::Session;
 
 use crate::AppState;
 
-use super::validation_errors::ServerError;
+use super::{csrf, validation_errors::ServerError};
 
 #[derive(Template, Deserialize, Debug)]
 #[template(path = "pages/signup.html")]
@@ -23,8 +24,11 @@ pub struct SignupTemplate {
     pub user_errors: HashMap<String, String>,
     pub name: String,
     pub email: String,
+    #[allow(dead_code)]
     pub password: String,
     pub is_authenticated: bool,
+    pub needs_verification: bool,
+    pub csrf_token: String,
 }
 
 impl SignupTemplate {
@@ -39,19 +43,43 @@ impl SignupTemplate {
 
 #[derive(Deserialize, Debug, Validate, Clone)]
 pub struct SignupData {
-    #[validate(length(min = 1, message = "This field cannot be blank"))]
+    #[validate(custom(function = "validate_name"))]
     pub name: String,
-    #[validate(length(min = 8, message = "This field must be at least 8 characters long"))]
+    #[validate(custom(function = "validate_password"))]
     pub password: String,
     // validate , value in 1,7,365
     #[validate(custom(function = "validate_email"))]
     pub email: String,
+    // filled in by `pow.js`
+    #[serde(default)]
+    pub pow_challenge: String,
+    #[serde(default)]
+    pub pow_solution: String,
 }
 
-fn validate_email(email: &String) -> Result<(), ValidationError> {
+// the rules for account details, shared with the account settings forms
+
+pub fn validate_name(name: &str) -> Result<(), ValidationError> {
+    if name.is_empty() {
+        return Err(ValidationError::new("length")
+            .with_message(Cow::Borrowed("This field cannot be blank")));
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    if password.chars().count() < 8 {
+        return Err(ValidationError::new("length").with_message(Cow::Borrowed(
+            "This field must be at least 8 characters long",
+        )));
+    }
+    Ok(())
+}
+
+pub fn validate_email(email: &str) -> Result<(), ValidationError> {
     let re = Regex::new("^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*$").unwrap();
     let caps = re.captures(email);
-    if let None = caps {
+    if caps.is_none() {
         return Err(ValidationError::new("email value")
             .with_message(Cow::Borrowed("This field must contain a valid email")));
     }
@@ -66,6 +94,11 @@ where
     type Rejection = RejectionWithUserInput;
 
     async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // the form is rendered again on errors and needs a token for the next attempt
+        let csrf_token = match req.extensions().get::<Session>().cloned() {
+            Some(session) => csrf::current(&session).await.unwrap_or_default(),
+            None => "".to_string(),
+        };
         let form_result = Form::<SignupData>::from_request(req, state).await;
         match form_result {
             Ok(Form(value)) => {
@@ -73,6 +106,7 @@ where
                     Err(RejectionWithUserInput {
                         error: ServerError::ValidationError(e),
                         value: Some(value),
+                        csrf_token,
                     })
                 } else {
                     Ok(value)
@@ -81,6 +115,7 @@ where
             Err(form_rejection) => Err(RejectionWithUserInput {
                 error: ServerError::AxumFormRejection(form_rejection),
                 value: None,
+                csrf_token,
             }),
         }
     }
@@ -90,6 +125,7 @@ pub struct RejectionWithUserInput {
     // FIX - ServerError::ValidationError should always be paired with a non-None value for the 'value' field of this struct
     error: ServerError,
     value: Option<SignupData>,
+    csrf_token: String,
 }
 
 impl IntoResponse for RejectionWithUserInput {
@@ -103,15 +139,16 @@ impl IntoResponse for RejectionWithUserInput {
                     password: "".to_string(),
                     email: value.email,
                     is_authenticated: false,
+                    needs_verification: false,
+                    csrf_token: self.csrf_token,
                 };
                 // this can be deserialized with serde.
                 let field_errors = e.errors();
                 for error in field_errors.keys() {
                     let mut error_string = "".to_string();
                     if let Some(Field(e)) = field_errors.get(error) {
-                        e.into_iter().for_each(|err| {
-                            error_string += &err.message.as_ref().unwrap().to_string()
-                        });
+                        e.iter()
+                            .for_each(|err| error_string += err.message.as_ref().unwrap().as_ref());
                     }
                     signup_template
                         .user_errors
@@ -0,0 +1,20 @@
+// Random secrets handed out to users (csrf tokens, links in emails). Only the SHA-256 digest
+// of a secret that grants access is stored, so a leaked table cannot be replayed.
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// 256 random bits, url safe so it can go into links as is.
+pub fn generate() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Hex encoded SHA-256 of the token, this is what goes into the database.
+pub fn digest(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
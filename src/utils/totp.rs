@@ -0,0 +1,107 @@
+// Time-based one-time passwords (RFC 6238) as used by authenticator apps:
+// HMAC-SHA1 over the number of 30 second steps since the epoch, truncated to 6 digits.
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::{QrCode, render::svg};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+
+use crate::utils::percent_encode;
+
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+// codes from the previous and the next step are accepted too, phone clocks drift
+const ALLOWED_DRIFT: i64 = 1;
+const ISSUER: &str = "Snippetbox";
+pub const RECOVERY_CODES: usize = 10;
+
+/// A new shared secret, base32 encoded the way authenticator apps expect it.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// The `otpauth://` link that goes into the QR code.
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(ISSUER),
+        percent_encode(account),
+        secret,
+        percent_encode(ISSUER),
+        DIGITS,
+        PERIOD
+    )
+}
+
+/// The QR code as an inline SVG document.
+pub fn qr_svg(uri: &str) -> Result<String, qrcode::types::QrError> {
+    let code = QrCode::new(uri.as_bytes())?;
+    let svg = code
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .quiet_zone(true)
+        .build();
+    // the XML declaration has no place inside an HTML page
+    Ok(match svg.find("<svg") {
+        Some(start) => svg[start..].to_string(),
+        None => svg,
+    })
+}
+
+fn code_at(key: &[u8], step: i64) -> u32 {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac takes any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Checks a code typed by the user. Returns the time step it belongs to, which has to be
+/// newer than `last_step` so that a code cannot be used twice.
+pub fn verify(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let now = chrono::Utc::now().timestamp() / PERIOD;
+    (now - ALLOWED_DRIFT..=now + ALLOWED_DRIFT)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(&key, *step) == code)
+}
+
+/// Single-use codes for when the phone is lost, e.g. `k7qm-2xdr-9fwa-hc4p` (80 random bits).
+pub fn generate_recovery_codes() -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            (0..4)
+                .map(|_| {
+                    (0..4)
+                        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// Recovery codes are compared without dashes, spaces or case.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
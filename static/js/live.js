@@ -0,0 +1,339 @@
+// Collaborative editing for live snippets.
+// Operations mirror src/live/ot.rs: a positive number retains, a negative number deletes
+// and a string inserts. Lengths are counted in code points, not UTF-16 units.
+
+function TextOperation() {
+	this.ops = [];
+	this.baseLength = 0;
+	this.targetLength = 0;
+}
+
+TextOperation.isRetain = function (op) { return typeof op === "number" && op > 0; };
+TextOperation.isDelete = function (op) { return typeof op === "number" && op < 0; };
+TextOperation.isInsert = function (op) { return typeof op === "string"; };
+
+TextOperation.fromJSON = function (ops) {
+	var operation = new TextOperation();
+	ops.forEach(function (op) {
+		if (TextOperation.isRetain(op)) operation.retain(op);
+		else if (TextOperation.isInsert(op)) operation.insert(op);
+		else if (TextOperation.isDelete(op)) operation.delete(-op);
+		else throw new Error("invalid operation component " + op);
+	});
+	return operation;
+};
+
+// The smallest operation that turns `before` into `after`.
+TextOperation.fromDiff = function (before, after) {
+	var a = Array.from(before), b = Array.from(after);
+	var prefix = 0;
+	while (prefix < a.length && prefix < b.length && a[prefix] === b[prefix]) prefix++;
+	var suffix = 0;
+	while (suffix < a.length - prefix && suffix < b.length - prefix &&
+		a[a.length - 1 - suffix] === b[b.length - 1 - suffix]) suffix++;
+	return new TextOperation()
+		.retain(prefix)
+		.delete(a.length - prefix - suffix)
+		.insert(b.slice(prefix, b.length - suffix).join(""))
+		.retain(suffix);
+};
+
+TextOperation.prototype.retain = function (n) {
+	if (n === 0) return this;
+	this.baseLength += n;
+	this.targetLength += n;
+	if (TextOperation.isRetain(this.ops[this.ops.length - 1])) {
+		this.ops[this.ops.length - 1] += n;
+	} else {
+		this.ops.push(n);
+	}
+	return this;
+};
+
+TextOperation.prototype.insert = function (str) {
+	if (str === "") return this;
+	this.targetLength += Array.from(str).length;
+	var ops = this.ops;
+	if (TextOperation.isInsert(ops[ops.length - 1])) {
+		ops[ops.length - 1] += str;
+	} else if (TextOperation.isDelete(ops[ops.length - 1])) {
+		// keep inserts before deletes, like the server does
+		if (TextOperation.isInsert(ops[ops.length - 2])) {
+			ops[ops.length - 2] += str;
+		} else {
+			ops[ops.length] = ops[ops.length - 1];
+			ops[ops.length - 2] = str;
+		}
+	} else {
+		ops.push(str);
+	}
+	return this;
+};
+
+TextOperation.prototype.delete = function (n) {
+	if (n === 0) return this;
+	this.baseLength += n;
+	if (TextOperation.isDelete(this.ops[this.ops.length - 1])) {
+		this.ops[this.ops.length - 1] -= n;
+	} else {
+		this.ops.push(-n);
+	}
+	return this;
+};
+
+TextOperation.prototype.isNoop = function () {
+	return this.ops.length === 0 || (this.ops.length === 1 && TextOperation.isRetain(this.ops[0]));
+};
+
+TextOperation.prototype.apply = function (str) {
+	var chars = Array.from(str);
+	if (chars.length !== this.baseLength) throw new Error("operation does not match the document");
+	var result = [], index = 0;
+	this.ops.forEach(function (op) {
+		if (TextOperation.isRetain(op)) {
+			result.push(chars.slice(index, index + op).join(""));
+			index += op;
+		} else if (TextOperation.isInsert(op)) {
+			result.push(op);
+		} else {
+			index -= op;
+		}
+	});
+	return result.join("");
+};
+
+// Where a position in the old document ends up in the new one.
+TextOperation.prototype.transformIndex = function (position) {
+	var index = 0, newIndex = position;
+	for (var i = 0; i < this.ops.length && index <= position; i++) {
+		var op = this.ops[i];
+		if (TextOperation.isRetain(op)) {
+			index += op;
+		} else if (TextOperation.isInsert(op)) {
+			newIndex += Array.from(op).length;
+		} else {
+			newIndex -= Math.min(-op, position - index);
+			index -= op;
+		}
+	}
+	return Math.max(newIndex, 0);
+};
+
+// An operation with the effect of `this` followed by `other`.
+TextOperation.prototype.compose = function (other) {
+	if (this.targetLength !== other.baseLength) throw new Error("operations cannot be composed");
+	var result = new TextOperation();
+	var ops1 = this.ops.slice(), ops2 = other.ops.slice();
+	var i1 = 0, i2 = 0, op1 = ops1[i1++], op2 = ops2[i2++];
+	while (op1 !== undefined || op2 !== undefined) {
+		if (TextOperation.isDelete(op1)) {
+			result.delete(-op1);
+			op1 = ops1[i1++];
+			continue;
+		}
+		if (TextOperation.isInsert(op2)) {
+			result.insert(op2);
+			op2 = ops2[i2++];
+			continue;
+		}
+		if (op1 === undefined || op2 === undefined) throw new Error("operations cannot be composed");
+		if (TextOperation.isRetain(op1) && TextOperation.isRetain(op2)) {
+			var min = Math.min(op1, op2);
+			result.retain(min);
+			op1 = op1 > min ? op1 - min : ops1[i1++];
+			op2 = op2 > min ? op2 - min : ops2[i2++];
+		} else if (TextOperation.isInsert(op1) && TextOperation.isDelete(op2)) {
+			var chars = Array.from(op1);
+			if (chars.length > -op2) {
+				op1 = chars.slice(-op2).join("");
+				op2 = ops2[i2++];
+			} else if (chars.length === -op2) {
+				op1 = ops1[i1++];
+				op2 = ops2[i2++];
+			} else {
+				op2 = op2 + chars.length;
+				op1 = ops1[i1++];
+			}
+		} else if (TextOperation.isInsert(op1) && TextOperation.isRetain(op2)) {
+			var inserted = Array.from(op1);
+			if (inserted.length > op2) {
+				result.insert(inserted.slice(0, op2).join(""));
+				op1 = inserted.slice(op2).join("");
+				op2 = ops2[i2++];
+			} else {
+				result.insert(op1);
+				op2 = inserted.length === op2 ? ops2[i2++] : op2 - inserted.length;
+				op1 = ops1[i1++];
+			}
+		} else {
+			// retain followed by delete
+			var deleted = Math.min(op1, -op2);
+			result.delete(deleted);
+			op1 = op1 > deleted ? op1 - deleted : ops1[i1++];
+			op2 = -op2 > deleted ? op2 + deleted : ops2[i2++];
+		}
+	}
+	return result;
+};
+
+// Same contract as Operation::transform on the server: a's inserts win ties.
+TextOperation.transform = function (a, b) {
+	if (a.baseLength !== b.baseLength) throw new Error("operations cannot be transformed");
+	var aPrime = new TextOperation(), bPrime = new TextOperation();
+	var ops1 = a.ops.slice(), ops2 = b.ops.slice();
+	var i1 = 0, i2 = 0, op1 = ops1[i1++], op2 = ops2[i2++];
+	while (op1 !== undefined || op2 !== undefined) {
+		if (TextOperation.isInsert(op1)) {
+			aPrime.insert(op1);
+			bPrime.retain(Array.from(op1).length);
+			op1 = ops1[i1++];
+			continue;
+		}
+		if (TextOperation.isInsert(op2)) {
+			aPrime.retain(Array.from(op2).length);
+			bPrime.insert(op2);
+			op2 = ops2[i2++];
+			continue;
+		}
+		if (op1 === undefined || op2 === undefined) throw new Error("operations cannot be transformed");
+		var x = Math.abs(op1), y = Math.abs(op2), min = Math.min(x, y);
+		if (TextOperation.isRetain(op1) && TextOperation.isRetain(op2)) {
+			aPrime.retain(min);
+			bPrime.retain(min);
+		} else if (TextOperation.isDelete(op1) && TextOperation.isRetain(op2)) {
+			aPrime.delete(min);
+		} else if (TextOperation.isRetain(op1) && TextOperation.isDelete(op2)) {
+			bPrime.delete(min);
+		}
+		op1 = x > min ? Math.sign(op1) * (x - min) : ops1[i1++];
+		op2 = y > min ? Math.sign(op2) * (y - min) : ops2[i2++];
+	}
+	return [aPrime, bPrime];
+};
+
+(function () {
+	var editor = document.getElementById("live-editor");
+	if (!editor) return;
+	var textarea = document.getElementById("live-content");
+	var status = document.getElementById("live-status");
+	var participantList = document.getElementById("live-participants");
+	var secretWarning = document.getElementById("live-secrets");
+	var secretMessage = document.getElementById("live-secrets-message");
+	var secretConfirm = document.getElementById("live-secrets-confirm");
+
+	var socket = new WebSocket(
+		(window.location.protocol === "https:" ? "wss://" : "ws://") + window.location.host +
+		"/snippet/live/" + editor.dataset.snippetId + "/ws");
+	var revision = 0;
+	// the operation waiting for an ack and the local changes made in the meantime
+	var outstanding = null;
+	var buffer = null;
+	// what the textarea contained after the last change we know about
+	var shadow = "";
+
+	function send(operation) {
+		socket.send(JSON.stringify({ type: "op", revision: revision, op: operation.ops }));
+	}
+
+	function codePointIndex(str, unitIndex) {
+		return Array.from(str.slice(0, unitIndex)).length;
+	}
+
+	function unitIndex(str, codePointIndex) {
+		return Array.from(str).slice(0, codePointIndex).join("").length;
+	}
+
+	function applyRemote(operation) {
+		var before = textarea.value;
+		var start = codePointIndex(before, textarea.selectionStart);
+		var end = codePointIndex(before, textarea.selectionEnd);
+		shadow = operation.apply(shadow);
+		textarea.value = shadow;
+		textarea.selectionStart = unitIndex(shadow, operation.transformIndex(start));
+		textarea.selectionEnd = unitIndex(shadow, operation.transformIndex(end));
+	}
+
+	textarea.addEventListener("input", function () {
+		var operation = TextOperation.fromDiff(shadow, textarea.value);
+		shadow = textarea.value;
+		if (operation.isNoop()) return;
+		if (outstanding === null) {
+			outstanding = operation;
+			send(operation);
+		} else {
+			buffer = buffer === null ? operation : buffer.compose(operation);
+		}
+	});
+
+	socket.addEventListener("message", function (event) {
+		var message = JSON.parse(event.data);
+		switch (message.type) {
+			case "init":
+				revision = message.revision;
+				shadow = message.text;
+				textarea.value = shadow;
+				textarea.disabled = false;
+				status.textContent = "Connected";
+				showParticipants(message.participants);
+				break;
+			case "ack":
+				revision = message.revision;
+				outstanding = buffer;
+				buffer = null;
+				if (outstanding !== null) send(outstanding);
+				break;
+			case "op":
+				revision = message.revision;
+				var operation = TextOperation.fromJSON(message.op);
+				// transform the incoming change over everything we have not had acknowledged yet
+				if (outstanding !== null) {
+					var pair = TextOperation.transform(outstanding, operation);
+					outstanding = pair[0];
+					operation = pair[1];
+				}
+				if (buffer !== null) {
+					var pair = TextOperation.transform(buffer, operation);
+					buffer = pair[0];
+					operation = pair[1];
+				}
+				applyRemote(operation);
+				break;
+			case "presence":
+				showParticipants(message.participants);
+				break;
+			case "secrets":
+				showSecrets(message.findings, message.blocked, message.held);
+				break;
+			case "error":
+				status.textContent = message.message;
+				break;
+		}
+	});
+
+	socket.addEventListener("close", function () {
+		textarea.disabled = true;
+		if (status.textContent === "Connected" || status.textContent === "Connecting...") {
+			status.textContent = "Disconnected, reload the page to continue editing";
+		}
+	});
+
+	function showParticipants(participants) {
+		participantList.textContent = participants.map(function (p) { return p.name; }).join(", ");
+	}
+
+	function showSecrets(findings, blocked, held) {
+		secretWarning.hidden = findings.length === 0;
+		secretConfirm.hidden = !held;
+		secretConfirm.disabled = false;
+		var prefix = "This looks like it contains secrets: ";
+		if (blocked) prefix = "Not saved, this looks like it contains secrets: ";
+		else if (held) prefix = "Not saved until somebody confirms, this looks like it contains secrets: ";
+		secretMessage.textContent = prefix + findings.join("; ");
+	}
+
+	// the server saves the text with the next save, and tells everybody
+	secretConfirm.addEventListener("click", function () {
+		secretConfirm.disabled = true;
+		socket.send(JSON.stringify({ type: "confirm" }));
+	});
+})();
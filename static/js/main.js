@@ -5,4 +5,61 @@ for (var i = 0; i < navLinks.length; i++) {
 		link.classList.add("live");
 		break;
 	}
-}
\ No newline at end of file
+}
+// Live updates of the latest snippets on the home page.
+if (window.location.pathname == "/" && window.EventSource) {
+	var table = document.getElementById("snippets");
+	var url = "/snippet/events";
+	if (table) {
+		url += "?last_event_id=" + encodeURIComponent(table.dataset.lastId);
+	}
+	var source = new EventSource(url);
+	source.addEventListener("snippet", function (event) {
+		var snippet = JSON.parse(event.data);
+		if (!table) {
+			table = createSnippetTable();
+		}
+		if (table.querySelector("tr[data-id='" + snippet.id + "']")) {
+			return;
+		}
+		var row = document.createElement("tr");
+		row.dataset.id = snippet.id;
+		var title = document.createElement("td");
+		var link = document.createElement("a");
+		link.href = "/snippet/view/" + snippet.id;
+		link.textContent = snippet.title;
+		title.appendChild(link);
+		var created = document.createElement("td");
+		created.textContent = snippet.created;
+		var id = document.createElement("td");
+		id.textContent = "#" + snippet.id;
+		row.append(title, created, id);
+		var header = table.querySelector("tr");
+		header.parentNode.insertBefore(row, header.nextSibling);
+		// the home page only ever lists the ten newest snippets
+		var rows = table.querySelectorAll("tr");
+		if (rows.length > 11) {
+			rows[rows.length - 1].remove();
+		}
+	});
+	source.addEventListener("reset", function () {
+		// too many updates were missed to replay them, start over
+		source.close();
+		window.location.reload();
+	});
+}
+
+function createSnippetTable() {
+	var table = document.createElement("table");
+	table.id = "snippets";
+	var header = document.createElement("tr");
+	["Title", "Created", "Id"].forEach(function (name) {
+		var th = document.createElement("th");
+		th.textContent = name;
+		header.appendChild(th);
+	});
+	table.appendChild(header);
+	var empty = document.getElementById("no-snippets");
+	empty.parentNode.replaceChild(table, empty);
+	return table;
+}
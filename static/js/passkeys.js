@@ -0,0 +1,147 @@
+// Passkey ceremonies for the login and account pages. The server's options carry binary
+// values as url safe base64, the browser wants ArrayBuffers and answers with them.
+
+function base64urlToBuffer(value) {
+	var base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+	var binary = atob(base64 + "===".slice((base64.length + 3) % 4));
+	var bytes = new Uint8Array(binary.length);
+	for (var i = 0; i < binary.length; i++) bytes[i] = binary.charCodeAt(i);
+	return bytes.buffer;
+}
+
+function bufferToBase64url(buffer) {
+	var bytes = new Uint8Array(buffer);
+	var binary = "";
+	for (var i = 0; i < bytes.length; i++) binary += String.fromCharCode(bytes[i]);
+	return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+// Rejects with the server's error messages, keyed like the fields of the page's forms.
+function postJSON(url, body, csrfToken) {
+	return fetch(url, {
+		method: "POST",
+		credentials: "same-origin",
+		headers: { "Content-Type": "application/json", "X-CSRF-Token": csrfToken },
+		body: JSON.stringify(body)
+	}).then(function (response) {
+		return response.json().catch(function () { return {}; }).then(function (data) {
+			if (!response.ok) {
+				var error = new Error("request to " + url + " failed with " + response.status);
+				error.errors = data.errors || {};
+				throw error;
+			}
+			return data;
+		});
+	});
+}
+
+function createPasskey(options) {
+	var publicKey = options.publicKey;
+	publicKey.challenge = base64urlToBuffer(publicKey.challenge);
+	publicKey.user.id = base64urlToBuffer(publicKey.user.id);
+	(publicKey.excludeCredentials || []).forEach(function (credential) {
+		credential.id = base64urlToBuffer(credential.id);
+	});
+	return navigator.credentials.create({ publicKey: publicKey }).then(function (credential) {
+		var response = credential.response;
+		return {
+			id: credential.id,
+			rawId: bufferToBase64url(credential.rawId),
+			type: credential.type,
+			extensions: credential.getClientExtensionResults(),
+			response: {
+				attestationObject: bufferToBase64url(response.attestationObject),
+				clientDataJSON: bufferToBase64url(response.clientDataJSON),
+				transports: response.getTransports ? response.getTransports() : []
+			}
+		};
+	});
+}
+
+function getPasskey(options) {
+	var publicKey = options.publicKey;
+	publicKey.challenge = base64urlToBuffer(publicKey.challenge);
+	(publicKey.allowCredentials || []).forEach(function (credential) {
+		credential.id = base64urlToBuffer(credential.id);
+	});
+	return navigator.credentials.get({ publicKey: publicKey }).then(function (credential) {
+		var response = credential.response;
+		return {
+			id: credential.id,
+			rawId: bufferToBase64url(credential.rawId),
+			type: credential.type,
+			extensions: credential.getClientExtensionResults(),
+			response: {
+				authenticatorData: bufferToBase64url(response.authenticatorData),
+				clientDataJSON: bufferToBase64url(response.clientDataJSON),
+				signature: bufferToBase64url(response.signature),
+				userHandle: response.userHandle ? bufferToBase64url(response.userHandle) : null
+			}
+		};
+	});
+}
+
+var loginButton = document.getElementById("passkey-login");
+if (loginButton) {
+	if (!window.PublicKeyCredential) {
+		loginButton.parentNode.hidden = true;
+	}
+	loginButton.addEventListener("click", function () {
+		var csrfToken = loginButton.dataset.csrfToken;
+		var message = document.getElementById("passkey-error");
+		message.hidden = true;
+		postJSON("/user/login/passkey/start", {}, csrfToken)
+			.then(getPasskey)
+			.then(function (credential) {
+				return postJSON("/user/login/passkey/finish", credential, csrfToken);
+			})
+			.then(function (outcome) {
+				window.location = outcome.redirect;
+			})
+			.catch(function (error) {
+				// also ends up here when the user cancels the browser's dialog
+				message.textContent = (error.errors && error.errors.login_error) ||
+					"Logging in with a passkey did not work. Please try again.";
+				message.hidden = false;
+			});
+	});
+}
+
+var registerForm = document.getElementById("passkey-register");
+if (registerForm) {
+	if (!window.PublicKeyCredential) {
+		registerForm.hidden = true;
+	}
+	registerForm.addEventListener("submit", function (event) {
+		event.preventDefault();
+		var csrfToken = registerForm.elements.csrf_token.value;
+		var messages = registerForm.querySelectorAll("[data-error]");
+		var showErrors = function (errors) {
+			for (var i = 0; i < messages.length; i++) {
+				var text = errors[messages[i].dataset.error];
+				messages[i].textContent = text || "";
+				messages[i].hidden = !text;
+			}
+		};
+		showErrors({});
+		var data = {
+			name: registerForm.elements.name.value,
+			password: registerForm.elements.password.value
+		};
+		postJSON("/account/passkeys/register/start", data, csrfToken)
+			.then(createPasskey)
+			.then(function (credential) {
+				return postJSON("/account/passkeys/register/finish", credential, csrfToken);
+			})
+			.then(function (outcome) {
+				window.location = outcome.redirect;
+			})
+			.catch(function (error) {
+				var errors = error.errors || {};
+				if (Object.keys(errors).length == 0) {
+					errors = { passkey: "Your passkey could not be added. Please try again." };
+				}
+				showErrors(errors);
+			});
+	});
+}
@@ -0,0 +1,67 @@
+// Proof of work for forms marked with data-pow. The challenge is fetched and solved as soon as
+// the page is open, submitting waits for the solution when the browser is not done yet.
+
+function leadingZeroBits(buffer) {
+	var bytes = new Uint8Array(buffer);
+	var bits = 0;
+	for (var i = 0; i < bytes.length; i++) {
+		if (bytes[i] === 0) {
+			bits += 8;
+			continue;
+		}
+		return bits + Math.clz32(bytes[i]) - 24;
+	}
+	return bits;
+}
+
+// Tries numbers in batches, digests of a batch are computed concurrently.
+function solve(challenge, difficulty) {
+	var encoder = new TextEncoder();
+	var batch = 2000;
+	function attempt(start) {
+		var digests = [];
+		for (var n = start; n < start + batch; n++) {
+			digests.push(crypto.subtle.digest("SHA-256", encoder.encode(challenge + ":" + n)));
+		}
+		return Promise.all(digests).then(function (hashes) {
+			for (var i = 0; i < hashes.length; i++) {
+				if (leadingZeroBits(hashes[i]) >= difficulty) return String(start + i);
+			}
+			return attempt(start + batch);
+		});
+	}
+	return attempt(0);
+}
+
+document.querySelectorAll("form[data-pow]").forEach(function (form) {
+	var solved = false;
+	var submitting = false;
+	var work = fetch("/user/challenge/" + form.dataset.pow, { credentials: "same-origin" })
+		.then(function (response) {
+			if (!response.ok) throw new Error("challenge request failed with " + response.status);
+			return response.json();
+		})
+		.then(function (data) {
+			return solve(data.challenge, data.difficulty).then(function (solution) {
+				form.elements.pow_challenge.value = data.challenge;
+				form.elements.pow_solution.value = solution;
+				solved = true;
+			});
+		});
+
+	form.addEventListener("submit", function (event) {
+		if (solved) return;
+		event.preventDefault();
+		if (submitting) return;
+		submitting = true;
+		var button = form.querySelector("input[type=submit]");
+		if (button) {
+			button.disabled = true;
+			button.value = "Checking your browser...";
+		}
+		// without a solution the server says what went wrong
+		work.catch(function () {}).then(function () {
+			form.submit();
+		});
+	});
+});